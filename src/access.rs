@@ -0,0 +1,213 @@
+use std::path::Path;
+
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::auth::Caller;
+use crate::models::{ErrorResponse, WebAPIResult};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Upload,
+    Read,
+    Delete,
+    /// everything above, also on uploads and files of other callers
+    Admin,
+}
+
+/// Grants `permissions` on blob names under `prefix` to `subject`, which is
+/// `user:<id>`, `tenant:<name>`, `role:<name>` or `*` for every caller. The prefix is
+/// matched by whole segments: `hr` covers `hr` and `hr/a` but not `hr-secrets`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct AccessRule {
+    pub subject: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub permissions: Vec<Permission>,
+}
+
+/// Rules from `ACCESS_RULES_FILE`, a JSON array of `AccessRule`. Access is denied unless a
+/// rule grants it. Without a rules file every caller may do everything.
+#[derive(Default)]
+pub struct AccessPolicy {
+    rules: Vec<AccessRule>,
+}
+
+/// Blob names with empty, `.` or `..` segments could be read differently by tools that
+/// normalize paths and escape their prefix that way.
+fn is_plain_path(path: &str) -> bool {
+    path.split('/')
+        .all(|segment| !segment.is_empty() && segment != "." && segment != "..")
+}
+
+/// Whether every name starting with `prefix` is under `rule_prefix`.
+fn covers_names_under(rule_prefix: &str, prefix: &str) -> bool {
+    let rule_prefix = rule_prefix.trim_start_matches('/');
+    match prefix.strip_prefix(rule_prefix) {
+        Some(rest) => rule_prefix.is_empty() || rule_prefix.ends_with('/') || rest.starts_with('/'),
+        None => false,
+    }
+}
+
+/// Whether `path` is under `rule_prefix`, see `AccessRule`.
+fn covers_path(rule_prefix: &str, path: &str) -> bool {
+    path == rule_prefix.trim_start_matches('/') || covers_names_under(rule_prefix, path)
+}
+
+fn matches_subject(subject: &str, caller: &Caller) -> bool {
+    if subject == "*" {
+        return true;
+    }
+    match subject.split_once(':') {
+        Some(("user", id)) => caller.id == id,
+        Some(("tenant", tenant)) => caller.tenant.as_deref() == Some(tenant),
+        Some(("role", role)) => caller.roles.iter().any(|r| r == role),
+        _ => false,
+    }
+}
+
+impl AccessPolicy {
+    pub fn load(path: &Path) -> Result<AccessPolicy, String> {
+        let rules = std::fs::read(path).map_err(|e| format!("read {:?} failed: {}", path, e))?;
+        let rules: Vec<AccessRule> = serde_json::from_slice(&rules)
+            .map_err(|e| format!("invalid access rules {:?}: {}", path, e))?;
+        for rule in &rules {
+            if rule.subject != "*" && !rule.subject.contains(':') {
+                return Err(format!("invalid access rule subject: {}", rule.subject));
+            }
+        }
+        Ok(AccessPolicy { rules })
+    }
+
+    pub fn enabled(&self) -> bool {
+        !self.rules.is_empty()
+    }
+
+    pub fn allows(&self, caller: &Caller, permission: Permission, path: &str) -> bool {
+        if !self.enabled() {
+            return true;
+        }
+        let path = path.trim_start_matches('/');
+        if !is_plain_path(path) {
+            return false;
+        }
+        self.rules.iter().any(|rule| {
+            matches_subject(&rule.subject, caller)
+                && covers_path(&rule.prefix, path)
+                && rule
+                    .permissions
+                    .iter()
+                    .any(|granted| *granted == permission || *granted == Permission::Admin)
+        })
+    }
+
+    /// Whether `caller` may act on uploads and files of others under `path`.
+    pub fn grants_admin(&self, caller: &Caller, path: &str) -> bool {
+        self.enabled() && self.allows(caller, Permission::Admin, path)
+    }
+
+    /// Whether `caller` may act on everything of others under the name prefix `prefix`,
+    /// which unlike `path` may end anywhere, also within a segment.
+    pub fn grants_admin_under(&self, caller: &Caller, prefix: &str) -> bool {
+        let prefix = prefix.trim_start_matches('/');
+        self.enabled()
+            && prefix
+                .split('/')
+                .all(|segment| segment != "." && segment != "..")
+            && self.rules.iter().any(|rule| {
+                matches_subject(&rule.subject, caller)
+                    && covers_names_under(&rule.prefix, prefix)
+                    && rule.permissions.contains(&Permission::Admin)
+            })
+    }
+
+    /// Rejects with 403 unless `caller` holds `permission` on `path`.
+    pub fn check(&self, caller: &Caller, permission: Permission, path: &str) -> WebAPIResult<()> {
+        if self.allows(caller, permission, path) {
+            return Ok(());
+        }
+        error!("{} denied {:?} on {}", caller.id, permission, path);
+        Err(ErrorResponse::with_status(
+            StatusCode::FORBIDDEN,
+            "permission denied",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn caller(id: &str) -> Caller {
+        Caller {
+            id: id.to_string(),
+            tenant: None,
+            roles: Vec::new(),
+        }
+    }
+
+    fn policy(prefix: &str, permissions: Vec<Permission>) -> AccessPolicy {
+        AccessPolicy {
+            rules: vec![AccessRule {
+                subject: "user:alice".to_string(),
+                prefix: prefix.to_string(),
+                permissions,
+            }],
+        }
+    }
+
+    #[test]
+    fn prefix_matches_whole_segments() {
+        let policy = policy("hr", vec![Permission::Read]);
+        let alice = caller("alice");
+        assert!(policy.allows(&alice, Permission::Read, "hr"));
+        assert!(policy.allows(&alice, Permission::Read, "hr/report.pdf"));
+        assert!(policy.allows(&alice, Permission::Read, "/hr/a/b"));
+        assert!(!policy.allows(&alice, Permission::Read, "hr-secrets/report.pdf"));
+        assert!(!policy.allows(&alice, Permission::Read, "hrx"));
+        assert!(!policy.allows(&alice, Permission::Upload, "hr/report.pdf"));
+        assert!(!policy.allows(&caller("bob"), Permission::Read, "hr/report.pdf"));
+    }
+
+    #[test]
+    fn prefix_ending_in_slash_and_empty_prefix() {
+        let alice = caller("alice");
+        let policy = policy("/hr/", vec![Permission::Read]);
+        assert!(policy.allows(&alice, Permission::Read, "hr/a"));
+        assert!(!policy.allows(&alice, Permission::Read, "hr"));
+        let policy = self::policy("", vec![Permission::Read]);
+        assert!(policy.allows(&alice, Permission::Read, "anything/at/all"));
+    }
+
+    #[test]
+    fn unplain_paths_denied() {
+        let policy = policy("hr", vec![Permission::Admin]);
+        let alice = caller("alice");
+        assert!(!policy.allows(&alice, Permission::Read, "hr/../payroll"));
+        assert!(!policy.allows(&alice, Permission::Read, "hr//a"));
+        assert!(!policy.allows(&alice, Permission::Read, "hr/./a"));
+    }
+
+    #[test]
+    fn admin_under_listing_prefix() {
+        let policy = policy("hr", vec![Permission::Admin]);
+        let alice = caller("alice");
+        assert!(policy.grants_admin(&alice, "hr/a"));
+        assert!(policy.grants_admin_under(&alice, "hr/"));
+        assert!(policy.grants_admin_under(&alice, "hr/rep"));
+        // would also list hr-secrets
+        assert!(!policy.grants_admin_under(&alice, "hr"));
+        assert!(!policy.grants_admin_under(&alice, "h"));
+        assert!(!policy.grants_admin_under(&alice, "hr/../x"));
+    }
+
+    #[test]
+    fn everything_allowed_without_rules() {
+        let policy = AccessPolicy::default();
+        let alice = caller("alice");
+        assert!(policy.allows(&alice, Permission::Delete, "a/b"));
+        assert!(!policy.grants_admin(&alice, "a/b"));
+    }
+}
@@ -1,31 +1,277 @@
+use std::collections::BTreeMap;
+use std::future::Future;
 use std::sync::Arc;
+use std::time::Duration;
 
 use actix_multipart::form::MultipartForm;
-use actix_web::{HttpResponse, Responder, web};
-use azure_identity::DefaultAzureCredential;
+use actix_web::http::header::{
+    Charset, ContentDisposition, DispositionParam, DispositionType, ETag, EntityTag, ExtendedValue,
+    ACCEPT_RANGES, CONTENT_RANGE, CONTENT_TYPE, IF_NONE_MATCH, IF_RANGE, RANGE,
+};
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpRequest, HttpResponse, Responder};
 use azure_storage::StorageCredentials;
-use azure_storage_blobs::prelude::ClientBuilder;
-use tracing::{debug, error};
+use azure_storage_blobs::prelude::BlobClient;
+use futures::TryStreamExt;
+use tracing::{debug, error, info};
 use tracing_attributes::instrument;
 
+use crate::access::{AccessPolicy, Permission};
+use crate::attributes;
+use crate::auth::Caller;
+use crate::catalog;
+use crate::checksum::{md5_digest, ChunkDigest, HashAlgorithm};
+use crate::chunking::{self, AZURE_APPEND_BLOB, AZURE_BLOCK_BLOB};
+use crate::concurrency::ConcurrencyLimits;
+use crate::db::{unix_now, DbPool};
+use crate::ledger;
 use crate::mime_types::MIME_TYPE;
 use crate::models::{
-    Config, ContinueUploadRequest, DbPool, ErrorResponse, FinishResponse, FinishUploadRequest,
-    MAX_CHUNK_SIZE, SharedData, StartUploadRequest, UploadInfo, UploadResponse, WebAPIResult,
+    AbortUploadRequest, Config, ConflictMode, ContinueUploadRequest, CreateShareRequest,
+    DeleteFileQuery, DeleteFileResponse, ErrorResponse, FileContent, FileListResponse, FileState,
+    FinishResponse, FinishUploadRequest, ListFilesQuery, ShareResponse, SharedData,
+    StartUploadRequest, TransferMode, UploadChunk, UploadInfo, UploadResponse, UploadState,
+    UploadedFile, WebAPIResult, DEFAULT_LIST_LIMIT, MAX_LIST_LIMIT, MAX_RENAME_ATTEMPTS,
 };
+use crate::quota;
+use crate::ranges::{self, ByteRange};
+use crate::session::SessionStore;
+use crate::shares::{self, ShareLinks};
+use crate::spool::Spool;
+use crate::storage;
+use crate::throttle::Throttle;
+use crate::tokens::UploadTokens;
+
+/// Appends `_{n}` to the file stem, keeping the extension and any folder prefix:
+/// `data/report.csv` becomes `data/report_1.csv`.
+fn suffixed_blob_name(blob_name: &str, n: u32) -> String {
+    let (dir, file) = match blob_name.rfind('/') {
+        Some(i) => blob_name.split_at(i + 1),
+        None => ("", blob_name),
+    };
+    match file.rfind('.') {
+        Some(i) if i > 0 => format!("{}{}_{}{}", dir, &file[..i], n, &file[i..]),
+        _ => format!("{}{}_{}", dir, file, n),
+    }
+}
+
+/// Decides which blob name an upload writes to according to the conflict mode.
+/// This runs before anything is written, so a rejected upload leaves storage untouched.
+async fn resolve_blob_name(
+    config: &Config,
+    credentials: &StorageCredentials,
+    pool: &DbPool,
+    sessions: &dyn SessionStore,
+    file_name: &str,
+    conflict_mode: ConflictMode,
+) -> WebAPIResult<String> {
+    match conflict_mode {
+        ConflictMode::Overwrite => {
+            if sessions.is_blob_name_reserved(file_name).await? {
+                error!("upload already in progress for {}", file_name);
+                return Err(ErrorResponse::with_status(
+                    StatusCode::CONFLICT,
+                    "upload already in progress",
+                ));
+            }
+            // other uploaded files are linked to this blob through deduplication
+            if let Some(content) = catalog::find_content_by_blob(pool, file_name).await? {
+                if content.ref_count > 1 {
+                    error!("blob is shared by deduplicated files: {}", file_name);
+                    return Err(ErrorResponse::with_status(
+                        StatusCode::CONFLICT,
+                        "blob is shared by deduplicated files",
+                    ));
+                }
+            }
+            Ok(file_name.to_string())
+        }
+        ConflictMode::Fail | ConflictMode::Rename => {
+            free_blob_name(file_name, conflict_mode, |name| async move {
+                Ok(sessions.is_blob_name_reserved(&name).await?
+                    || storage::blob_exists(config, credentials, &name).await?)
+            })
+            .await
+        }
+    }
+}
+
+/// Picks the name of a `Fail` or `Rename` upload, `taken` tells whether a name is in use.
+async fn free_blob_name<F, Fut>(
+    file_name: &str,
+    conflict_mode: ConflictMode,
+    taken: F,
+) -> WebAPIResult<String>
+where
+    F: Fn(String) -> Fut,
+    Fut: Future<Output = WebAPIResult<bool>>,
+{
+    if conflict_mode != ConflictMode::Rename {
+        if taken(file_name.to_string()).await? {
+            error!("blob already exists: {}", file_name);
+            return Err(ErrorResponse::with_status(
+                StatusCode::CONFLICT,
+                "blob already exists",
+            ));
+        }
+        return Ok(file_name.to_string());
+    }
+    for n in 0..=MAX_RENAME_ATTEMPTS {
+        let candidate = if n == 0 {
+            file_name.to_string()
+        } else {
+            suffixed_blob_name(file_name, n)
+        };
+        if !taken(candidate.clone()).await? {
+            return Ok(candidate);
+        }
+    }
+    error!("no free blob name found for {}", file_name);
+    Err(ErrorResponse::with_status(
+        StatusCode::CONFLICT,
+        "no free blob name found",
+    ))
+}
+
+/// Loads an upload `caller` holds `permission` on. Uploads of other callers are rejected
+/// with 403 unless `caller` is admin of their destination.
+async fn get_upload_info(
+    sessions: &dyn SessionStore,
+    access: &AccessPolicy,
+    upload_id: &str,
+    caller: &Caller,
+    permission: Permission,
+) -> WebAPIResult<UploadInfo> {
+    match sessions.get(upload_id).await? {
+        Some(upload_info)
+            if upload_info.owner != caller.id
+                && !access.grants_admin(caller, &upload_info.blob_name) =>
+        {
+            error!(
+                "upload {} of {} requested by {}",
+                upload_id, upload_info.owner, caller.id
+            );
+            Err(ErrorResponse::with_status(
+                StatusCode::FORBIDDEN,
+                "upload belongs to another caller",
+            ))
+        }
+        Some(upload_info) => {
+            access.check(caller, permission, &upload_info.blob_name)?;
+            Ok(upload_info)
+        }
+        None => {
+            error!("upload not found: {}", upload_id);
+            Err(ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                "upload not found",
+            ))
+        }
+    }
+}
+
+/// Overwriting a blob deletes the files stored in it, which takes the same rights as
+/// deleting them: files of other callers only give way to an admin.
+async fn check_overwrite(
+    pool: &DbPool,
+    access: &AccessPolicy,
+    caller: &Caller,
+    blob_name: &str,
+) -> WebAPIResult<()> {
+    let owners = catalog::file_owners_at(pool, blob_name).await?;
+    if owners.is_empty() {
+        return Ok(());
+    }
+    if owners.iter().any(|owner| *owner != caller.id) && !access.grants_admin(caller, blob_name) {
+        error!(
+            "{} may not overwrite files of others in {}",
+            caller.id, blob_name
+        );
+        return Err(ErrorResponse::with_status(
+            StatusCode::FORBIDDEN,
+            "blob holds files of another caller",
+        ));
+    }
+    access.check(caller, Permission::Delete, blob_name)
+}
+
+/// Moves an upload to `state`, logging when the state changes.
+async fn set_state(
+    sessions: &dyn SessionStore,
+    upload_info: &UploadInfo,
+    state: UploadState,
+) -> WebAPIResult<()> {
+    sessions.transition(&upload_info.upload_id, state).await?;
+    if upload_info.state != state {
+        info!("upload {} is {}", upload_info.upload_id, state);
+    }
+    Ok(())
+}
 
-#[instrument]
+/// Removes what an upload that will never be finished left in the spool and in storage
+/// and gives back its quota. Failures are only logged, nothing reads the staging prefix.
+async fn discard_upload(
+    config: &Config,
+    credentials: &StorageCredentials,
+    pool: &DbPool,
+    spool: &Spool,
+    upload_id: &str,
+) {
+    if let Err(e) = quota::release(pool, upload_id).await {
+        error!("release quota of {} failed: {}", upload_id, e);
+    }
+    if let Err(e) = spool.discard_upload(upload_id).await {
+        error!("discard spooled data of {} failed: {}", upload_id, e);
+    }
+    for staging_name in [
+        storage::staging_blob_name(upload_id),
+        storage::rebuilt_blob_name(upload_id),
+    ] {
+        let staging_client = storage::blob_client(config, credentials, &staging_name);
+        if let Err(e) = storage::delete_blob(config, &staging_client).await {
+            error!("delete staging blob {} failed: {}", staging_name, e);
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(http_req, access, throttle, upload_tokens, sessions))]
 pub async fn start_upload(
+    http_req: HttpRequest,
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    throttle: web::Data<Throttle>,
     config: web::Data<Config>,
+    upload_tokens: web::Data<UploadTokens>,
     shared_credentials: web::Data<SharedData>,
     pool: web::Data<DbPool>,
+    sessions: web::Data<dyn SessionStore>,
     req: web::Json<StartUploadRequest>,
 ) -> WebAPIResult<impl Responder> {
-    let default_creds = Arc::new(DefaultAzureCredential::default());
-    let credentials = StorageCredentials::token_credential(default_creds);
+    throttle.check_start_upload(&http_req, &caller)?;
+    access.check(&caller, Permission::Upload, &req.file_name)?;
+    let credentials = &shared_credentials.credentials;
     let upload_id = uuid::Uuid::new_v4().to_string();
 
-    let file_ext = &req.file_name.split('.').last();
+    if req.file_name.starts_with(storage::STAGING_PREFIX) {
+        error!("file_name uses the staging prefix: {}", req.file_name);
+        return Err(ErrorResponse::with_status(
+            StatusCode::BAD_REQUEST,
+            "file_name must not start with the staging prefix",
+        ));
+    }
+    attributes::validate_metadata(&req.metadata)?;
+    attributes::validate_tags(&req.tags)?;
+    let transfer_mode = req.transfer_mode.unwrap_or_default();
+    if transfer_mode == TransferMode::Direct && !config.direct_uploads {
+        error!("direct uploads are disabled");
+        return Err(ErrorResponse::with_status(
+            StatusCode::BAD_REQUEST,
+            "direct uploads are not enabled",
+        ));
+    }
+
+    let file_ext = &req.file_name.split('.').next_back();
     let file_ext = match file_ext {
         Some(ext) => ext,
         None => {
@@ -38,168 +284,1389 @@ pub async fn start_upload(
         .unwrap_or(&"application/octet-stream");
     debug!("start_upload content_type : {:#?}", content_type);
 
-    shared_credentials
-        .shared_data_map
-        .lock()
-        .unwrap()
-        .insert(upload_id.clone(), credentials.clone());
-    let res = pool.get().unwrap().execute(
-        r#"
-            INSERT INTO temp_file_uploader(
+    // the same content was uploaded and verified before, link to it instead of transferring it
+    // again. Not with metadata, tags or an access tier, the existing blob keeps those of the
+    // first upload.
+    let verified_content =
+        if req.metadata.is_empty() && req.tags.is_empty() && req.access_tier.is_none() {
+            catalog::find_verified_content(&pool, &req.file_hash, req.file_size, &caller.id).await?
+        } else {
+            None
+        };
+    if let Some(content) = verified_content {
+        if storage::blob_exists(&config, credentials, &content.blob_name).await? {
+            let uploaded_file = UploadedFile {
+                upload_id: upload_id.clone(),
+                file_name: req.file_name.clone(),
+                file_size: req.file_size,
+                file_hash: req.file_hash.clone(),
+                content_type: content_type.to_string(),
+                content_id: content.content_id,
+                deduplicated: true,
+                owner: caller.id.clone(),
+                tenant: caller.tenant.clone(),
+                created_at: unix_now(),
+                metadata: BTreeMap::new(),
+                tags: BTreeMap::new(),
+                access_tier: None,
+            };
+            catalog::link_uploaded_file(&pool, &uploaded_file).await?;
+            let quota_remaining =
+                quota::remaining(&pool, &config, &caller.id, caller.tenant.as_deref()).await?;
+            let resp = UploadResponse {
                 upload_id,
-                file_name,
-                file_size,
-                file_hash,
-                content_type,
-                blob_access_token,
-                blob_file_hash
-            ) VALUES (
-                ?1,
-                ?2,
-                ?3,
-                ?4,
-                ?5,
-                ?6,
-                ?7
+                chunk_size: None,
+                blob_name: Some(content.blob_name),
+                deduplicated: Some(true),
+                state: Some(UploadState::Completed),
+                upload_token: None,
+                quota_remaining,
+                chunk_urls: None,
+                chunk_urls_expire_at: None,
+            };
+            debug!("start_upload deduplicated: {:#?}", resp);
+            return Ok(HttpResponse::Ok().json(resp));
+        }
+        error!(
+            "deduplicated content missing in storage: {}",
+            content.blob_name
+        );
+        catalog::unverify_content(&pool, &content.content_id).await?;
+    }
+
+    let (block_size, chunk_size) = match transfer_mode {
+        TransferMode::Proxied => {
+            let block_size = chunking::block_size_for(&AZURE_APPEND_BLOB, req.file_size)?;
+            let chunk_size = chunking::negotiate_chunk_size(
+                &config,
+                block_size,
+                req.preferred_chunk_size,
+                req.connection_profile,
             );
-        "#,
-        (
-            &upload_id,
-            &req.file_name,
-            &req.file_size,
-            &req.file_hash,
-            content_type,
-            &"-",
-            &"-",
-        ),
+            (block_size, chunk_size)
+        }
+        // every chunk is put as one block of the staging blob
+        TransferMode::Direct => {
+            let chunk_size = chunking::direct_chunk_size(
+                &AZURE_BLOCK_BLOB,
+                req.file_size,
+                req.preferred_chunk_size,
+            )?;
+            (chunk_size, chunk_size)
+        }
+    };
+    debug!(
+        "start_upload block_size : {} chunk_size : {}",
+        block_size, chunk_size
+    );
+    let conflict_mode = req.conflict_mode.unwrap_or(config.conflict_mode);
+    let blob_name = resolve_blob_name(
+        &config,
+        credentials,
+        &pool,
+        sessions.as_ref(),
+        &req.file_name,
+        conflict_mode,
+    )
+    .await?;
+    debug!(
+        "start_upload blob_name : {} ({:?})",
+        blob_name, conflict_mode
     );
-    if let Err(e) = res {
-        error!("insert failed: {:?}", e);
-        return Err(ErrorResponse::new("insert failed"));
+    if conflict_mode == ConflictMode::Overwrite {
+        check_overwrite(&pool, &access, &caller, &blob_name).await?;
     }
 
-    let blob_client = ClientBuilder::new(&config.account, credentials.clone())
-        .blob_client(&config.container, &req.file_name);
-
-    //let content_type = "text/plain";
-    let block_res = blob_client
-        .put_append_blob()
-        .content_type(&req.content_type)
-        .await;
-    if let Err(e) = block_res {
-        error!("put block failed: {:#?}", e);
-        return Err(ErrorResponse::new("put block failed"));
+    let upload_info = UploadInfo {
+        upload_id: upload_id.clone(),
+        file_name: req.file_name.clone(),
+        file_size: req.file_size,
+        file_hash: req.file_hash.clone(),
+        content_type: content_type.to_string(),
+        blob_access_token: "-".to_string(),
+        blob_file_hash: "-".to_string(),
+        blob_name: blob_name.clone(),
+        conflict_mode,
+        block_size,
+        chunk_size,
+        staged_size: 0,
+        state: UploadState::Created,
+        owner: caller.id.clone(),
+        tenant: caller.tenant.clone(),
+        metadata: req.metadata.clone(),
+        tags: req.tags.clone(),
+        access_tier: config.access_tier_for(req.access_tier, req.file_size, content_type),
+        transfer_mode,
+    };
+    quota::reserve(&pool, &config, &upload_info).await?;
+    if let Err(e) = sessions.create(&upload_info).await {
+        quota::release(&pool, &upload_id).await?;
+        return Err(e);
     }
+    info!(
+        "upload {} of {} is {}",
+        upload_id,
+        caller.id,
+        UploadState::Created
+    );
 
+    // data goes to the hidden staging blob until finish_upload promotes it
+    let staging_name = storage::staging_blob_name(&upload_id);
+    let (upload_token, chunk_urls, chunk_urls_expire_at) = match transfer_mode {
+        TransferMode::Proxied => {
+            let blob_client = storage::blob_client(&config, credentials, &staging_name);
+            //let content_type = "text/plain";
+            storage::create_append_blob(&config, &blob_client, &req.content_type).await?;
+            (upload_tokens.issue(&upload_info), None, None)
+        }
+        // the client puts the blocks itself, the token has to last until finish_upload
+        TransferMode::Direct => {
+            let (chunk_urls, expire_at) = storage::direct_chunk_urls(
+                &config,
+                credentials,
+                &staging_name,
+                req.file_size,
+                chunk_size,
+                Duration::from_secs(config.direct_upload_url_ttl_secs),
+            )
+            .await?;
+            (
+                upload_tokens.issue_until(&upload_info, expire_at),
+                Some(chunk_urls),
+                Some(expire_at),
+            )
+        }
+    };
+
+    let quota_remaining =
+        quota::remaining(&pool, &config, &caller.id, caller.tenant.as_deref()).await?;
     let resp = UploadResponse {
         upload_id,
-        chunk_size: Some(MAX_CHUNK_SIZE),
+        chunk_size: Some(chunk_size),
+        blob_name: Some(blob_name),
+        deduplicated: Some(false),
+        state: Some(UploadState::Created),
+        upload_token: Some(upload_token),
+        quota_remaining,
+        chunk_urls,
+        chunk_urls_expire_at,
     };
     debug!("start_upload: {:#?}", resp);
     Ok(HttpResponse::Ok().json(resp))
 }
 
-#[instrument(skip(form))]
+/// Writes `chunk` behind the bytes carried over from the previous chunk. Only whole blocks
+/// are written unless `flush` is set, the rest is carried over to the next call. Bytes that
+/// already reached storage are skipped, so a chunk retried after a partial failure is not
+/// appended twice. With the spool enabled blocks go to the spool instead and reach
+/// storage through its worker.
+async fn write_chunk(
+    config: &Config,
+    sessions: &dyn SessionStore,
+    spool: &Spool,
+    blob_client: &BlobClient,
+    upload_info: &UploadInfo,
+    chunk: &[u8],
+    flush: bool,
+) -> WebAPIResult<u64> {
+    let pending = sessions
+        .load_pending(&upload_info.upload_id)
+        .await?
+        .unwrap_or_default();
+    let mut data = pending.data;
+    data.extend_from_slice(chunk);
+    let written = upload_info
+        .staged_size
+        .saturating_sub(pending.offset)
+        .min(data.len() as u64);
+    let data = data.split_off(written as usize);
+
+    let (mut blocks, mut remainder) = chunking::split_blocks(data, upload_info.block_size);
+    if flush && !remainder.is_empty() {
+        blocks.push(std::mem::take(&mut remainder));
+    }
+    let mut staged_size = upload_info.staged_size;
+    for block in blocks {
+        let len = block.len() as u64;
+        if spool.enabled() {
+            spool
+                .write_block(&upload_info.upload_id, staged_size, block)
+                .await?;
+        } else {
+            storage::append_block_at(config, blob_client, staged_size, block).await?;
+        }
+        staged_size += len;
+        sessions
+            .save_staged_size(&upload_info.upload_id, staged_size)
+            .await?;
+    }
+    sessions
+        .save_progress(&upload_info.upload_id, staged_size, &remainder)
+        .await?;
+    Ok(staged_size)
+}
+
+/// Bytes of an upload stored by chunks that completed, saved with the carried over bytes
+/// at the end of `write_chunk`. It is ahead of the ledger when a chunk was stored but
+/// recording it failed.
+async fn completed_size(sessions: &dyn SessionStore, upload_id: &str) -> WebAPIResult<u64> {
+    Ok(sessions
+        .load_pending(upload_id)
+        .await?
+        .map_or(0, |pending| pending.offset + pending.data.len() as u64))
+}
+
+/// Decides where a chunk goes: the ledger row to record once it is written, or `None` when
+/// a retry of it is stored already. A chunk that was stored without its ledger row gets
+/// the row now instead of being appended a second time.
+async fn place_chunk(
+    pool: &DbPool,
+    sessions: &dyn SessionStore,
+    upload_info: &UploadInfo,
+    chunk_index: Option<u64>,
+    data: &[u8],
+) -> WebAPIResult<Option<UploadChunk>> {
+    let upload_id = &upload_info.upload_id;
+    let (next_index, next_offset) = match ledger::last_chunk(pool, upload_id).await? {
+        Some(last) => (last.chunk_index + 1, last.chunk_offset + last.chunk_size),
+        None => (0, 0),
+    };
+    let chunk = UploadChunk {
+        upload_id: upload_id.clone(),
+        chunk_index: next_index,
+        chunk_offset: next_offset,
+        chunk_size: data.len() as u64,
+        checksum: hex::encode(md5_digest(data)),
+        block_id: chunking::block_id(next_offset / upload_info.block_size),
+        received_at: unix_now(),
+    };
+    match chunk_index {
+        Some(index) if index < next_index => match ledger::find_chunk(pool, upload_id, index)
+            .await?
+        {
+            Some(stored)
+                if stored.chunk_size == chunk.chunk_size && stored.checksum == chunk.checksum =>
+            {
+                return Ok(None);
+            }
+            _ => {
+                error!(
+                    "continue_upload chunk {} differs from the stored one",
+                    index
+                );
+                return Err(ErrorResponse::with_status(
+                    StatusCode::CONFLICT,
+                    "chunk_index already stored with different data",
+                ));
+            }
+        },
+        Some(index) if index > next_index => {
+            error!(
+                "continue_upload chunk {} out of order, expected {}",
+                index, next_index
+            );
+            return Err(ErrorResponse::with_status(
+                StatusCode::CONFLICT,
+                "chunks must be sent in order",
+            ));
+        }
+        _ => {}
+    }
+    let completed = completed_size(sessions, upload_id).await?;
+    if completed == next_offset {
+        return Ok(Some(chunk));
+    }
+    if completed != next_offset + chunk.chunk_size {
+        error!(
+            "continue_upload {} stored {} bytes, the ledger ends at {}",
+            upload_id, completed, next_offset
+        );
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            "chunk does not match the stored data",
+        ));
+    }
+    info!(
+        "continue_upload chunk {} of {} stored before, recording it",
+        next_index, upload_id
+    );
+    ledger::record_chunk(pool, &chunk).await?;
+    Ok(None)
+}
+
+type DigestParser = fn(&str) -> Option<ChunkDigest>;
+
+/// Collects the digests the client sent for a chunk, either as form fields or as a
+/// `Content-MD5` header carrying the MD5 of the chunk data.
+fn chunk_digests(
+    http_req: &HttpRequest,
+    form: &ContinueUploadRequest,
+) -> WebAPIResult<Vec<ChunkDigest>> {
+    let mut values: Vec<(&str, DigestParser)> = Vec::new();
+    if let Some(header) = http_req.headers().get("Content-MD5") {
+        match header.to_str() {
+            Ok(value) => values.push((value, ChunkDigest::parse_md5)),
+            Err(_) => values.push(("", ChunkDigest::parse_md5)),
+        }
+    }
+    if let Some(value) = &form.chunk_md5 {
+        values.push((value.as_str(), ChunkDigest::parse_md5));
+    }
+    if let Some(value) = &form.chunk_crc32c {
+        values.push((value.as_str(), ChunkDigest::parse_crc32c));
+    }
+    if let Some(value) = &form.chunk_sha256 {
+        values.push((value.as_str(), ChunkDigest::parse_sha256));
+    }
+
+    let mut digests = Vec::with_capacity(values.len());
+    for (value, parse) in values {
+        match parse(value) {
+            Some(digest) => digests.push(digest),
+            None => {
+                error!("invalid chunk checksum: {}", value);
+                return Err(ErrorResponse::with_status(
+                    StatusCode::BAD_REQUEST,
+                    "invalid chunk checksum",
+                ));
+            }
+        }
+    }
+    Ok(digests)
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(http_req, access, concurrency, upload_tokens, sessions, form))]
 pub async fn continue_upload(
+    http_req: HttpRequest,
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    concurrency: web::Data<ConcurrencyLimits>,
+    upload_tokens: web::Data<UploadTokens>,
     shared_credentials: web::Data<SharedData>,
     config: web::Data<Config>,
     pool: web::Data<DbPool>,
+    sessions: web::Data<dyn SessionStore>,
+    spool: web::Data<Spool>,
     form: MultipartForm<ContinueUploadRequest>,
 ) -> WebAPIResult<impl Responder> {
-    let update_id = &form.upload_id;
+    let update_id = form.upload_id.as_str().to_owned();
     let update_id = update_id.as_str();
+    let chunk_index = form.chunk_index.as_ref().map(|index| index.0);
 
-    let res = pool.get().unwrap().query_row(
-        r#"
-            SELECT
-                upload_id,
-                file_name,
-                file_size,
-                file_hash,
-                content_type,
-                blob_access_token,
-                blob_file_hash
-            FROM temp_file_uploader WHERE upload_id = ?1;
-        "#,
-        &[&update_id],
-        |row| {
-            let upload_info = UploadInfo {
-                upload_id: row.get(0)?,
-                file_name: row.get(1)?,
-                file_size: row.get(2)?,
-                file_hash: row.get(3)?,
-                content_type: row.get(4)?,
-                blob_access_token: row.get(5)?,
-                blob_file_hash: row.get(6)?,
-            };
-            Ok(upload_info)
-        },
+    let upload_info = get_upload_info(
+        sessions.as_ref(),
+        &access,
+        update_id,
+        &caller,
+        Permission::Upload,
+    )
+    .await?;
+    upload_tokens.verify(&http_req, &upload_info)?;
+    // only callers allowed to upload take a slot, others cannot block the upload
+    let _slot = concurrency.enter_upload(update_id)?;
+    if upload_info.transfer_mode == TransferMode::Direct {
+        error!("continue_upload for direct upload {}", update_id);
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            "chunks of a direct upload go to storage",
+        ));
+    }
+    // also rejects chunks for uploads that are finalizing or over
+    set_state(sessions.as_ref(), &upload_info, UploadState::Uploading).await?;
+    let credentials = &shared_credentials.credentials;
+    let digests = chunk_digests(&http_req, &form)?;
+    let blob_client = storage::blob_client(
+        &config,
+        credentials,
+        &storage::staging_blob_name(&upload_info.upload_id),
     );
-    if let Err(e) = res {
-        error!("query failed: {:?}", e);
-        return Err(ErrorResponse::new("query failed"));
-    }
-    let upload_info = res.unwrap();
-    match shared_credentials
-        .shared_data_map
-        .lock()
-        .unwrap()
-        .get(update_id)
-    {
-        Some(credentials) => {
-            debug!("continue_upload credentials : {:?}", credentials);
-            let blob_client = ClientBuilder::new(&config.account, credentials.to_owned())
-                .blob_client(&config.container, &upload_info.file_name);
-            match form.into_inner().chunk_data {
-                Some(chunk_data) => {
-                    //debug!("continue_upload chunk_data : {:?}", chunk_data);
-                    debug!("continue_upload chunk_data : {:#?}", &chunk_data);
-                    //let content_type = "text/plain";
-                    //if let Some(mut mime_type) = chunk_data.content_type {
-                    //    debug!("continue_upload content_type : {:#?}", mime_type);
-                    //}
-                    let block_res = blob_client
-                        .append_block(chunk_data.data.to_vec())
-                        //.content_type(content_type)
-                        .await;
-                    if let Err(e) = block_res {
-                        error!("put block failed: {:#?}", e);
-                        return Err(ErrorResponse::new("put block failed"));
-                    }
+    match form.into_inner().chunk_data {
+        Some(chunk_data) => {
+            //debug!("continue_upload chunk_data : {:?}", chunk_data);
+            debug!("continue_upload chunk_data : {:#?}", &chunk_data);
+            //let content_type = "text/plain";
+            //if let Some(mut mime_type) = chunk_data.content_type {
+            //    debug!("continue_upload content_type : {:#?}", mime_type);
+            //}
+            if chunk_data.data.len() as u64 > upload_info.chunk_size {
+                error!(
+                    "continue_upload chunk of {} bytes exceeds chunk_size {}",
+                    chunk_data.data.len(),
+                    upload_info.chunk_size
+                );
+                return Err(ErrorResponse::with_status(
+                    StatusCode::PAYLOAD_TOO_LARGE,
+                    "chunk exceeds the negotiated chunk_size",
+                ));
+            }
+            // a chunk corrupted between the client and us is rejected before it is written
+            for digest in &digests {
+                if !digest.matches(&chunk_data.data) {
+                    error!("continue_upload {} checksum mismatch", digest.name());
+                    return Err(ErrorResponse::with_status(
+                        StatusCode::BAD_REQUEST,
+                        "chunk checksum mismatch",
+                    ));
                 }
-                None => {
-                    error!("continue_upload chunk_data not found");
-                    return Err(ErrorResponse::new("continue_upload chunk_data not found"));
+            }
+            // the ledger tells where the chunk goes and whether a retry is stored already
+            match place_chunk(
+                &pool,
+                sessions.as_ref(),
+                &upload_info,
+                chunk_index,
+                &chunk_data.data,
+            )
+            .await?
+            {
+                Some(chunk) => {
+                    // chunks are re-cut into blocks of block_size, a short tail waits for the
+                    // next chunk
+                    write_chunk(
+                        &config,
+                        sessions.as_ref(),
+                        &spool,
+                        &blob_client,
+                        &upload_info,
+                        &chunk_data.data,
+                        false,
+                    )
+                    .await?;
+                    ledger::record_chunk(&pool, &chunk).await?;
                 }
+                None => debug!("continue_upload chunk {:?} already stored", chunk_index),
             }
         }
         None => {
-            error!("continue_upload credentials not found");
-            return Err(ErrorResponse::new("continue_upload credentials not found"));
+            error!("continue_upload chunk_data not found");
+            return Err(ErrorResponse::new("continue_upload chunk_data not found"));
         }
     }
 
     //TokenCredential::new(access_token);
+    let upload_token = upload_tokens.issue(&upload_info);
     let resp = UploadResponse {
         upload_id: upload_info.upload_id,
         chunk_size: None,
+        blob_name: None,
+        deduplicated: None,
+        state: Some(UploadState::Uploading),
+        upload_token: Some(upload_token),
+        quota_remaining: None,
+        chunk_urls: None,
+        chunk_urls_expire_at: None,
     };
     debug!("continue_upload: {:#?}", resp);
     Ok(HttpResponse::Ok().json(resp))
 }
 
-#[instrument]
+/// Verifies the staged data and publishes it under the real blob name. Errors with
+/// 400 or 409 mean the data can never be published, others may pass on a retry.
+async fn publish_upload(
+    config: &Config,
+    pool: &DbPool,
+    sessions: &dyn SessionStore,
+    spool: &Spool,
+    credentials: &StorageCredentials,
+    upload_info: &UploadInfo,
+) -> WebAPIResult<()> {
+    let staging_name = storage::staging_blob_name(&upload_info.upload_id);
+
+    // verify the staged data before anything becomes visible under the real name
+    let staging_client = storage::blob_client(config, credentials, &staging_name);
+    match upload_info.transfer_mode {
+        TransferMode::Proxied => {
+            write_chunk(
+                config,
+                sessions,
+                spool,
+                &staging_client,
+                upload_info,
+                &[],
+                true,
+            )
+            .await?;
+            spool.flush_upload(config, &upload_info.upload_id).await?;
+        }
+        TransferMode::Direct => {
+            storage::commit_blocks(
+                config,
+                &staging_client,
+                upload_info.file_size,
+                upload_info.chunk_size,
+                &upload_info.content_type,
+            )
+            .await?;
+        }
+    }
+    let staged_size = storage::blob_size(config, &staging_client).await?;
+    if staged_size != upload_info.file_size {
+        error!(
+            "finish_upload size mismatch: staged {} declared {}",
+            staged_size, upload_info.file_size
+        );
+        return Err(ErrorResponse::with_status(
+            StatusCode::BAD_REQUEST,
+            "uploaded size does not match file_size",
+        ));
+    }
+    let hash_algorithm = HashAlgorithm::from_hex_digest(&upload_info.file_hash);
+    if let Some(algorithm) = hash_algorithm {
+        let staged_hash =
+            storage::blob_hash(config, &staging_client, staged_size, algorithm).await?;
+        if !staged_hash.eq_ignore_ascii_case(&upload_info.file_hash) {
+            error!(
+                "finish_upload hash mismatch: staged {} declared {}",
+                staged_hash, upload_info.file_hash
+            );
+            return Err(ErrorResponse::with_status(
+                StatusCode::BAD_REQUEST,
+                "uploaded data does not match file_hash",
+            ));
+        }
+    }
+    // someone may have created the blob directly while this upload was running
+    if upload_info.conflict_mode != ConflictMode::Overwrite
+        && storage::blob_exists(config, credentials, &upload_info.blob_name).await?
+    {
+        error!("blob already exists: {}", upload_info.blob_name);
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            "blob already exists",
+        ));
+    }
+    // append blobs take no access tier, the data is moved into a block blob that does
+    let rebuilt =
+        upload_info.transfer_mode == TransferMode::Proxied && upload_info.access_tier.is_some();
+    let source_name = if rebuilt {
+        let rebuilt_name = storage::rebuilt_blob_name(&upload_info.upload_id);
+        storage::rebuild_as_block_blob(
+            config,
+            credentials,
+            &staging_name,
+            &rebuilt_name,
+            staged_size,
+            &upload_info.content_type,
+        )
+        .await?;
+        rebuilt_name
+    } else {
+        staging_name.clone()
+    };
+    storage::promote(
+        config,
+        credentials,
+        &source_name,
+        &upload_info.blob_name,
+        &upload_info.metadata,
+        &upload_info.tags,
+        upload_info.access_tier,
+    )
+    .await?;
+    if rebuilt {
+        if let Err(e) = storage::delete_blob(config, &staging_client).await {
+            error!(
+                "delete staging blob of {} failed: {}",
+                upload_info.upload_id, e
+            );
+        }
+    }
+
+    // only content whose hash the server checked itself is offered for deduplication
+    let content = FileContent {
+        content_id: uuid::Uuid::new_v4().to_string(),
+        file_hash: upload_info.file_hash.clone(),
+        file_size: upload_info.file_size,
+        blob_name: upload_info.blob_name.clone(),
+        verified: hash_algorithm.is_some(),
+        ref_count: 1,
+    };
+    let uploaded_file = UploadedFile {
+        upload_id: upload_info.upload_id.clone(),
+        file_name: upload_info.file_name.clone(),
+        file_size: upload_info.file_size,
+        file_hash: upload_info.file_hash.clone(),
+        content_type: upload_info.content_type.clone(),
+        content_id: content.content_id.clone(),
+        deduplicated: false,
+        owner: upload_info.owner.clone(),
+        tenant: upload_info.tenant.clone(),
+        created_at: unix_now(),
+        metadata: upload_info.metadata.clone(),
+        tags: upload_info.tags.clone(),
+        access_tier: upload_info.access_tier,
+    };
+    catalog::publish_uploaded_file(pool, &content, &uploaded_file).await
+}
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(http_req, access, upload_tokens, sessions))]
 pub async fn finish_upload(
+    http_req: HttpRequest,
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    upload_tokens: web::Data<UploadTokens>,
+    shared_credentials: web::Data<SharedData>,
     pool: web::Data<DbPool>,
+    sessions: web::Data<dyn SessionStore>,
+    spool: web::Data<Spool>,
     config: web::Data<Config>,
     req: web::Json<FinishUploadRequest>,
 ) -> WebAPIResult<impl Responder> {
     //debug!("finish_upload with : {:#?}", req);
     let update_id = &req.upload_id;
 
+    let upload_info = get_upload_info(
+        sessions.as_ref(),
+        &access,
+        update_id,
+        &caller,
+        Permission::Upload,
+    )
+    .await?;
+    upload_tokens.verify(&http_req, &upload_info)?;
+    let credentials = &shared_credentials.credentials;
     let resp = FinishResponse {
         upload_id: update_id.clone(),
-        file_hash: "".to_string(),
+        file_hash: upload_info.file_hash.clone(),
+        verified: HashAlgorithm::from_hex_digest(&upload_info.file_hash).is_some(),
+        state: UploadState::Completed,
+    };
+    // a retried finish of a completed upload gets the same answer again
+    if upload_info.state == UploadState::Completed {
+        debug!("finish_upload {} already completed", update_id);
+        return Ok(HttpResponse::Ok().json(resp));
+    }
+
+    // files may have been published under the name since start_upload
+    if upload_info.conflict_mode == ConflictMode::Overwrite {
+        check_overwrite(&pool, &access, &caller, &upload_info.blob_name).await?;
+    }
+    set_state(sessions.as_ref(), &upload_info, UploadState::Finalizing).await?;
+    let res = publish_upload(
+        &config,
+        &pool,
+        sessions.as_ref(),
+        &spool,
+        credentials,
+        &upload_info,
+    )
+    .await;
+    match res {
+        Ok(()) => {}
+        Err(e) if e.status == StatusCode::BAD_REQUEST || e.status == StatusCode::CONFLICT => {
+            if let Err(e) = sessions.transition(update_id, UploadState::Failed).await {
+                error!("finish_upload mark {} failed: {}", update_id, e);
+            }
+            info!("upload {} is {}: {}", update_id, UploadState::Failed, e);
+            discard_upload(&config, credentials, &pool, &spool, update_id).await;
+            return Err(e);
+        }
+        // stays finalizing, finish_upload may be retried
+        Err(e) => return Err(e),
+    }
+    let finalizing = UploadInfo {
+        state: UploadState::Finalizing,
+        ..upload_info
     };
+    set_state(sessions.as_ref(), &finalizing, UploadState::Completed).await?;
     Ok(HttpResponse::Ok().json(resp))
 }
+
+#[allow(clippy::too_many_arguments)]
+#[instrument(skip(access, sessions))]
+pub async fn abort_upload(
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    shared_credentials: web::Data<SharedData>,
+    pool: web::Data<DbPool>,
+    sessions: web::Data<dyn SessionStore>,
+    spool: web::Data<Spool>,
+    config: web::Data<Config>,
+    req: web::Json<AbortUploadRequest>,
+) -> WebAPIResult<impl Responder> {
+    let upload_info = get_upload_info(
+        sessions.as_ref(),
+        &access,
+        &req.upload_id,
+        &caller,
+        Permission::Upload,
+    )
+    .await?;
+    set_state(sessions.as_ref(), &upload_info, UploadState::Aborted).await?;
+    discard_upload(
+        &config,
+        &shared_credentials.credentials,
+        &pool,
+        &spool,
+        &upload_info.upload_id,
+    )
+    .await;
+
+    let quota_remaining =
+        quota::remaining(&pool, &config, &caller.id, caller.tenant.as_deref()).await?;
+    let resp = UploadResponse {
+        upload_id: upload_info.upload_id,
+        chunk_size: None,
+        blob_name: None,
+        deduplicated: None,
+        state: Some(UploadState::Aborted),
+        upload_token: None,
+        quota_remaining,
+        chunk_urls: None,
+        chunk_urls_expire_at: None,
+    };
+    Ok(HttpResponse::Ok().json(resp))
+}
+
+/// Loads an uploaded file in `state` `caller` holds `permission` on, together with the
+/// name of the blob holding its data. Files of other callers are rejected with 403 unless
+/// `caller` is admin of that blob.
+async fn get_uploaded_file(
+    pool: &DbPool,
+    access: &AccessPolicy,
+    upload_id: &str,
+    state: FileState,
+    caller: &Caller,
+    permission: Permission,
+) -> WebAPIResult<(UploadedFile, String)> {
+    match catalog::find_uploaded_file(pool, upload_id, state).await? {
+        Some((file, blob_name))
+            if file.owner != caller.id && !access.grants_admin(caller, &blob_name) =>
+        {
+            error!(
+                "file {} of {} requested by {}",
+                upload_id, file.owner, caller.id
+            );
+            Err(ErrorResponse::with_status(
+                StatusCode::FORBIDDEN,
+                "file belongs to another caller",
+            ))
+        }
+        Some((file, blob_name)) => {
+            access.check(caller, permission, &blob_name)?;
+            Ok((file, blob_name))
+        }
+        None => {
+            error!("file not found: {}", upload_id);
+            Err(ErrorResponse::with_status(
+                StatusCode::NOT_FOUND,
+                "file not found",
+            ))
+        }
+    }
+}
+
+/// `attachment` with the original file name, as plain ASCII and as UTF-8 for clients
+/// that understand `filename*`.
+fn content_disposition(file_name: &str) -> ContentDisposition {
+    let name = file_name.rsplit('/').next().unwrap_or(file_name);
+    let ascii_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() && c != '"' && c != '\\' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![
+            DispositionParam::Filename(ascii_name),
+            DispositionParam::FilenameExt(ExtendedValue {
+                charset: Charset::Ext("UTF-8".to_string()),
+                language_tag: None,
+                value: name.as_bytes().to_vec(),
+            }),
+        ],
+    }
+}
+
+/// Streams `file` from `blob_name`, answering conditional and range requests. The
+/// content id serves as strong ETag, content is never changed in place, overwriting a
+/// blob records new content.
+async fn serve_file(
+    http_req: &HttpRequest,
+    config: &Config,
+    credentials: &StorageCredentials,
+    file: UploadedFile,
+    blob_name: &str,
+) -> WebAPIResult<HttpResponse> {
+    let etag = EntityTag::new_strong(file.content_id.clone());
+    let headers = http_req.headers();
+    if ranges::etag_matches(headers.get(IF_NONE_MATCH), &etag) {
+        return Ok(HttpResponse::NotModified()
+            .insert_header(ETag(etag))
+            .finish());
+    }
+
+    if !storage::blob_exists(config, credentials, blob_name).await? {
+        error!(
+            "content of file {} missing in storage: {}",
+            file.upload_id, blob_name
+        );
+        return Err(ErrorResponse::with_status(
+            StatusCode::NOT_FOUND,
+            "file content missing in storage",
+        ));
+    }
+    let size = file.file_size;
+    // a range only applies while the client still has the same content
+    let range = match headers.get(RANGE).and_then(|range| range.to_str().ok()) {
+        Some(_)
+            if headers.contains_key(IF_RANGE)
+                && !ranges::if_range_matches(headers.get(IF_RANGE), &etag) =>
+        {
+            ByteRange::Full
+        }
+        Some(range) => ranges::parse_range(range, size),
+        None => ByteRange::Full,
+    };
+    let (mut builder, start, end) = match range {
+        ByteRange::Full => (HttpResponse::Ok(), 0, size),
+        ByteRange::Partial { start, end } => {
+            let mut builder = HttpResponse::PartialContent();
+            builder.insert_header((
+                CONTENT_RANGE,
+                format!("bytes {}-{}/{}", start, end - 1, size),
+            ));
+            (builder, start, end)
+        }
+        ByteRange::Unsatisfiable => {
+            return Ok(HttpResponse::RangeNotSatisfiable()
+                .insert_header((CONTENT_RANGE, format!("bytes */{}", size)))
+                .insert_header(ETag(etag))
+                .finish());
+        }
+    };
+    debug!(
+        "serve_file {} bytes {}..{} of {}",
+        file.upload_id, start, end, blob_name
+    );
+
+    let blob_client = storage::blob_client(config, credentials, blob_name);
+    let body = storage::read_stream(config.clone(), blob_client, start, end)
+        .map_err(actix_web::Error::from);
+    Ok(builder
+        .insert_header((CONTENT_TYPE, file.content_type.as_str()))
+        .insert_header(content_disposition(&file.file_name))
+        .insert_header(ETag(etag))
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .no_chunking(end - start)
+        .streaming(body))
+}
+
+/// Downloads a completed upload the caller may read.
+#[instrument(skip(http_req, access))]
+pub async fn download_file(
+    http_req: HttpRequest,
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    shared_credentials: web::Data<SharedData>,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> WebAPIResult<HttpResponse> {
+    let upload_id = path.into_inner();
+    let (file, blob_name) = get_uploaded_file(
+        &pool,
+        &access,
+        &upload_id,
+        FileState::Active,
+        &caller,
+        Permission::Read,
+    )
+    .await?;
+    serve_file(
+        &http_req,
+        &config,
+        &shared_credentials.credentials,
+        file,
+        &blob_name,
+    )
+    .await
+}
+
+/// Lists completed uploads, by default those of the caller. Files of other owners need
+/// `admin` on `name_prefix`, for `owner=*` over every owner as well.
+#[instrument(skip(access))]
+pub async fn list_files(
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    pool: web::Data<DbPool>,
+    query: web::Query<ListFilesQuery>,
+) -> WebAPIResult<impl Responder> {
+    let query = query.into_inner();
+    let owner = match query.owner.as_deref() {
+        None => Some(caller.id.as_str()),
+        Some("*") => None,
+        Some(owner) => Some(owner),
+    };
+    if owner != Some(caller.id.as_str())
+        && !access.grants_admin_under(&caller, query.name_prefix.as_deref().unwrap_or(""))
+    {
+        error!("{} denied listing files of {:?}", caller.id, query.owner);
+        return Err(ErrorResponse::with_status(
+            StatusCode::FORBIDDEN,
+            "permission denied",
+        ));
+    }
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_LIST_LIMIT)
+        .clamp(1, MAX_LIST_LIMIT);
+    let (files, total) = catalog::list_uploaded_files(&pool, &query, owner, limit).await?;
+    Ok(HttpResponse::Ok().json(FileListResponse {
+        files,
+        total,
+        limit,
+        offset: query.offset,
+    }))
+}
+
+/// Creates a share link to a completed upload of the caller, usable without credentials.
+#[instrument(skip(http_req, access, shares, req))]
+pub async fn create_share(
+    http_req: HttpRequest,
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    shares: web::Data<ShareLinks>,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+    req: web::Json<CreateShareRequest>,
+) -> WebAPIResult<impl Responder> {
+    let upload_id = path.into_inner();
+    let (file, _) = get_uploaded_file(
+        &pool,
+        &access,
+        &upload_id,
+        FileState::Active,
+        &caller,
+        Permission::Read,
+    )
+    .await?;
+    let req = req.into_inner();
+    let share = shares
+        .create(
+            &pool,
+            &file.upload_id,
+            &caller.id,
+            req.expires_in_secs.map(Duration::from_secs),
+            req.max_downloads,
+            req.password.as_deref(),
+        )
+        .await?;
+    info!(
+        "share {} of {} created by {}",
+        share.share_id, upload_id, caller.id
+    );
+    let info = http_req.connection_info();
+    Ok(HttpResponse::Ok().json(ShareResponse {
+        url: format!(
+            "{}://{}/api/v1/shares/{}",
+            info.scheme(),
+            info.host(),
+            share.token
+        ),
+        share_id: share.share_id,
+        upload_id,
+        expires_at: share.expires_at,
+        max_downloads: req.max_downloads,
+        password_protected: req.password.is_some(),
+    }))
+}
+
+/// Downloads a file through a share link, no credentials needed. Every request serving
+/// content counts against the download limit, resumed ranges included.
+#[instrument(skip_all)]
+pub async fn download_share(
+    http_req: HttpRequest,
+    shares: web::Data<ShareLinks>,
+    shared_credentials: web::Data<SharedData>,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> WebAPIResult<HttpResponse> {
+    let upload_id = shares.redeem(&pool, &http_req, &path).await?;
+    let (file, blob_name) =
+        match catalog::find_uploaded_file(&pool, &upload_id, FileState::Active).await? {
+            Some(found) => found,
+            None => {
+                error!("shared file not found: {}", upload_id);
+                return Err(ErrorResponse::with_status(
+                    StatusCode::NOT_FOUND,
+                    "file not found",
+                ));
+            }
+        };
+    serve_file(
+        &http_req,
+        &config,
+        &shared_credentials.credentials,
+        file,
+        &blob_name,
+    )
+    .await
+}
+
+/// Deletes a file for good: its blob goes when no other file links to it any more, the
+/// row stays as a record. The blob is deleted first, so a failure leaves the file as it
+/// was and the delete can be repeated.
+async fn purge_file(
+    config: &Config,
+    credentials: &StorageCredentials,
+    pool: &DbPool,
+    file: &UploadedFile,
+    blob_name: &str,
+) -> WebAPIResult<()> {
+    if let Some(content) = catalog::sole_content(pool, file, blob_name).await? {
+        // no longer offered for deduplication, which would link to it again
+        catalog::unverify_content(pool, &content.content_id).await?;
+        let blob_client = storage::blob_client(config, credentials, blob_name);
+        storage::delete_blob(config, &blob_client).await?;
+    }
+    if catalog::mark_file_deleted(pool, &file.upload_id).await? {
+        info!(
+            "file {} of {} is {}",
+            file.upload_id,
+            file.owner,
+            FileState::Deleted.as_str()
+        );
+    }
+    Ok(())
+}
+
+/// Deletes a file, into the trash when a trash period is configured. `permanent` skips
+/// the trash and also deletes files already in it.
+#[instrument(skip(access))]
+pub async fn delete_file(
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    shared_credentials: web::Data<SharedData>,
+    config: web::Data<Config>,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+    query: web::Query<DeleteFileQuery>,
+) -> WebAPIResult<impl Responder> {
+    let upload_id = path.into_inner();
+    let permanent = query.permanent || config.trash_period_secs == 0;
+    let found = get_uploaded_file(
+        &pool,
+        &access,
+        &upload_id,
+        FileState::Active,
+        &caller,
+        Permission::Delete,
+    )
+    .await;
+    let (file, blob_name) = match found {
+        Err(e) if permanent && e.status == StatusCode::NOT_FOUND => {
+            get_uploaded_file(
+                &pool,
+                &access,
+                &upload_id,
+                FileState::Trashed,
+                &caller,
+                Permission::Delete,
+            )
+            .await?
+        }
+        found => found?,
+    };
+
+    if permanent {
+        purge_file(
+            &config,
+            &shared_credentials.credentials,
+            &pool,
+            &file,
+            &blob_name,
+        )
+        .await?;
+        return Ok(HttpResponse::Ok().json(DeleteFileResponse {
+            upload_id,
+            state: FileState::Deleted,
+            purge_at: None,
+        }));
+    }
+    let purge_at = unix_now() + config.trash_period_secs as i64;
+    if !catalog::trash_uploaded_file(&pool, &upload_id, purge_at).await? {
+        error!("file {} changed while being deleted", upload_id);
+        return Err(ErrorResponse::with_status(
+            StatusCode::CONFLICT,
+            "file changed while being deleted",
+        ));
+    }
+    info!(
+        "file {} of {} is {}",
+        upload_id,
+        file.owner,
+        FileState::Trashed.as_str()
+    );
+    Ok(HttpResponse::Ok().json(DeleteFileResponse {
+        upload_id,
+        state: FileState::Trashed,
+        purge_at: Some(purge_at),
+    }))
+}
+
+/// Takes a file out of the trash before its trash period is over.
+#[instrument(skip(access))]
+pub async fn restore_file(
+    caller: Caller,
+    access: web::Data<AccessPolicy>,
+    pool: web::Data<DbPool>,
+    path: web::Path<String>,
+) -> WebAPIResult<impl Responder> {
+    let upload_id = path.into_inner();
+    let (file, _) = get_uploaded_file(
+        &pool,
+        &access,
+        &upload_id,
+        FileState::Trashed,
+        &caller,
+        Permission::Delete,
+    )
+    .await?;
+    if !catalog::restore_uploaded_file(&pool, &upload_id).await? {
+        error!("file {} left the trash before its restore", upload_id);
+        return Err(ErrorResponse::with_status(
+            StatusCode::GONE,
+            "trash period is over",
+        ));
+    }
+    info!(
+        "file {} of {} is {}",
+        upload_id,
+        file.owner,
+        FileState::Active.as_str()
+    );
+    Ok(HttpResponse::Ok().json(DeleteFileResponse {
+        upload_id,
+        state: FileState::Active,
+        purge_at: None,
+    }))
+}
+
+/// Chunks being processed right now, across the server and per upload.
+pub async fn concurrency_stats(
+    _caller: Caller,
+    concurrency: web::Data<ConcurrencyLimits>,
+) -> WebAPIResult<impl Responder> {
+    Ok(HttpResponse::Ok().json(concurrency.stats()))
+}
+
+/// Trashed files purged by the sweeper per run, the rest waits for the next one.
+const TRASH_SWEEP_BATCH: u64 = 100;
+
+/// Background worker expiring uploads that saw no request for `idle`, their staged data
+/// is discarded and their quota given back. It also empties the trash of files whose
+/// trash period is over.
+pub async fn run_sweeper(
+    pool: DbPool,
+    sessions: Arc<dyn SessionStore>,
+    spool: Arc<Spool>,
+    config: Config,
+    credentials: StorageCredentials,
+    idle: Duration,
+    interval: Duration,
+) {
+    loop {
+        actix_web::rt::time::sleep(interval).await;
+        match sessions.sweep(idle.as_secs()).await {
+            Ok(expired) => {
+                for upload_info in expired {
+                    info!(
+                        "upload {} is {}",
+                        upload_info.upload_id,
+                        UploadState::Expired
+                    );
+                    discard_upload(&config, &credentials, &pool, &spool, &upload_info.upload_id)
+                        .await;
+                }
+            }
+            Err(e) => error!("sweep uploads failed: {}", e),
+        }
+        match catalog::find_expired_trash(&pool, TRASH_SWEEP_BATCH).await {
+            Ok(expired) => {
+                for (file, blob_name) in expired {
+                    let purged = purge_file(&config, &credentials, &pool, &file, &blob_name).await;
+                    if let Err(e) = purged {
+                        error!("purge file {} failed: {}", file.upload_id, e);
+                    }
+                }
+            }
+            Err(e) => error!("sweep trash failed: {}", e),
+        }
+        if let Ok(dropped) = shares::sweep_expired(&pool).await {
+            if dropped > 0 {
+                debug!("dropped {} expired share links", dropped);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::session::DbSessionStore;
+
+    async fn pick(
+        file_name: &str,
+        conflict_mode: ConflictMode,
+        taken: &[&str],
+    ) -> WebAPIResult<String> {
+        let taken: HashSet<String> = taken.iter().map(|name| name.to_string()).collect();
+        free_blob_name(file_name, conflict_mode, |name| {
+            let used = taken.contains(&name);
+            async move { Ok(used) }
+        })
+        .await
+    }
+
+    #[test]
+    fn suffix_goes_before_extension() {
+        assert_eq!(
+            suffixed_blob_name("data/report.csv", 1),
+            "data/report_1.csv"
+        );
+        assert_eq!(suffixed_blob_name("archive.tar.gz", 2), "archive.tar_2.gz");
+        assert_eq!(suffixed_blob_name("v1.0/README", 3), "v1.0/README_3");
+        assert_eq!(suffixed_blob_name(".env", 1), ".env_1");
+    }
+
+    #[actix_web::test]
+    async fn fail_mode_rejects_taken_name() {
+        assert_eq!(
+            pick("a.txt", ConflictMode::Fail, &[]).await.unwrap(),
+            "a.txt"
+        );
+        let err = pick("a.txt", ConflictMode::Fail, &["a.txt"])
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(err.error, "blob already exists");
+    }
+
+    #[actix_web::test]
+    async fn rename_mode_takes_first_free_suffix() {
+        assert_eq!(
+            pick("a.txt", ConflictMode::Rename, &[]).await.unwrap(),
+            "a.txt"
+        );
+        assert_eq!(
+            pick("a.txt", ConflictMode::Rename, &["a.txt", "a_1.txt"])
+                .await
+                .unwrap(),
+            "a_2.txt"
+        );
+    }
+
+    #[actix_web::test]
+    async fn rename_mode_gives_up() {
+        let mut taken = vec!["a.txt".to_string()];
+        taken.extend((1..=MAX_RENAME_ATTEMPTS).map(|n| suffixed_blob_name("a.txt", n)));
+        let taken: Vec<&str> = taken.iter().map(String::as_str).collect();
+        let err = pick("a.txt", ConflictMode::Rename, &taken)
+            .await
+            .unwrap_err();
+        assert_eq!(err.status, StatusCode::CONFLICT);
+        assert_eq!(err.error, "no free blob name found");
+    }
+
+    async fn chunked_upload() -> (DbPool, DbSessionStore, UploadInfo) {
+        let pool = DbPool::open(None).unwrap();
+        pool.migrate().await.unwrap();
+        let sessions = DbSessionStore::new(pool.clone());
+        let upload_info: UploadInfo = serde_json::from_value(serde_json::json!({
+            "upload_id": "u1",
+            "file_name": "a.bin",
+            "file_size": 10,
+            "file_hash": "",
+            "content_type": "application/octet-stream",
+            "blob_access_token": "",
+            "blob_file_hash": "",
+            "blob_name": "a.bin",
+            "conflict_mode": "fail",
+            "block_size": 4,
+            "chunk_size": 6,
+            "staged_size": 0,
+            "state": "uploading",
+            "owner": "alice",
+        }))
+        .unwrap();
+        sessions.create(&upload_info).await.unwrap();
+        (pool, sessions, upload_info)
+    }
+
+    async fn place(
+        upload: &(DbPool, DbSessionStore, UploadInfo),
+        chunk_index: Option<u64>,
+        data: &[u8],
+    ) -> WebAPIResult<Option<UploadChunk>> {
+        let (pool, sessions, upload_info) = upload;
+        place_chunk(pool, sessions, upload_info, chunk_index, data).await
+    }
+
+    #[actix_web::test]
+    async fn chunk_placed_behind_the_ledger() {
+        let upload = chunked_upload().await;
+        let chunk = place(&upload, Some(0), b"abcdef").await.unwrap().unwrap();
+        assert_eq!((chunk.chunk_index, chunk.chunk_offset), (0, 0));
+        // what write_chunk and the ledger store for it
+        upload.1.save_progress("u1", 4, b"ef").await.unwrap();
+        ledger::record_chunk(&upload.0, &chunk).await.unwrap();
+
+        assert!(place(&upload, Some(0), b"abcdef").await.unwrap().is_none());
+        let e = place(&upload, Some(0), b"abcdeg").await.unwrap_err();
+        assert_eq!(e.status, StatusCode::CONFLICT);
+        let e = place(&upload, Some(2), b"ghij").await.unwrap_err();
+        assert_eq!(e.status, StatusCode::CONFLICT);
+        let next = place(&upload, Some(1), b"ghij").await.unwrap().unwrap();
+        assert_eq!((next.chunk_index, next.chunk_offset), (1, 6));
+        assert_eq!(next.block_id, chunking::block_id(1));
+    }
+
+    #[actix_web::test]
+    async fn chunk_stored_without_ledger_row_not_written_again() {
+        for chunk_index in [Some(0), None] {
+            let upload = chunked_upload().await;
+            assert!(place(&upload, chunk_index, b"abcdef")
+                .await
+                .unwrap()
+                .is_some());
+            // write_chunk stored the chunk, then recording it in the ledger failed
+            upload.1.save_progress("u1", 4, b"ef").await.unwrap();
+
+            // the retry must not append the chunk a second time
+            assert!(place(&upload, chunk_index, b"abcdef")
+                .await
+                .unwrap()
+                .is_none());
+            let recorded = ledger::find_chunk(&upload.0, "u1", 0)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((recorded.chunk_offset, recorded.chunk_size), (0, 6));
+            let next = place(&upload, chunk_index.map(|_| 1), b"ghij")
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!((next.chunk_index, next.chunk_offset), (1, 6));
+        }
+    }
+
+    #[actix_web::test]
+    async fn stored_chunk_of_other_size_rejected() {
+        let upload = chunked_upload().await;
+        upload.1.save_progress("u1", 4, b"ef").await.unwrap();
+        let e = place(&upload, Some(0), b"abcde").await.unwrap_err();
+        assert_eq!(e.status, StatusCode::CONFLICT);
+        assert!(ledger::last_chunk(&upload.0, "u1").await.unwrap().is_none());
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::{BTreeMap, HashSet};
+
+use actix_web::http::StatusCode;
+use tracing::error;
+
+use crate::models::{ErrorResponse, WebAPIResult};
+
+/// Azure caps the names and values of all metadata of a blob together at 8 KiB.
+pub const MAX_METADATA_BYTES: usize = 8 * 1024;
+pub const MAX_TAGS: usize = 10;
+pub const MAX_TAG_KEY_LEN: usize = 128;
+pub const MAX_TAG_VALUE_LEN: usize = 256;
+
+fn invalid(error: &str) -> ErrorResponse {
+    error!("{}", error);
+    ErrorResponse::with_status(StatusCode::BAD_REQUEST, error)
+}
+
+/// Metadata names are C# identifiers and case insensitive, values go into HTTP headers
+/// and must be printable ASCII.
+pub fn validate_metadata(metadata: &BTreeMap<String, String>) -> WebAPIResult<()> {
+    let mut names = HashSet::new();
+    let mut size = 0;
+    for (name, value) in metadata {
+        let mut chars = name.chars();
+        let is_identifier = chars
+            .next()
+            .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+        if !is_identifier {
+            return Err(invalid(&format!("invalid metadata name: {}", name)));
+        }
+        if !names.insert(name.to_ascii_lowercase()) {
+            return Err(invalid(&format!("duplicate metadata name: {}", name)));
+        }
+        if !value.chars().all(|c| c == ' ' || c.is_ascii_graphic()) {
+            return Err(invalid(&format!("invalid value of metadata {}", name)));
+        }
+        size += name.len() + value.len();
+    }
+    if size > MAX_METADATA_BYTES {
+        return Err(invalid(&format!(
+            "metadata larger than {} bytes",
+            MAX_METADATA_BYTES
+        )));
+    }
+    Ok(())
+}
+
+/// Characters blob index tags allow in keys and values.
+fn is_tag_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || " +-./:=_".contains(c)
+}
+
+/// Tags follow the rules of Azure blob index tags, so they can be searched there.
+pub fn validate_tags(tags: &BTreeMap<String, String>) -> WebAPIResult<()> {
+    if tags.len() > MAX_TAGS {
+        return Err(invalid(&format!("more than {} tags", MAX_TAGS)));
+    }
+    for (key, value) in tags {
+        if key.is_empty() || key.len() > MAX_TAG_KEY_LEN || !key.chars().all(is_tag_char) {
+            return Err(invalid(&format!("invalid tag key: {}", key)));
+        }
+        if value.len() > MAX_TAG_VALUE_LEN || !value.chars().all(is_tag_char) {
+            return Err(invalid(&format!("invalid value of tag {}", key)));
+        }
+    }
+    Ok(())
+}
+
+/// Metadata and tags are stored as JSON objects.
+pub fn to_json(map: &BTreeMap<String, String>) -> String {
+    serde_json::to_string(map).unwrap_or_else(|_| "{}".to_string())
+}
+
+pub fn from_json(json: &str) -> BTreeMap<String, String> {
+    serde_json::from_str(json).unwrap_or_default()
+}
@@ -0,0 +1,822 @@
+use tracing::error;
+
+use crate::attributes;
+use crate::db::{unix_now, DbError, DbPool, DbRow, Value};
+use crate::models::{
+    ErrorResponse, FileContent, FileEntry, FileSort, FileState, FileStatus, ListFilesQuery,
+    SortOrder, UploadedFile, WebAPIResult,
+};
+use crate::quota;
+use crate::storage;
+
+const CONTENT_COLUMNS: &str = "content_id, file_hash, file_size, blob_name, verified, ref_count";
+
+fn content_from_row(row: &DbRow) -> Result<FileContent, DbError> {
+    Ok(FileContent {
+        content_id: row.get(0)?,
+        file_hash: row.get(1)?,
+        file_size: row.get(2)?,
+        blob_name: row.get(3)?,
+        verified: row.get(4)?,
+        ref_count: row.get(5)?,
+    })
+}
+
+fn db_error(context: &str, e: DbError) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+async fn query_content(
+    pool: &DbPool,
+    sql: &str,
+    params: &[Value],
+) -> WebAPIResult<Option<FileContent>> {
+    let res = match pool.query_opt(sql, params).await {
+        Ok(Some(row)) => content_from_row(&row).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    res.map_err(|e| db_error("query content failed", e))
+}
+
+/// Finds content with the same hash and size whose hash was checked by the server.
+/// Only content `owner` uploaded before is offered, otherwise knowing a hash would be
+/// enough to get a copy of someone else's file.
+pub async fn find_verified_content(
+    pool: &DbPool,
+    file_hash: &str,
+    file_size: u64,
+    owner: &str,
+) -> WebAPIResult<Option<FileContent>> {
+    query_content(
+        pool,
+        &format!(
+            r#"
+            SELECT {} FROM file_contents
+            WHERE file_hash = $1 AND file_size = $2 AND verified = $3 AND content_id IN (
+                SELECT content_id FROM uploaded_files WHERE owner = $4 AND state <> 'deleted'
+            )
+            LIMIT 1;
+        "#,
+            CONTENT_COLUMNS
+        ),
+        &[
+            file_hash.to_lowercase().into(),
+            file_size.into(),
+            true.into(),
+            owner.into(),
+        ],
+    )
+    .await
+}
+
+pub async fn find_content_by_blob(
+    pool: &DbPool,
+    blob_name: &str,
+) -> WebAPIResult<Option<FileContent>> {
+    query_content(
+        pool,
+        &format!(
+            "SELECT {} FROM file_contents WHERE blob_name = $1;",
+            CONTENT_COLUMNS
+        ),
+        &[blob_name.into()],
+    )
+    .await
+}
+
+/// The content in `blob_name` when `file` is the last file linked to it, so the blob can
+/// go with the file.
+pub async fn sole_content(
+    pool: &DbPool,
+    file: &UploadedFile,
+    blob_name: &str,
+) -> WebAPIResult<Option<FileContent>> {
+    Ok(find_content_by_blob(pool, blob_name)
+        .await?
+        .filter(|content| content.content_id == file.content_id && content.ref_count <= 1))
+}
+
+/// Stops offering content for deduplication, e.g. when its blob disappeared from storage.
+pub async fn unverify_content(pool: &DbPool, content_id: &str) -> WebAPIResult<()> {
+    pool.execute(
+        "UPDATE file_contents SET verified = $2 WHERE content_id = $1;",
+        &[content_id.into(), false.into()],
+    )
+    .await
+    .map(|_| ())
+    .map_err(|e| db_error("update content failed", e))
+}
+
+fn uploaded_file_from_row(row: &DbRow) -> Result<UploadedFile, DbError> {
+    Ok(UploadedFile {
+        upload_id: row.get(0)?,
+        file_name: row.get(1)?,
+        file_size: row.get(2)?,
+        file_hash: row.get(3)?,
+        content_type: row.get(4)?,
+        content_id: row.get(5)?,
+        deduplicated: row.get(6)?,
+        owner: row.get(7)?,
+        tenant: row.get(8)?,
+        created_at: row.get(9)?,
+        metadata: attributes::from_json(&row.get::<String>(10)?),
+        tags: attributes::from_json(&row.get::<String>(11)?),
+        access_tier: row
+            .get::<Option<String>>(12)?
+            .and_then(|tier| tier.parse().ok()),
+    })
+}
+
+const INSERT_UPLOADED_FILE: &str = r#"
+    INSERT INTO uploaded_files(
+        upload_id,
+        file_name,
+        file_size,
+        file_hash,
+        content_type,
+        content_id,
+        deduplicated,
+        owner,
+        tenant,
+        created_at,
+        metadata,
+        tags,
+        access_tier
+    ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13);
+"#;
+
+fn uploaded_file_params(file: &UploadedFile) -> Vec<Value> {
+    vec![
+        (&file.upload_id).into(),
+        (&file.file_name).into(),
+        file.file_size.into(),
+        (&file.file_hash).into(),
+        (&file.content_type).into(),
+        (&file.content_id).into(),
+        file.deduplicated.into(),
+        (&file.owner).into(),
+        file.tenant.as_deref().into(),
+        file.created_at.into(),
+        attributes::to_json(&file.metadata).into(),
+        attributes::to_json(&file.tags).into(),
+        file.access_tier.map(|tier| tier.as_str()).into(),
+    ]
+}
+
+/// Records a new uploaded file that reuses existing content instead of its own upload.
+pub async fn link_uploaded_file(pool: &DbPool, file: &UploadedFile) -> WebAPIResult<()> {
+    pool.transaction(&[
+        (
+            "UPDATE file_contents SET ref_count = ref_count + 1 WHERE content_id = $1;",
+            vec![(&file.content_id).into()],
+        ),
+        (INSERT_UPLOADED_FILE, uploaded_file_params(file)),
+    ])
+    .await
+    .map(|_| ())
+    .map_err(|e| db_error("link uploaded file failed", e))
+}
+
+/// Where the content records of overwritten blobs are moved, inside the staging prefix
+/// that no uploaded file can be named in, so the blob name is free for the new content.
+fn overwritten_prefix() -> String {
+    format!("{}overwritten/", storage::STAGING_PREFIX)
+}
+
+/// Records freshly promoted content and the uploaded file pointing to it, which also turns
+/// the quota reserved by the upload into stored bytes.
+/// Content previously stored under the same blob name was overwritten, the files that
+/// referenced it are kept as deleted, with their history.
+pub async fn publish_uploaded_file(
+    pool: &DbPool,
+    content: &FileContent,
+    file: &UploadedFile,
+) -> WebAPIResult<()> {
+    let insert_content = format!(
+        "INSERT INTO file_contents({}) VALUES ($1, $2, $3, $4, $5, $6);",
+        CONTENT_COLUMNS
+    );
+    pool.transaction(&[
+        (
+            r#"
+                UPDATE uploaded_files
+                SET state = 'deleted', deleted_at = COALESCE(deleted_at, $2), purge_at = NULL
+                WHERE state <> 'deleted' AND content_id IN (
+                    SELECT content_id FROM file_contents WHERE blob_name = $1
+                );
+            "#,
+            vec![(&content.blob_name).into(), unix_now().into()],
+        ),
+        (
+            r#"
+                UPDATE file_contents
+                SET blob_name = $2 || content_id, verified = $3, ref_count = 0
+                WHERE blob_name = $1;
+            "#,
+            vec![
+                (&content.blob_name).into(),
+                overwritten_prefix().into(),
+                false.into(),
+            ],
+        ),
+        (
+            insert_content.as_str(),
+            vec![
+                (&content.content_id).into(),
+                content.file_hash.to_lowercase().into(),
+                content.file_size.into(),
+                (&content.blob_name).into(),
+                content.verified.into(),
+                content.ref_count.into(),
+            ],
+        ),
+        (INSERT_UPLOADED_FILE, uploaded_file_params(file)),
+        (quota::RELEASE_RESERVATION, vec![(&file.upload_id).into()]),
+    ])
+    .await
+    .map(|_| ())
+    .map_err(|e| db_error("publish uploaded file failed", e))
+}
+
+/// Owners of the active and trashed files whose data is in the blob `blob_name`.
+pub async fn file_owners_at(pool: &DbPool, blob_name: &str) -> WebAPIResult<Vec<String>> {
+    let rows = pool
+        .query(
+            r#"
+                SELECT DISTINCT f.owner FROM uploaded_files f
+                JOIN file_contents c ON c.content_id = f.content_id
+                WHERE c.blob_name = $1 AND f.state IN ('active', 'trashed');
+            "#,
+            &[blob_name.into()],
+        )
+        .await
+        .map_err(|e| db_error("query file owners failed", e))?;
+    rows.iter()
+        .map(|row| row.get(0))
+        .collect::<Result<Vec<String>, DbError>>()
+        .map_err(|e| db_error("query file owners failed", e))
+}
+
+/// Columns read by `uploaded_file_from_row` followed by the blob name, from
+/// `uploaded_files f` joined with `file_contents c`.
+const FILE_COLUMNS: &str = r#"
+    f.upload_id,
+    f.file_name,
+    f.file_size,
+    f.file_hash,
+    f.content_type,
+    f.content_id,
+    f.deduplicated,
+    f.owner,
+    f.tenant,
+    f.created_at,
+    f.metadata,
+    f.tags,
+    f.access_tier,
+    c.blob_name
+"#;
+
+fn file_and_blob_from_row(row: &DbRow) -> Result<(UploadedFile, String), DbError> {
+    Ok((uploaded_file_from_row(row)?, row.get(13)?))
+}
+
+/// An uploaded file in `state` and the name of the blob holding its data.
+pub async fn find_uploaded_file(
+    pool: &DbPool,
+    upload_id: &str,
+    state: FileState,
+) -> WebAPIResult<Option<(UploadedFile, String)>> {
+    let res = pool
+        .query_opt(
+            &format!(
+                r#"
+                SELECT {} FROM uploaded_files f
+                JOIN file_contents c ON c.content_id = f.content_id
+                WHERE f.upload_id = $1 AND f.state = $2;
+            "#,
+                FILE_COLUMNS
+            ),
+            &[upload_id.into(), state.as_str().into()],
+        )
+        .await;
+    let res = match res {
+        Ok(Some(row)) => file_and_blob_from_row(&row).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    res.map_err(|e| db_error("query uploaded file failed", e))
+}
+
+/// Moves an active file to the trash until `purge_at`. False when it was not active.
+pub async fn trash_uploaded_file(
+    pool: &DbPool,
+    upload_id: &str,
+    purge_at: i64,
+) -> WebAPIResult<bool> {
+    pool.execute(
+        r#"
+            UPDATE uploaded_files SET state = 'trashed', deleted_at = $2, purge_at = $3
+            WHERE upload_id = $1 AND state = 'active';
+        "#,
+        &[upload_id.into(), unix_now().into(), purge_at.into()],
+    )
+    .await
+    .map(|count| count > 0)
+    .map_err(|e| db_error("trash uploaded file failed", e))
+}
+
+/// Takes a file back out of the trash. False when it was not in the trash or its trash
+/// period is over.
+pub async fn restore_uploaded_file(pool: &DbPool, upload_id: &str) -> WebAPIResult<bool> {
+    pool.execute(
+        r#"
+            UPDATE uploaded_files SET state = 'active', deleted_at = NULL, purge_at = NULL
+            WHERE upload_id = $1 AND state = 'trashed' AND purge_at > $2;
+        "#,
+        &[upload_id.into(), unix_now().into()],
+    )
+    .await
+    .map(|count| count > 0)
+    .map_err(|e| db_error("restore uploaded file failed", e))
+}
+
+/// Marks an active or trashed file deleted and drops its reference to the content, both
+/// or neither. False when the file was deleted already.
+pub async fn mark_file_deleted(pool: &DbPool, upload_id: &str) -> WebAPIResult<bool> {
+    // 'purging' only exists inside the transaction, it lets the other statements see
+    // whether the first one changed the file
+    let counts = pool
+        .transaction(&[
+            (
+                r#"
+                    UPDATE uploaded_files
+                    SET state = 'purging', deleted_at = COALESCE(deleted_at, $2), purge_at = NULL
+                    WHERE upload_id = $1 AND state IN ('active', 'trashed');
+                "#,
+                vec![upload_id.into(), unix_now().into()],
+            ),
+            (
+                r#"
+                    UPDATE file_contents SET ref_count = ref_count - 1 WHERE content_id IN (
+                        SELECT content_id FROM uploaded_files
+                        WHERE upload_id = $1 AND state = 'purging'
+                    );
+                "#,
+                vec![upload_id.into()],
+            ),
+            (
+                r#"
+                    UPDATE uploaded_files SET state = 'deleted'
+                    WHERE upload_id = $1 AND state = 'purging';
+                "#,
+                vec![upload_id.into()],
+            ),
+        ])
+        .await
+        .map_err(|e| db_error("delete uploaded file failed", e))?;
+    Ok(counts.first() == Some(&1))
+}
+
+/// Trashed files whose trash period is over, at most `limit` of them.
+pub async fn find_expired_trash(
+    pool: &DbPool,
+    limit: u64,
+) -> WebAPIResult<Vec<(UploadedFile, String)>> {
+    let rows = pool
+        .query(
+            &format!(
+                r#"
+                SELECT {} FROM uploaded_files f
+                JOIN file_contents c ON c.content_id = f.content_id
+                WHERE f.state = 'trashed' AND f.purge_at <= $1
+                LIMIT $2;
+            "#,
+                FILE_COLUMNS
+            ),
+            &[unix_now().into(), limit.into()],
+        )
+        .await
+        .map_err(|e| db_error("query expired trash failed", e))?;
+    rows.iter()
+        .map(file_and_blob_from_row)
+        .collect::<Result<Vec<_>, DbError>>()
+        .map_err(|e| db_error("query expired trash failed", e))
+}
+
+/// `column` starts with `$param`, compared as plain strings. `LIKE` would need escaping and
+/// is case sensitive in Postgres only.
+fn starts_with_sql(column: &str, param: usize) -> String {
+    format!(
+        "SUBSTR({column}, 1, LENGTH(CAST(${param} AS TEXT))) = CAST(${param} AS TEXT)",
+        column = column,
+        param = param
+    )
+}
+
+/// Uploaded files matching `query`, one page of them in the requested order, and how
+/// many match in total. `owner` of `None` lists every owner.
+pub async fn list_uploaded_files(
+    pool: &DbPool,
+    query: &ListFilesQuery,
+    owner: Option<&str>,
+    limit: u64,
+) -> WebAPIResult<(Vec<FileEntry>, u64)> {
+    let mut conditions = Vec::new();
+    let mut params: Vec<Value> = Vec::new();
+    if let Some(owner) = owner {
+        params.push(owner.into());
+        conditions.push(format!("f.owner = ${}", params.len()));
+    }
+    if let Some(name_prefix) = &query.name_prefix {
+        params.push(name_prefix.into());
+        conditions.push(starts_with_sql("f.file_name", params.len()));
+    }
+    if let Some(content_type) = &query.content_type {
+        match content_type.strip_suffix("/*") {
+            Some(major) => {
+                params.push(format!("{}/", major).into());
+                conditions.push(starts_with_sql("f.content_type", params.len()));
+            }
+            None => {
+                params.push(content_type.into());
+                conditions.push(format!("f.content_type = ${}", params.len()));
+            }
+        }
+    }
+    if let Some(min_size) = query.min_size {
+        params.push(min_size.into());
+        conditions.push(format!("f.file_size >= ${}", params.len()));
+    }
+    if let Some(max_size) = query.max_size {
+        params.push(max_size.into());
+        conditions.push(format!("f.file_size <= ${}", params.len()));
+    }
+    if let Some(created_after) = query.created_after {
+        params.push(created_after.into());
+        conditions.push(format!("f.created_at >= ${}", params.len()));
+    }
+    if let Some(created_before) = query.created_before {
+        params.push(created_before.into());
+        conditions.push(format!("f.created_at < ${}", params.len()));
+    }
+    params.push(query.state.as_str().into());
+    conditions.push(format!("f.state = ${}", params.len()));
+    if let Some(status) = query.status {
+        params.push((status == FileStatus::Verified).into());
+        conditions.push(format!("c.verified = ${}", params.len()));
+    }
+    let filter = format!("WHERE {}", conditions.join(" AND "));
+
+    let total = pool
+        .query_opt(
+            &format!(
+                r#"
+                SELECT COUNT(*) FROM uploaded_files f
+                JOIN file_contents c ON c.content_id = f.content_id
+                {};
+            "#,
+                filter
+            ),
+            &params,
+        )
+        .await
+        .and_then(|row| row.map_or(Ok(0), |row| row.get::<u64>(0)))
+        .map_err(|e| db_error("count uploaded files failed", e))?;
+
+    let sort_column = match query.sort {
+        FileSort::Created => "f.created_at",
+        FileSort::Name => "f.file_name",
+        FileSort::Size => "f.file_size",
+    };
+    let order = match query.order {
+        SortOrder::Asc => "ASC",
+        SortOrder::Desc => "DESC",
+    };
+    let mut page_params = params.clone();
+    page_params.push(limit.into());
+    page_params.push(query.offset.into());
+    let rows = pool
+        .query(
+            &format!(
+                r#"
+                SELECT {columns}, c.verified, f.state, f.deleted_at, f.purge_at
+                FROM uploaded_files f JOIN file_contents c ON c.content_id = f.content_id
+                {filter}
+                ORDER BY {sort_column} {order}, f.upload_id {order}
+                LIMIT ${limit} OFFSET ${offset};
+            "#,
+                columns = FILE_COLUMNS,
+                filter = filter,
+                sort_column = sort_column,
+                order = order,
+                limit = params.len() + 1,
+                offset = params.len() + 2,
+            ),
+            &page_params,
+        )
+        .await
+        .map_err(|e| db_error("list uploaded files failed", e))?;
+    let files = rows
+        .iter()
+        .map(|row| {
+            let (file, blob_name) = file_and_blob_from_row(row)?;
+            Ok(FileEntry {
+                file,
+                blob_name,
+                status: if row.get::<bool>(14)? {
+                    FileStatus::Verified
+                } else {
+                    FileStatus::Unverified
+                },
+                state: row.get::<String>(15)?.parse().unwrap_or(FileState::Deleted),
+                deleted_at: row.get(16)?,
+                purge_at: row.get(17)?,
+            })
+        })
+        .collect::<Result<Vec<_>, DbError>>()
+        .map_err(|e| db_error("list uploaded files failed", e))?;
+    Ok((files, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn pool() -> DbPool {
+        let pool = DbPool::open(None).unwrap();
+        pool.migrate().await.unwrap();
+        pool
+    }
+
+    fn content(content_id: &str, blob_name: &str) -> FileContent {
+        FileContent {
+            content_id: content_id.to_string(),
+            file_hash: format!("{}ff", content_id),
+            file_size: 10,
+            blob_name: blob_name.to_string(),
+            verified: true,
+            ref_count: 1,
+        }
+    }
+
+    fn file(upload_id: &str, content_id: &str) -> UploadedFile {
+        UploadedFile {
+            upload_id: upload_id.to_string(),
+            file_name: "a.txt".to_string(),
+            file_size: 10,
+            file_hash: format!("{}ff", content_id),
+            content_type: "text/plain".to_string(),
+            content_id: content_id.to_string(),
+            deduplicated: false,
+            owner: "alice".to_string(),
+            tenant: None,
+            created_at: unix_now(),
+            metadata: Default::default(),
+            tags: Default::default(),
+            access_tier: None,
+        }
+    }
+
+    async fn ref_count(pool: &DbPool, blob_name: &str) -> u64 {
+        find_content_by_blob(pool, blob_name)
+            .await
+            .unwrap()
+            .unwrap()
+            .ref_count
+    }
+
+    async fn file_state(pool: &DbPool, upload_id: &str) -> Option<(FileState, String)> {
+        for state in [FileState::Active, FileState::Trashed, FileState::Deleted] {
+            if let Some((_, blob_name)) = find_uploaded_file(pool, upload_id, state).await.unwrap()
+            {
+                return Some((state, blob_name));
+            }
+        }
+        None
+    }
+
+    #[actix_web::test]
+    async fn content_kept_until_last_linked_file_deleted() {
+        let pool = pool().await;
+        let first = file("u1", "c1");
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &first)
+            .await
+            .unwrap();
+        let second = UploadedFile {
+            upload_id: "u2".to_string(),
+            deduplicated: true,
+            ..first.clone()
+        };
+        link_uploaded_file(&pool, &second).await.unwrap();
+        assert_eq!(ref_count(&pool, "a.txt").await, 2);
+        assert!(sole_content(&pool, &first, "a.txt")
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(mark_file_deleted(&pool, "u1").await.unwrap());
+        // deleting again must not drop the reference twice
+        assert!(!mark_file_deleted(&pool, "u1").await.unwrap());
+        assert_eq!(ref_count(&pool, "a.txt").await, 1);
+        let sole = sole_content(&pool, &second, "a.txt").await.unwrap();
+        assert_eq!(sole.unwrap().content_id, "c1");
+
+        assert!(mark_file_deleted(&pool, "u2").await.unwrap());
+        assert_eq!(ref_count(&pool, "a.txt").await, 0);
+    }
+
+    #[actix_web::test]
+    async fn verified_content_offered_to_its_owner_only() {
+        let pool = pool().await;
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &file("u1", "c1"))
+            .await
+            .unwrap();
+        let found = find_verified_content(&pool, "C1FF", 10, "alice")
+            .await
+            .unwrap();
+        assert_eq!(found.unwrap().content_id, "c1");
+        assert!(find_verified_content(&pool, "c1ff", 10, "bob")
+            .await
+            .unwrap()
+            .is_none());
+        assert!(find_verified_content(&pool, "c1ff", 11, "alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn overwrite_keeps_replaced_file_as_deleted() {
+        let pool = pool().await;
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &file("u1", "c1"))
+            .await
+            .unwrap();
+        publish_uploaded_file(&pool, &content("c2", "a.txt"), &file("u2", "c2"))
+            .await
+            .unwrap();
+
+        let current = find_content_by_blob(&pool, "a.txt").await.unwrap().unwrap();
+        assert_eq!(current.content_id, "c2");
+        assert_eq!(
+            file_state(&pool, "u2").await,
+            Some((FileState::Active, "a.txt".to_string()))
+        );
+        assert_eq!(
+            file_state(&pool, "u1").await,
+            Some((FileState::Deleted, format!("{}c1", overwritten_prefix())))
+        );
+        // the replaced content is gone from storage, it must not be linked again
+        assert!(find_verified_content(&pool, "c1ff", 10, "alice")
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[actix_web::test]
+    async fn trashed_file_restored() {
+        let pool = pool().await;
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &file("u1", "c1"))
+            .await
+            .unwrap();
+        assert!(trash_uploaded_file(&pool, "u1", unix_now() + 3600)
+            .await
+            .unwrap());
+        assert!(!trash_uploaded_file(&pool, "u1", unix_now() + 3600)
+            .await
+            .unwrap());
+        assert!(find_expired_trash(&pool, 10).await.unwrap().is_empty());
+
+        assert!(restore_uploaded_file(&pool, "u1").await.unwrap());
+        assert_eq!(
+            file_state(&pool, "u1").await,
+            Some((FileState::Active, "a.txt".to_string()))
+        );
+        assert!(!restore_uploaded_file(&pool, "u1").await.unwrap());
+    }
+
+    #[actix_web::test]
+    async fn expired_trash_not_restored() {
+        let pool = pool().await;
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &file("u1", "c1"))
+            .await
+            .unwrap();
+        assert!(trash_uploaded_file(&pool, "u1", unix_now() - 1)
+            .await
+            .unwrap());
+        assert!(!restore_uploaded_file(&pool, "u1").await.unwrap());
+        let expired = find_expired_trash(&pool, 10).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0.upload_id, "u1");
+    }
+
+    #[actix_web::test]
+    async fn trashed_file_not_restored_over_reused_name() {
+        let pool = pool().await;
+        publish_uploaded_file(&pool, &content("c1", "a.txt"), &file("u1", "c1"))
+            .await
+            .unwrap();
+        assert!(trash_uploaded_file(&pool, "u1", unix_now() + 3600)
+            .await
+            .unwrap());
+        publish_uploaded_file(&pool, &content("c2", "a.txt"), &file("u2", "c2"))
+            .await
+            .unwrap();
+
+        assert!(!restore_uploaded_file(&pool, "u1").await.unwrap());
+        assert_eq!(
+            file_state(&pool, "u2").await,
+            Some((FileState::Active, "a.txt".to_string()))
+        );
+        assert_eq!(
+            file_state(&pool, "u1").await.map(|(state, _)| state),
+            Some(FileState::Deleted)
+        );
+        assert!(find_expired_trash(&pool, 10).await.unwrap().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn files_listed_by_filter_and_page() {
+        let pool = pool().await;
+        let entries = [
+            ("u1", "alice", "docs/a.txt", "text/plain", 10, 100),
+            ("u2", "alice", "docs/b.csv", "text/csv", 20, 200),
+            ("u3", "alice", "img/c.png", "image/png", 30, 300),
+            ("u4", "bob", "docs/d.txt", "text/plain", 40, 400),
+        ];
+        for (upload_id, owner, blob_name, content_type, file_size, created_at) in entries {
+            let content_id = format!("c{}", upload_id);
+            let content = FileContent {
+                file_size,
+                ..content(&content_id, blob_name)
+            };
+            let uploaded = UploadedFile {
+                file_name: blob_name.to_string(),
+                file_size,
+                content_type: content_type.to_string(),
+                owner: owner.to_string(),
+                created_at,
+                ..file(upload_id, &content_id)
+            };
+            publish_uploaded_file(&pool, &content, &uploaded)
+                .await
+                .unwrap();
+        }
+        let ids = |files: &[FileEntry]| -> Vec<String> {
+            files
+                .iter()
+                .map(|entry| entry.file.upload_id.clone())
+                .collect()
+        };
+
+        let query = ListFilesQuery::default();
+        let (files, total) = list_uploaded_files(&pool, &query, Some("alice"), 2)
+            .await
+            .unwrap();
+        assert_eq!((ids(&files), total), (vec!["u3".into(), "u2".into()], 3));
+        let query = ListFilesQuery {
+            offset: 2,
+            ..Default::default()
+        };
+        let (files, _) = list_uploaded_files(&pool, &query, Some("alice"), 2)
+            .await
+            .unwrap();
+        assert_eq!(ids(&files), vec!["u1".to_string()]);
+
+        let query = ListFilesQuery {
+            name_prefix: Some("docs/".to_string()),
+            content_type: Some("text/*".to_string()),
+            sort: FileSort::Size,
+            order: SortOrder::Asc,
+            ..Default::default()
+        };
+        let (files, total) = list_uploaded_files(&pool, &query, None, 10).await.unwrap();
+        assert_eq!(
+            (ids(&files), total),
+            (vec!["u1".into(), "u2".into(), "u4".into()], 3)
+        );
+
+        let query = ListFilesQuery {
+            min_size: Some(20),
+            created_before: Some(300),
+            ..Default::default()
+        };
+        let (files, _) = list_uploaded_files(&pool, &query, None, 10).await.unwrap();
+        assert_eq!(ids(&files), vec!["u2".to_string()]);
+
+        assert!(trash_uploaded_file(&pool, "u1", unix_now() + 3600)
+            .await
+            .unwrap());
+        let query = ListFilesQuery {
+            state: FileState::Trashed,
+            ..Default::default()
+        };
+        let (files, total) = list_uploaded_files(&pool, &query, Some("alice"), 10)
+            .await
+            .unwrap();
+        assert_eq!((ids(&files), total), (vec!["u1".into()], 1));
+        assert_eq!(files[0].state, FileState::Trashed);
+        assert_eq!(files[0].status, FileStatus::Verified);
+    }
+}
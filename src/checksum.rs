@@ -0,0 +1,183 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use md5::Md5;
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Md5,
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// Works out which algorithm produced a hex digest sent by a client from its length.
+    /// Anything that is not a hex MD5 or SHA-256 digest cannot be verified.
+    pub fn from_hex_digest(digest: &str) -> Option<HashAlgorithm> {
+        if !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+            return None;
+        }
+        match digest.len() {
+            32 => Some(HashAlgorithm::Md5),
+            64 => Some(HashAlgorithm::Sha256),
+            _ => None,
+        }
+    }
+}
+
+pub enum Hasher {
+    Md5(Md5),
+    Sha256(Sha256),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        match algorithm {
+            HashAlgorithm::Md5 => Hasher::Md5(Md5::new()),
+            HashAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Md5(hasher) => hasher.update(data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Md5(hasher) => hex::encode(hasher.finalize()),
+            Hasher::Sha256(hasher) => hex::encode(hasher.finalize()),
+        }
+    }
+}
+
+/// A digest sent by the client for a single chunk, checked before the chunk is written.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ChunkDigest {
+    Md5([u8; 16]),
+    Crc32c(u32),
+    Sha256([u8; 32]),
+}
+
+/// Accepts a digest either hex encoded or base64 encoded (the `Content-MD5` form).
+fn decode_digest<const N: usize>(value: &str) -> Option<[u8; N]> {
+    let value = value.trim();
+    let bytes = if value.len() == N * 2 && value.chars().all(|c| c.is_ascii_hexdigit()) {
+        hex::decode(value).ok()?
+    } else {
+        STANDARD.decode(value).ok()?
+    };
+    bytes.try_into().ok()
+}
+
+impl ChunkDigest {
+    pub fn parse_md5(value: &str) -> Option<ChunkDigest> {
+        decode_digest::<16>(value).map(ChunkDigest::Md5)
+    }
+
+    pub fn parse_crc32c(value: &str) -> Option<ChunkDigest> {
+        decode_digest::<4>(value).map(|bytes| ChunkDigest::Crc32c(u32::from_be_bytes(bytes)))
+    }
+
+    pub fn parse_sha256(value: &str) -> Option<ChunkDigest> {
+        decode_digest::<32>(value).map(ChunkDigest::Sha256)
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            ChunkDigest::Md5(_) => "md5",
+            ChunkDigest::Crc32c(_) => "crc32c",
+            ChunkDigest::Sha256(_) => "sha256",
+        }
+    }
+
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match self {
+            ChunkDigest::Md5(expected) => md5_digest(data) == *expected,
+            ChunkDigest::Crc32c(expected) => crc32c::crc32c(data) == *expected,
+            ChunkDigest::Sha256(expected) => Sha256::digest(data).as_slice() == expected,
+        }
+    }
+}
+
+pub fn md5_digest(data: &[u8]) -> [u8; 16] {
+    Md5::digest(data).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hash_algorithm_from_digest_length() {
+        assert_eq!(
+            HashAlgorithm::from_hex_digest(&"a".repeat(32)),
+            Some(HashAlgorithm::Md5)
+        );
+        assert_eq!(
+            HashAlgorithm::from_hex_digest(&"A".repeat(64)),
+            Some(HashAlgorithm::Sha256)
+        );
+        assert_eq!(HashAlgorithm::from_hex_digest(&"a".repeat(40)), None);
+        assert_eq!(HashAlgorithm::from_hex_digest(&"g".repeat(32)), None);
+    }
+
+    #[test]
+    fn md5_digest_hex_or_base64() {
+        let md5 = md5_digest(b"hello");
+        let hex = hex::encode(md5);
+        let base64 = STANDARD.encode(md5);
+        assert_eq!(ChunkDigest::parse_md5(&hex), Some(ChunkDigest::Md5(md5)));
+        assert_eq!(
+            ChunkDigest::parse_md5(&hex.to_uppercase()),
+            Some(ChunkDigest::Md5(md5))
+        );
+        assert_eq!(ChunkDigest::parse_md5(&base64), Some(ChunkDigest::Md5(md5)));
+        assert_eq!(
+            ChunkDigest::parse_md5(&format!(" {} ", hex)),
+            Some(ChunkDigest::Md5(md5))
+        );
+    }
+
+    #[test]
+    fn digest_of_wrong_length_is_rejected() {
+        assert_eq!(ChunkDigest::parse_md5(&"a".repeat(30)), None);
+        assert_eq!(ChunkDigest::parse_sha256(&hex::encode([0u8; 16])), None);
+        assert_eq!(ChunkDigest::parse_md5("not a digest"), None);
+        assert_eq!(ChunkDigest::parse_crc32c(""), None);
+    }
+
+    #[test]
+    fn crc32c_is_big_endian() {
+        let crc = crc32c::crc32c(b"hello");
+        let digest = ChunkDigest::parse_crc32c(&hex::encode(crc.to_be_bytes())).unwrap();
+        assert_eq!(digest, ChunkDigest::Crc32c(crc));
+        assert!(digest.matches(b"hello"));
+        assert!(!digest.matches(b"hellO"));
+    }
+
+    #[test]
+    fn digests_match_their_data() {
+        let sha256 = ChunkDigest::parse_sha256(&hex::encode(Sha256::digest(b"data"))).unwrap();
+        assert!(sha256.matches(b"data"));
+        assert!(!sha256.matches(b"other"));
+        let md5 = ChunkDigest::Md5(md5_digest(b"data"));
+        assert!(md5.matches(b"data"));
+        assert!(!md5.matches(b""));
+    }
+
+    #[test]
+    fn hasher_hex_digests() {
+        let mut hasher = Hasher::new(HashAlgorithm::Md5);
+        hasher.update(b"hel");
+        hasher.update(b"lo");
+        assert_eq!(hasher.finalize_hex(), "5d41402abc4b2a76b9719d911017c592");
+        let mut hasher = Hasher::new(HashAlgorithm::Sha256);
+        hasher.update(b"");
+        assert_eq!(
+            hasher.finalize_hex(),
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855"
+        );
+    }
+}
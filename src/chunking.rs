@@ -0,0 +1,270 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use tracing::error;
+
+use crate::models::{
+    Config, ConnectionProfile, ErrorResponse, WebAPIResult, MULTIPART_LIMIT, MULTIPART_OVERHEAD,
+};
+
+/// Limits of the object type a backend assembles uploads into.
+#[derive(Clone, Copy, Debug)]
+pub struct BackendCapabilities {
+    pub name: &'static str,
+    /// block size the server aims for when coalescing small chunks
+    pub preferred_block_size: u64,
+    pub max_block_size: u64,
+    pub max_block_count: u64,
+    pub max_object_size: u64,
+}
+
+/// Append blobs take at most 50,000 blocks of up to 100 MiB, but the blob itself
+/// is capped at 50,000 x 4 MiB.
+pub const AZURE_APPEND_BLOB: BackendCapabilities = BackendCapabilities {
+    name: "azure-append-blob",
+    preferred_block_size: 4 * 1024 * 1024,
+    max_block_size: 100 * 1024 * 1024,
+    max_block_count: 50_000,
+    max_object_size: 50_000 * 4 * 1024 * 1024,
+};
+
+/// Block blobs, used by direct uploads where every chunk is one block. Azure allows
+/// 50,000 blocks of up to 4000 MiB.
+pub const AZURE_BLOCK_BLOB: BackendCapabilities = BackendCapabilities {
+    name: "azure-block-blob",
+    preferred_block_size: 16 * 1024 * 1024,
+    max_block_size: 4000 * 1024 * 1024,
+    max_block_count: 50_000,
+    max_object_size: 50_000 * 4000 * 1024 * 1024,
+};
+
+/// Size of the blocks written to the backend for a file of `file_size` bytes: the preferred
+/// size, or larger when the file would otherwise need more blocks than the backend allows.
+pub fn block_size_for(caps: &BackendCapabilities, file_size: u64) -> WebAPIResult<u64> {
+    if file_size > caps.max_object_size {
+        error!(
+            "file_size {} exceeds {} limit {}",
+            file_size, caps.name, caps.max_object_size
+        );
+        return Err(ErrorResponse::with_status(
+            actix_web::http::StatusCode::PAYLOAD_TOO_LARGE,
+            "file_size exceeds the storage backend limit",
+        ));
+    }
+    let min_for_count = file_size.div_ceil(caps.max_block_count);
+    Ok(caps
+        .preferred_block_size
+        .max(min_for_count)
+        .min(caps.max_block_size))
+}
+
+fn profile_chunk_size(profile: ConnectionProfile, max_chunk_size: u64) -> u64 {
+    match profile {
+        ConnectionProfile::Mobile => 1024 * 1024,
+        ConnectionProfile::Standard => 8 * 1024 * 1024,
+        ConnectionProfile::Datacenter => max_chunk_size,
+    }
+}
+
+/// Chunk size returned to the client. The client's wish (or its connection profile) is
+/// bounded by the configured policy and by what fits in one request through the WAF and
+/// the multipart limit. Chunks of at least one block are rounded down to whole blocks so
+/// they map to blocks without buffering.
+pub fn negotiate_chunk_size(
+    config: &Config,
+    block_size: u64,
+    preferred_chunk_size: Option<u64>,
+    connection_profile: Option<ConnectionProfile>,
+) -> u64 {
+    let mut upper = config
+        .max_chunk_size
+        .min(MULTIPART_LIMIT - MULTIPART_OVERHEAD);
+    if let Some(waf_max_request_size) = config.waf_max_request_size {
+        upper = upper.min(waf_max_request_size.saturating_sub(MULTIPART_OVERHEAD));
+    }
+    let lower = config.min_chunk_size.min(upper).max(1);
+    let upper = upper.max(lower);
+
+    let wanted = match (preferred_chunk_size, connection_profile) {
+        (Some(size), _) => size,
+        (None, Some(profile)) => profile_chunk_size(profile, upper),
+        (None, None) => upper,
+    };
+    let chunk_size = wanted.clamp(lower, upper);
+    if chunk_size >= block_size && chunk_size - chunk_size % block_size >= lower {
+        chunk_size - chunk_size % block_size
+    } else {
+        chunk_size
+    }
+}
+
+/// Chunk size of a direct upload. Chunks go to storage as they are, so only the backend
+/// limits apply, not those of requests to this server.
+pub fn direct_chunk_size(
+    caps: &BackendCapabilities,
+    file_size: u64,
+    preferred_chunk_size: Option<u64>,
+) -> WebAPIResult<u64> {
+    // rejects files over the backend limit
+    block_size_for(caps, file_size)?;
+    let lower = file_size.div_ceil(caps.max_block_count).max(1);
+    Ok(preferred_chunk_size
+        .unwrap_or(caps.preferred_block_size)
+        .clamp(lower, caps.max_block_size))
+}
+
+/// Splits `data` into whole blocks and returns them with the remainder that is
+/// too small to be written yet.
+pub fn split_blocks(mut data: Vec<u8>, block_size: u64) -> (Vec<Vec<u8>>, Vec<u8>) {
+    assert!(block_size > 0, "block_size must not be 0");
+    let block_size = block_size as usize;
+    let mut blocks = Vec::with_capacity(data.len() / block_size);
+    let whole = data.len() - data.len() % block_size;
+    let remainder = data.split_off(whole);
+    if whole > 0 {
+        blocks.extend(data.chunks(block_size).map(|block| block.to_vec()));
+    }
+    (blocks, remainder)
+}
+
+/// Id of the `index`-th block of a blob. Azure wants all block ids of a blob to have
+/// the same length, so the index is zero padded before it is base64 encoded.
+pub fn block_id(index: u64) -> String {
+    STANDARD.encode(raw_block_id(index))
+}
+
+/// `block_id` before base64 encoding, as the storage SDK takes it.
+pub fn raw_block_id(index: u64) -> String {
+    format!("{:016}", index)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn config() -> Config {
+        Config::new("account", "container")
+    }
+
+    #[test]
+    fn split_blocks_keeps_remainder() {
+        let (blocks, remainder) = split_blocks((0..10).collect(), 4);
+        assert_eq!(blocks, vec![vec![0, 1, 2, 3], vec![4, 5, 6, 7]]);
+        assert_eq!(remainder, vec![8, 9]);
+    }
+
+    #[test]
+    fn split_blocks_of_whole_blocks() {
+        let (blocks, remainder) = split_blocks(vec![1; 8], 4);
+        assert_eq!(blocks.len(), 2);
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    fn split_blocks_shorter_than_a_block() {
+        let (blocks, remainder) = split_blocks(vec![1, 2, 3], 4);
+        assert!(blocks.is_empty());
+        assert_eq!(remainder, vec![1, 2, 3]);
+        let (blocks, remainder) = split_blocks(Vec::new(), 4);
+        assert!(blocks.is_empty());
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    #[should_panic]
+    fn split_blocks_rejects_zero_block_size() {
+        split_blocks(vec![1], 0);
+    }
+
+    #[test]
+    fn block_size_grows_for_large_files() {
+        assert_eq!(
+            block_size_for(&AZURE_APPEND_BLOB, 10 * MIB).unwrap(),
+            4 * MIB
+        );
+        assert_eq!(block_size_for(&AZURE_APPEND_BLOB, 0).unwrap(), 4 * MIB);
+        let caps = BackendCapabilities {
+            name: "test",
+            preferred_block_size: 4,
+            max_block_size: 100,
+            max_block_count: 10,
+            max_object_size: 1000,
+        };
+        assert_eq!(block_size_for(&caps, 40).unwrap(), 4);
+        assert_eq!(block_size_for(&caps, 41).unwrap(), 5);
+        assert_eq!(block_size_for(&caps, 1000).unwrap(), 100);
+        assert!(block_size_for(&caps, 1001).is_err());
+    }
+
+    #[test]
+    fn chunk_size_within_policy() {
+        let config = config();
+        let block_size = 4 * MIB;
+        assert_eq!(
+            negotiate_chunk_size(&config, block_size, Some(1), None),
+            config.min_chunk_size
+        );
+        assert_eq!(
+            negotiate_chunk_size(&config, block_size, Some(u64::MAX), None),
+            config.max_chunk_size
+        );
+        assert_eq!(
+            negotiate_chunk_size(&config, block_size, None, Some(ConnectionProfile::Mobile)),
+            MIB
+        );
+    }
+
+    #[test]
+    fn chunk_size_rounded_to_whole_blocks() {
+        let config = config();
+        assert_eq!(
+            negotiate_chunk_size(&config, 4 * MIB, Some(10 * MIB), None),
+            8 * MIB
+        );
+        assert_eq!(
+            negotiate_chunk_size(&config, 4 * MIB, Some(3 * MIB), None),
+            3 * MIB
+        );
+    }
+
+    #[test]
+    fn chunk_size_fits_through_the_waf() {
+        let mut config = config();
+        config.waf_max_request_size = Some(2 * MIB);
+        let chunk_size = negotiate_chunk_size(&config, 4 * MIB, None, None);
+        assert_eq!(chunk_size, 2 * MIB - MULTIPART_OVERHEAD);
+    }
+
+    #[test]
+    fn direct_chunk_size_within_block_blob_limits() {
+        assert_eq!(
+            direct_chunk_size(&AZURE_BLOCK_BLOB, 100 * MIB, None).unwrap(),
+            16 * MIB
+        );
+        assert_eq!(
+            direct_chunk_size(&AZURE_BLOCK_BLOB, 1000, Some(1)).unwrap(),
+            1
+        );
+        assert_eq!(
+            direct_chunk_size(&AZURE_BLOCK_BLOB, 100 * MIB, Some(1)).unwrap(),
+            (100 * MIB).div_ceil(50_000)
+        );
+        // 50,000 blocks of 1 byte are not enough
+        assert_eq!(
+            direct_chunk_size(&AZURE_BLOCK_BLOB, 100_000, Some(1)).unwrap(),
+            2
+        );
+        assert!(direct_chunk_size(&AZURE_BLOCK_BLOB, u64::MAX, None).is_err());
+    }
+
+    #[test]
+    fn block_ids_have_the_same_length() {
+        assert_eq!(raw_block_id(7), "0000000000000007");
+        assert_eq!(block_id(0).len(), block_id(49_999).len());
+        assert_eq!(
+            STANDARD.decode(block_id(42)).unwrap(),
+            raw_block_id(42).as_bytes()
+        );
+    }
+}
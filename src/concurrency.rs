@@ -0,0 +1,151 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::web::Data;
+use futures::future::LocalBoxFuture;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tracing::error;
+
+use crate::models::{ErrorResponse, WebAPIResult};
+
+/// Limits how many chunks are processed at once, across the server and per upload. Each
+/// chunk request holds its whole body in memory, so the global limit is what bounds the
+/// memory of the server.
+pub struct ConcurrencyLimits {
+    global: Semaphore,
+    global_max: usize,
+    per_upload_max: usize,
+    /// how long a chunk waits for a global slot before it is rejected
+    queue_timeout: Duration,
+    uploads: Mutex<HashMap<String, usize>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConcurrencyStats {
+    pub chunks_in_flight: usize,
+    pub max_chunks_in_flight: usize,
+    pub uploads_in_flight: usize,
+    pub max_chunks_in_flight_per_upload: usize,
+}
+
+/// A chunk of an upload being processed, gives its slot back when dropped.
+pub struct UploadSlot<'a> {
+    limits: &'a ConcurrencyLimits,
+    upload_id: String,
+}
+
+impl Drop for UploadSlot<'_> {
+    fn drop(&mut self) {
+        let mut uploads = self.limits.uploads.lock().unwrap();
+        if let Some(count) = uploads.get_mut(&self.upload_id) {
+            *count -= 1;
+            if *count == 0 {
+                uploads.remove(&self.upload_id);
+            }
+        }
+    }
+}
+
+impl ConcurrencyLimits {
+    pub fn new(global_max: usize, per_upload_max: usize, queue_timeout: Duration) -> Self {
+        ConcurrencyLimits {
+            global: Semaphore::new(global_max.max(1)),
+            global_max: global_max.max(1),
+            per_upload_max: per_upload_max.max(1),
+            queue_timeout,
+            uploads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a slot of `upload_id`, rejecting with 503 while it has the maximum in flight.
+    pub fn enter_upload(&self, upload_id: &str) -> WebAPIResult<UploadSlot<'_>> {
+        let mut uploads = self.uploads.lock().unwrap();
+        let count = uploads.entry(upload_id.to_string()).or_insert(0);
+        if *count >= self.per_upload_max {
+            error!("upload {} has {} chunks in flight", upload_id, count);
+            return Err(ErrorResponse::unavailable(
+                "too many chunks in flight for this upload",
+                Duration::from_secs(1),
+            ));
+        }
+        *count += 1;
+        Ok(UploadSlot {
+            limits: self,
+            upload_id: upload_id.to_string(),
+        })
+    }
+
+    pub fn stats(&self) -> ConcurrencyStats {
+        ConcurrencyStats {
+            chunks_in_flight: self.global_max - self.global.available_permits(),
+            max_chunks_in_flight: self.global_max,
+            uploads_in_flight: self.uploads.lock().unwrap().len(),
+            max_chunks_in_flight_per_upload: self.per_upload_max,
+        }
+    }
+}
+
+/// Middleware taking a global slot before the request body is read. Requests wait up to
+/// the queue timeout for a slot and are rejected with 503 after that.
+pub struct GlobalChunkLimit(pub Data<ConcurrencyLimits>);
+
+impl<S, B> Transform<S, ServiceRequest> for GlobalChunkLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = GlobalChunkLimitService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(GlobalChunkLimitService {
+            service: Rc::new(service),
+            limits: self.0.clone(),
+        }))
+    }
+}
+
+pub struct GlobalChunkLimitService<S> {
+    service: Rc<S>,
+    limits: Data<ConcurrencyLimits>,
+}
+
+impl<S, B> Service<ServiceRequest> for GlobalChunkLimitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limits = self.limits.clone();
+        Box::pin(async move {
+            let acquire = limits.global.acquire();
+            let _permit = match actix_web::rt::time::timeout(limits.queue_timeout, acquire).await {
+                Ok(Ok(permit)) => permit,
+                _ => {
+                    error!("{} chunks in flight, rejecting", limits.global_max);
+                    return Err(ErrorResponse::unavailable(
+                        "too many chunks in flight",
+                        Duration::from_secs(1),
+                    )
+                    .into());
+                }
+            };
+            service.call(req).await
+        })
+    }
+}
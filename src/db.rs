@@ -0,0 +1,624 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_web::web;
+use bytes::BytesMut;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::types::{ToSqlOutput, ValueRef};
+use tokio_postgres::error::SqlState;
+use tokio_postgres::types::{to_sql_checked, IsNull, ToSql, Type};
+use tokio_postgres::NoTls;
+use tracing::{debug, info};
+
+use crate::migrations::{self, Migration};
+
+const SQLITE_BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+const POSTGRES_POOL_SIZE: usize = 16;
+/// Serializes migrations of replicas starting at the same time.
+const POSTGRES_MIGRATION_LOCK: i64 = 0x7570_6c6f_6164;
+
+/// Current time in unix seconds, the form times are stored in when they are read back.
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Dialect {
+    Sqlite,
+    Postgres,
+}
+
+/// Connection pool of the metadata database.
+///
+/// Queries are written once with `$1`, `$2`, ... placeholders, which Postgres takes as they
+/// are and which are rewritten to `?1`, `?2`, ... for SQLite.
+#[derive(Clone)]
+pub enum DbPool {
+    Sqlite(r2d2::Pool<SqliteConnectionManager>),
+    Postgres(deadpool_postgres::Pool),
+}
+
+impl std::fmt::Debug for DbPool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("DbPool").field(&self.dialect()).finish()
+    }
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Pool(String),
+    Sqlite(rusqlite::Error),
+    Postgres(tokio_postgres::Error),
+    /// a column did not hold the type it was read as
+    Column(usize),
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "connection pool: {}", e),
+            DbError::Sqlite(e) => write!(f, "sqlite: {}", e),
+            DbError::Postgres(e) => write!(f, "postgres: {}", e),
+            DbError::Column(i) => write!(f, "column {} has an unexpected type", i),
+        }
+    }
+}
+
+impl DbError {
+    pub fn is_unique_violation(&self) -> bool {
+        match self {
+            DbError::Sqlite(rusqlite::Error::SqliteFailure(e, _)) => {
+                e.code == rusqlite::ErrorCode::ConstraintViolation
+            }
+            DbError::Postgres(e) => e.code() == Some(&SqlState::UNIQUE_VIOLATION),
+            _ => false,
+        }
+    }
+}
+
+impl From<rusqlite::Error> for DbError {
+    fn from(e: rusqlite::Error) -> Self {
+        DbError::Sqlite(e)
+    }
+}
+
+impl From<tokio_postgres::Error> for DbError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        DbError::Postgres(e)
+    }
+}
+
+impl From<r2d2::Error> for DbError {
+    fn from(e: r2d2::Error) -> Self {
+        DbError::Pool(e.to_string())
+    }
+}
+
+impl From<deadpool_postgres::PoolError> for DbError {
+    fn from(e: deadpool_postgres::PoolError) -> Self {
+        DbError::Pool(e.to_string())
+    }
+}
+
+/// A query parameter or column value, the common ground of both databases.
+/// Booleans are stored as integers by SQLite.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Int(i64),
+    Bool(bool),
+    Text(String),
+    Bytes(Vec<u8>),
+}
+
+impl From<&str> for Value {
+    fn from(v: &str) -> Self {
+        Value::Text(v.to_string())
+    }
+}
+
+impl From<&String> for Value {
+    fn from(v: &String) -> Self {
+        Value::Text(v.clone())
+    }
+}
+
+impl From<String> for Value {
+    fn from(v: String) -> Self {
+        Value::Text(v)
+    }
+}
+
+impl From<i64> for Value {
+    fn from(v: i64) -> Self {
+        Value::Int(v)
+    }
+}
+
+impl From<u64> for Value {
+    fn from(v: u64) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<u32> for Value {
+    fn from(v: u32) -> Self {
+        Value::Int(v as i64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(v: bool) -> Self {
+        Value::Bool(v)
+    }
+}
+
+impl From<&[u8]> for Value {
+    fn from(v: &[u8]) -> Self {
+        Value::Bytes(v.to_vec())
+    }
+}
+
+impl From<Vec<u8>> for Value {
+    fn from(v: Vec<u8>) -> Self {
+        Value::Bytes(v)
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(v: Option<T>) -> Self {
+        v.map(Into::into).unwrap_or(Value::Null)
+    }
+}
+
+impl rusqlite::ToSql for Value {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Value::Null => ToSqlOutput::Borrowed(ValueRef::Null),
+            Value::Int(v) => ToSqlOutput::from(*v),
+            Value::Bool(v) => ToSqlOutput::from(*v as i64),
+            Value::Text(v) => ToSqlOutput::from(v.as_str()),
+            Value::Bytes(v) => ToSqlOutput::Borrowed(ValueRef::Blob(v.as_slice())),
+        })
+    }
+}
+
+impl ToSql for Value {
+    fn to_sql(
+        &self,
+        ty: &Type,
+        out: &mut BytesMut,
+    ) -> Result<IsNull, Box<dyn std::error::Error + Sync + Send>> {
+        match self {
+            Value::Null => Ok(IsNull::Yes),
+            Value::Int(v) if *ty == Type::INT4 => (*v as i32).to_sql(ty, out),
+            Value::Int(v) if *ty == Type::INT2 => (*v as i16).to_sql(ty, out),
+            Value::Int(v) if *ty == Type::BOOL => (*v != 0).to_sql(ty, out),
+            Value::Int(v) => v.to_sql(ty, out),
+            Value::Bool(v) => v.to_sql(ty, out),
+            Value::Text(v) => v.to_sql(ty, out),
+            Value::Bytes(v) => v.to_sql(ty, out),
+        }
+    }
+
+    fn accepts(_ty: &Type) -> bool {
+        true
+    }
+
+    to_sql_checked!();
+}
+
+fn sqlite_value(value: ValueRef) -> Value {
+    match value {
+        ValueRef::Null => Value::Null,
+        ValueRef::Integer(v) => Value::Int(v),
+        ValueRef::Real(v) => Value::Int(v as i64),
+        ValueRef::Text(v) => Value::Text(String::from_utf8_lossy(v).to_string()),
+        ValueRef::Blob(v) => Value::Bytes(v.to_vec()),
+    }
+}
+
+fn postgres_value(row: &tokio_postgres::Row, i: usize) -> Result<Value, DbError> {
+    let ty = row.columns()[i].type_();
+    let value = if *ty == Type::INT8 {
+        row.try_get::<_, Option<i64>>(i)?.map(Value::Int)
+    } else if *ty == Type::INT4 {
+        row.try_get::<_, Option<i32>>(i)?
+            .map(|v| Value::Int(v as i64))
+    } else if *ty == Type::INT2 {
+        row.try_get::<_, Option<i16>>(i)?
+            .map(|v| Value::Int(v as i64))
+    } else if *ty == Type::BOOL {
+        row.try_get::<_, Option<bool>>(i)?.map(Value::Bool)
+    } else if *ty == Type::BYTEA {
+        row.try_get::<_, Option<Vec<u8>>>(i)?.map(Value::Bytes)
+    } else {
+        row.try_get::<_, Option<String>>(i)?.map(Value::Text)
+    };
+    Ok(value.unwrap_or(Value::Null))
+}
+
+/// Conversion of a column value, see `DbRow::get`.
+pub trait FromValue: Sized {
+    fn from_value(value: &Value) -> Option<Self>;
+}
+
+impl FromValue for String {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Text(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for i64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Int(v) => Some(*v),
+            Value::Bool(v) => Some(*v as i64),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for u64 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|v| u64::try_from(v).ok())
+    }
+}
+
+impl FromValue for u32 {
+    fn from_value(value: &Value) -> Option<Self> {
+        i64::from_value(value).and_then(|v| u32::try_from(v).ok())
+    }
+}
+
+impl FromValue for bool {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bool(v) => Some(*v),
+            Value::Int(v) => Some(*v != 0),
+            _ => None,
+        }
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Bytes(v) => Some(v.clone()),
+            _ => None,
+        }
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(value: &Value) -> Option<Self> {
+        match value {
+            Value::Null => Some(None),
+            value => T::from_value(value).map(Some),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DbRow(Vec<Value>);
+
+impl DbRow {
+    pub fn get<T: FromValue>(&self, i: usize) -> Result<T, DbError> {
+        self.0
+            .get(i)
+            .and_then(T::from_value)
+            .ok_or(DbError::Column(i))
+    }
+}
+
+/// Rewrites `$n` placeholders to the `?n` form of SQLite.
+fn sqlite_sql(sql: &str) -> String {
+    let mut out = String::with_capacity(sql.len());
+    let mut chars = sql.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c == '$' && chars.peek().is_some_and(|next| next.is_ascii_digit()) {
+            out.push('?');
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn postgres_params(params: &[Value]) -> Vec<&(dyn ToSql + Sync)> {
+    params.iter().map(|v| v as &(dyn ToSql + Sync)).collect()
+}
+
+fn sqlite_query(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    params: &[Value],
+) -> Result<Vec<DbRow>, DbError> {
+    let mut stmt = conn.prepare(&sqlite_sql(sql))?;
+    let columns = stmt.column_count();
+    let rows = stmt.query_map(rusqlite::params_from_iter(params.iter()), |row| {
+        (0..columns)
+            .map(|i| row.get_ref(i).map(sqlite_value))
+            .collect::<rusqlite::Result<Vec<Value>>>()
+            .map(DbRow)
+    })?;
+    Ok(rows.collect::<rusqlite::Result<Vec<DbRow>>>()?)
+}
+
+fn sqlite_execute(
+    conn: &rusqlite::Connection,
+    sql: &str,
+    params: &[Value],
+) -> Result<u64, DbError> {
+    Ok(conn.execute(&sqlite_sql(sql), rusqlite::params_from_iter(params.iter()))? as u64)
+}
+
+/// Runs `f` with a pooled SQLite connection on the blocking thread pool. Waiting for the
+/// connection and running the statements both block, which must not stall the async workers.
+async fn with_sqlite<T, F>(pool: &r2d2::Pool<SqliteConnectionManager>, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut rusqlite::Connection) -> Result<T, DbError> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    web::block(move || {
+        let mut conn = pool.get()?;
+        f(&mut conn)
+    })
+    .await
+    .map_err(|e| DbError::Pool(e.to_string()))?
+}
+
+impl DbPool {
+    /// Opens the database named by `DATABASE_URL`: `postgres://...` for Postgres, a file
+    /// path (optionally prefixed with `sqlite://`) for SQLite, or an in-memory SQLite
+    /// database when unset.
+    pub fn open(url: Option<&str>) -> Result<DbPool, String> {
+        match url {
+            Some(url) if url.starts_with("postgres://") || url.starts_with("postgresql://") => {
+                let pg_config: tokio_postgres::Config = url
+                    .parse()
+                    .map_err(|e| format!("invalid DATABASE_URL: {}", e))?;
+                let manager = deadpool_postgres::Manager::new(pg_config, NoTls);
+                let pool = deadpool_postgres::Pool::builder(manager)
+                    .max_size(POSTGRES_POOL_SIZE)
+                    .build()
+                    .map_err(|e| format!("{:?}", e))?;
+                info!("metadata database: postgres");
+                Ok(DbPool::Postgres(pool))
+            }
+            Some(url) => {
+                let path = url.trim_start_matches("sqlite://");
+                info!("metadata database: sqlite {}", path);
+                let manager = SqliteConnectionManager::file(path)
+                    .with_init(|conn| conn.busy_timeout(SQLITE_BUSY_TIMEOUT));
+                r2d2::Pool::new(manager)
+                    .map(DbPool::Sqlite)
+                    .map_err(|e| format!("{:?}", e))
+            }
+            None => {
+                // every connection would open its own empty database, so the pool holds
+                // exactly one connection and never lets it go
+                info!("metadata database: sqlite in memory");
+                r2d2::Pool::builder()
+                    .max_size(1)
+                    .idle_timeout(None)
+                    .max_lifetime(None)
+                    .build(SqliteConnectionManager::memory())
+                    .map(DbPool::Sqlite)
+                    .map_err(|e| format!("{:?}", e))
+            }
+        }
+    }
+
+    pub fn dialect(&self) -> Dialect {
+        match self {
+            DbPool::Sqlite(_) => Dialect::Sqlite,
+            DbPool::Postgres(_) => Dialect::Postgres,
+        }
+    }
+
+    /// Runs one statement and returns the number of affected rows.
+    pub async fn execute(&self, sql: &str, params: &[Value]) -> Result<u64, DbError> {
+        match self {
+            DbPool::Sqlite(pool) => {
+                let (sql, params) = (sql.to_string(), params.to_vec());
+                with_sqlite(pool, move |conn| sqlite_execute(conn, &sql, &params)).await
+            }
+            DbPool::Postgres(pool) => {
+                let client = pool.get().await?;
+                Ok(client.execute(sql, &postgres_params(params)).await?)
+            }
+        }
+    }
+
+    pub async fn query(&self, sql: &str, params: &[Value]) -> Result<Vec<DbRow>, DbError> {
+        match self {
+            DbPool::Sqlite(pool) => {
+                let (sql, params) = (sql.to_string(), params.to_vec());
+                with_sqlite(pool, move |conn| sqlite_query(conn, &sql, &params)).await
+            }
+            DbPool::Postgres(pool) => {
+                let client = pool.get().await?;
+                let rows = client.query(sql, &postgres_params(params)).await?;
+                rows.iter()
+                    .map(|row| {
+                        (0..row.len())
+                            .map(|i| postgres_value(row, i))
+                            .collect::<Result<Vec<Value>, DbError>>()
+                            .map(DbRow)
+                    })
+                    .collect()
+            }
+        }
+    }
+
+    pub async fn query_opt(&self, sql: &str, params: &[Value]) -> Result<Option<DbRow>, DbError> {
+        Ok(self.query(sql, params).await?.into_iter().next())
+    }
+
+    /// Runs the statements in one transaction and returns the rows affected by each.
+    pub async fn transaction(
+        &self,
+        statements: &[(&str, Vec<Value>)],
+    ) -> Result<Vec<u64>, DbError> {
+        match self {
+            DbPool::Sqlite(pool) => {
+                let statements: Vec<(String, Vec<Value>)> = statements
+                    .iter()
+                    .map(|(sql, params)| (sql.to_string(), params.clone()))
+                    .collect();
+                with_sqlite(pool, move |conn| {
+                    let tx = conn.transaction()?;
+                    let counts = statements
+                        .iter()
+                        .map(|(sql, params)| sqlite_execute(&tx, sql, params))
+                        .collect::<Result<Vec<u64>, DbError>>()?;
+                    tx.commit()?;
+                    Ok(counts)
+                })
+                .await
+            }
+            DbPool::Postgres(pool) => {
+                let mut counts = Vec::with_capacity(statements.len());
+                let mut client = pool.get().await?;
+                let tx = client.transaction().await?;
+                for (sql, params) in statements {
+                    counts.push(tx.execute(*sql, &postgres_params(params)).await?);
+                }
+                tx.commit().await?;
+                Ok(counts)
+            }
+        }
+    }
+
+    /// Applies the migrations that have not been applied yet, each in its own transaction.
+    pub async fn migrate(&self) -> Result<(), DbError> {
+        let create = "CREATE TABLE IF NOT EXISTS schema_migrations(
+            version BIGINT PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_dt TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+        );";
+        self.execute(create, &[]).await?;
+        for migration in migrations::MIGRATIONS {
+            self.apply(migration).await?;
+        }
+        Ok(())
+    }
+
+    async fn apply(&self, migration: &Migration) -> Result<(), DbError> {
+        let check = "SELECT COUNT(*) FROM schema_migrations WHERE version = $1;";
+        let record = "INSERT INTO schema_migrations(version, name) VALUES ($1, $2);";
+        let record_params = [Value::from(migration.version), Value::from(migration.name)];
+        let sql = migration.render(self.dialect());
+        match self {
+            DbPool::Sqlite(pool) => {
+                let version = migration.version;
+                let applied = with_sqlite(pool, move |conn| {
+                    let tx = conn.transaction()?;
+                    let applied: i64 =
+                        tx.query_row(&sqlite_sql(check), [version], |row| row.get(0))?;
+                    if applied > 0 {
+                        return Ok(true);
+                    }
+                    tx.execute_batch(&sql)?;
+                    sqlite_execute(&tx, record, &record_params)?;
+                    tx.commit()?;
+                    Ok(false)
+                })
+                .await?;
+                if applied {
+                    return Ok(());
+                }
+            }
+            DbPool::Postgres(pool) => {
+                let mut client = pool.get().await?;
+                let tx = client.transaction().await?;
+                tx.execute(
+                    "SELECT pg_advisory_xact_lock($1);",
+                    &[&POSTGRES_MIGRATION_LOCK],
+                )
+                .await?;
+                let applied: i64 = tx.query_one(check, &[&migration.version]).await?.get(0);
+                if applied > 0 {
+                    return Ok(());
+                }
+                tx.batch_execute(&sql).await?;
+                tx.execute(record, &postgres_params(&record_params)).await?;
+                tx.commit().await?;
+            }
+        }
+        debug!("applied migration {} {}", migration.version, migration.name);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_rewritten_for_sqlite() {
+        assert_eq!(
+            sqlite_sql("SELECT a FROM t WHERE b = $1 AND c IN ($2, $10);"),
+            "SELECT a FROM t WHERE b = ?1 AND c IN (?2, ?10);"
+        );
+    }
+
+    #[test]
+    fn dollar_signs_without_digits_kept() {
+        assert_eq!(
+            sqlite_sql("SELECT '$', 'a$b' || $"),
+            "SELECT '$', 'a$b' || $"
+        );
+        assert_eq!(sqlite_sql(""), "");
+    }
+
+    #[test]
+    fn column_values_converted() {
+        let row = DbRow(vec![
+            Value::Int(1),
+            Value::Null,
+            Value::Text("a".to_string()),
+            Value::Int(-1),
+        ]);
+        assert!(row.get::<bool>(0).unwrap());
+        assert_eq!(row.get::<Option<String>>(1).unwrap(), None);
+        assert_eq!(row.get::<String>(2).unwrap(), "a");
+        assert!(matches!(row.get::<u64>(3), Err(DbError::Column(3))));
+        assert!(matches!(row.get::<i64>(4), Err(DbError::Column(4))));
+    }
+
+    #[actix_web::test]
+    async fn sqlite_round_trip() {
+        let pool = DbPool::open(None).unwrap();
+        pool.execute("CREATE TABLE t(a BIGINT, b TEXT);", &[])
+            .await
+            .unwrap();
+        let counts = pool
+            .transaction(&[
+                (
+                    "INSERT INTO t(a, b) VALUES ($1, $2);",
+                    vec![1u64.into(), "x".into()],
+                ),
+                (
+                    "INSERT INTO t(a, b) VALUES ($1, $2);",
+                    vec![2u64.into(), "y".into()],
+                ),
+            ])
+            .await
+            .unwrap();
+        assert_eq!(counts, vec![1, 1]);
+        let row = pool
+            .query_opt("SELECT b FROM t WHERE a = $1;", &[2u64.into()])
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.get::<String>(0).unwrap(), "y");
+    }
+}
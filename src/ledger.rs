@@ -0,0 +1,103 @@
+use actix_web::http::StatusCode;
+use tracing::error;
+
+use crate::db::{DbError, DbPool, DbRow, Value};
+use crate::models::{ErrorResponse, UploadChunk, WebAPIResult};
+
+const CHUNK_COLUMNS: &str =
+    "upload_id, chunk_index, chunk_offset, chunk_size, checksum, block_id, received_at";
+
+fn chunk_from_row(row: &DbRow) -> Result<UploadChunk, DbError> {
+    Ok(UploadChunk {
+        upload_id: row.get(0)?,
+        chunk_index: row.get(1)?,
+        chunk_offset: row.get(2)?,
+        chunk_size: row.get(3)?,
+        checksum: row.get(4)?,
+        block_id: row.get(5)?,
+        received_at: row.get(6)?,
+    })
+}
+
+fn ledger_error(context: &str, e: DbError) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+async fn query_chunk(
+    pool: &DbPool,
+    sql: &str,
+    params: &[Value],
+) -> WebAPIResult<Option<UploadChunk>> {
+    let res = match pool.query_opt(sql, params).await {
+        Ok(Some(row)) => chunk_from_row(&row).map(Some),
+        Ok(None) => Ok(None),
+        Err(e) => Err(e),
+    };
+    res.map_err(|e| ledger_error("query chunk failed", e))
+}
+
+/// The chunk received last, the next one continues behind it.
+pub async fn last_chunk(pool: &DbPool, upload_id: &str) -> WebAPIResult<Option<UploadChunk>> {
+    query_chunk(
+        pool,
+        &format!(
+            "SELECT {} FROM upload_chunks WHERE upload_id = $1 ORDER BY chunk_index DESC LIMIT 1;",
+            CHUNK_COLUMNS
+        ),
+        &[upload_id.into()],
+    )
+    .await
+}
+
+pub async fn find_chunk(
+    pool: &DbPool,
+    upload_id: &str,
+    chunk_index: u64,
+) -> WebAPIResult<Option<UploadChunk>> {
+    query_chunk(
+        pool,
+        &format!(
+            "SELECT {} FROM upload_chunks WHERE upload_id = $1 AND chunk_index = $2;",
+            CHUNK_COLUMNS
+        ),
+        &[upload_id.into(), chunk_index.into()],
+    )
+    .await
+}
+
+/// Records a chunk once it reached storage (or the spool). Two requests racing for the
+/// same index are told apart by the primary key, the loser gets 409.
+pub async fn record_chunk(pool: &DbPool, chunk: &UploadChunk) -> WebAPIResult<()> {
+    let res = pool
+        .execute(
+            &format!(
+                "INSERT INTO upload_chunks({}) VALUES ($1, $2, $3, $4, $5, $6, $7);",
+                CHUNK_COLUMNS
+            ),
+            &[
+                (&chunk.upload_id).into(),
+                chunk.chunk_index.into(),
+                chunk.chunk_offset.into(),
+                chunk.chunk_size.into(),
+                (&chunk.checksum).into(),
+                (&chunk.block_id).into(),
+                chunk.received_at.into(),
+            ],
+        )
+        .await;
+    match res {
+        Ok(_) => Ok(()),
+        Err(e) if e.is_unique_violation() => {
+            error!(
+                "chunk {} of {} already recorded",
+                chunk.chunk_index, chunk.upload_id
+            );
+            Err(ErrorResponse::with_status(
+                StatusCode::CONFLICT,
+                "chunk already recorded",
+            ))
+        }
+        Err(e) => Err(ledger_error("record chunk failed", e)),
+    }
+}
@@ -1,22 +1,251 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::fmt::Display;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_files::Files;
 use actix_multipart::form::MultipartFormConfig;
-use actix_web::{App, HttpServer, web};
 use actix_web::middleware::Logger;
 use actix_web::web::Data;
-use log::{debug, error};
-use r2d2_sqlite::SqliteConnectionManager;
+use actix_web::{web, App, HttpServer};
+use azure_identity::DefaultAzureCredential;
+use azure_storage::StorageCredentials;
+use log::{debug, error, warn};
 
-use crate::models::{Config, SharedData};
+use crate::access::AccessPolicy;
+use crate::auth::Authenticator;
+use crate::concurrency::{ConcurrencyLimits, GlobalChunkLimit};
+use crate::db::DbPool;
+use crate::models::{Config, SharedData, MULTIPART_LIMIT};
+use crate::retry::CircuitBreaker;
+use crate::session::SessionStore;
+use crate::shares::ShareLinks;
+use crate::spool::Spool;
+use crate::throttle::{BandwidthLimit, RateLimiter, Throttle};
+use crate::tokens::{TokenKey, UploadTokens};
 
+mod access;
 mod apis;
+mod attributes;
+mod auth;
+mod catalog;
+mod checksum;
+mod chunking;
+mod concurrency;
+mod db;
+mod ledger;
+mod migrations;
 mod mime_types;
 mod models;
+mod quota;
+mod ranges;
+mod retry;
+mod session;
+mod shares;
+mod spool;
+mod storage;
+mod throttle;
+mod tokens;
 
 //type DbPool = r2d2::Pool<r2d2::ConnectionManager<SqliteConnection>>;
 
+const DEFAULT_SPOOL_MAX_BYTES: u64 = 1024 * 1024 * 1024;
+const SPOOL_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const DEFAULT_SESSION_TTL_SECS: u64 = 24 * 60 * 60;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_UPLOAD_TOKEN_TTL_SECS: u64 = 15 * 60;
+const DEFAULT_SHARE_LINK_TTL_SECS: u64 = 24 * 60 * 60;
+const DEFAULT_MAX_SHARE_LINK_TTL_SECS: u64 = 30 * 24 * 60 * 60;
+const DEFAULT_MAX_CHUNKS_IN_FLIGHT: usize = 16;
+/// Chunks of an upload continue from the staged size and carried over bytes the previous
+/// one left, two at once would both write from the same offset.
+const DEFAULT_MAX_CHUNKS_IN_FLIGHT_PER_UPLOAD: usize = 1;
+const DEFAULT_CHUNK_QUEUE_TIMEOUT_MS: u64 = 5000;
+
+/// Reads an optional setting from the environment, failing when it is set but invalid.
+fn env_setting<T>(name: &str) -> Result<Option<T>, String>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| format!("invalid {}: {}", name, e)),
+        Err(_) => Ok(None),
+    }
+}
+
+fn load_config(account: &str, container: &str) -> Result<Config, String> {
+    let mut config = Config::new(account, container);
+    if let Some(conflict_mode) = env_setting("UPLOAD_CONFLICT_MODE")? {
+        config.conflict_mode = conflict_mode;
+    }
+    if let Some(min_chunk_size) = env_setting("CHUNK_SIZE_MIN")? {
+        config.min_chunk_size = min_chunk_size;
+    }
+    if let Some(max_chunk_size) = env_setting("CHUNK_SIZE_MAX")? {
+        config.max_chunk_size = max_chunk_size;
+    }
+    config.waf_max_request_size = env_setting("WAF_MAX_REQUEST_SIZE")?;
+    if let Some(max_attempts) = env_setting("STORAGE_RETRY_ATTEMPTS")? {
+        config.retry_policy.max_attempts = max_attempts;
+    }
+    if let Some(base_delay_ms) = env_setting("STORAGE_RETRY_BASE_DELAY_MS")? {
+        config.retry_policy.base_delay_ms = base_delay_ms;
+    }
+    if let Some(max_delay_ms) = env_setting("STORAGE_RETRY_MAX_DELAY_MS")? {
+        config.retry_policy.max_delay_ms = max_delay_ms;
+    }
+    config.user_quota_bytes = env_setting("USER_QUOTA_BYTES")?;
+    config.tenant_quota_bytes = env_setting("TENANT_QUOTA_BYTES")?;
+    config.trash_period_secs = env_setting("TRASH_PERIOD_SECS")?.unwrap_or(0);
+    if let Some(rules_file) = env_setting::<PathBuf>("ACCESS_TIER_RULES_FILE")? {
+        let rules = std::fs::read(&rules_file)
+            .map_err(|e| format!("read {:?} failed: {}", rules_file, e))?;
+        config.access_tier_rules = serde_json::from_slice(&rules)
+            .map_err(|e| format!("invalid access tier rules {:?}: {}", rules_file, e))?;
+    }
+    config.default_access_tier = env_setting("DEFAULT_ACCESS_TIER")?;
+    config.direct_uploads = env_setting("DIRECT_UPLOADS")?.unwrap_or(false);
+    if let Some(ttl_secs) = env_setting("DIRECT_UPLOAD_URL_TTL_SECS")? {
+        config.direct_upload_url_ttl_secs = ttl_secs;
+    }
+    let breaker_threshold = env_setting("STORAGE_BREAKER_THRESHOLD")?.unwrap_or(5);
+    let breaker_open_secs = env_setting("STORAGE_BREAKER_OPEN_SECS")?.unwrap_or(30);
+    config.circuit_breaker = Arc::new(CircuitBreaker::new(
+        breaker_threshold,
+        Duration::from_secs(breaker_open_secs),
+    ));
+    if config.min_chunk_size == 0 || config.min_chunk_size > config.max_chunk_size {
+        return Err("CHUNK_SIZE_MIN must be between 1 and CHUNK_SIZE_MAX".to_string());
+    }
+    if config.direct_upload_url_ttl_secs == 0 {
+        return Err("DIRECT_UPLOAD_URL_TTL_SECS must be at least 1".to_string());
+    }
+    Ok(config)
+}
+
+/// The spool is only used when `SPOOL_DIR` is set. Spooled blocks outlive a restart, so
+/// the uploads they belong to must too: it needs a `DATABASE_URL`. They are only on this
+/// replica's disk, so the sessions must not be shared with other replicas, which could
+/// finish an upload without its spooled blocks.
+fn open_spool(
+    credentials: StorageCredentials,
+    database_url: Option<&str>,
+    sessions_shared: bool,
+) -> Result<Spool, String> {
+    match env_setting::<PathBuf>("SPOOL_DIR")? {
+        Some(_) if database_url.is_none() => {
+            Err("SPOOL_DIR needs a persistent DATABASE_URL".to_string())
+        }
+        Some(_) if sessions_shared => Err(
+            "SPOOL_DIR is local to a replica and cannot be used with shared sessions".to_string(),
+        ),
+        Some(spool_dir) => {
+            let max_bytes = env_setting("SPOOL_MAX_BYTES")?.unwrap_or(DEFAULT_SPOOL_MAX_BYTES);
+            Spool::open(spool_dir, max_bytes, credentials).map_err(|e| format!("{:?}", e))
+        }
+        None => Ok(Spool::disabled()),
+    }
+}
+
+/// API keys come from `API_KEYS`, JWTs are checked against `JWT_JWKS_FILE` and
+/// `JWT_PUBLIC_KEY_FILE`.
+fn open_authenticator() -> Result<Authenticator, String> {
+    let mut authenticator = Authenticator::default();
+    if let Some(api_keys) = env_setting::<String>("API_KEYS")? {
+        authenticator.add_api_keys(&api_keys)?;
+    }
+    if let Some(jwks_file) = env_setting::<PathBuf>("JWT_JWKS_FILE")? {
+        authenticator.add_jwks_file(&jwks_file)?;
+    }
+    if let Some(public_key_file) = env_setting::<PathBuf>("JWT_PUBLIC_KEY_FILE")? {
+        authenticator.add_public_key_file(&public_key_file)?;
+    }
+    authenticator.issuer = env_setting("JWT_ISSUER")?;
+    authenticator.audience = env_setting("JWT_AUDIENCE")?;
+    authenticator.tenant_claim = env_setting("JWT_TENANT_CLAIM")?;
+    authenticator.roles_claim = env_setting("JWT_ROLES_CLAIM")?;
+    Ok(authenticator)
+}
+
+/// Tokens are signed with `TOKEN_SECRET`, which all replicas must share.
+fn load_token_key() -> Result<TokenKey, String> {
+    match env_setting::<String>("TOKEN_SECRET")? {
+        Some(secret) if secret.len() < 32 => {
+            Err("TOKEN_SECRET must be at least 32 characters".to_string())
+        }
+        Some(secret) => Ok(TokenKey::new(secret.as_bytes())),
+        None => {
+            warn!("no TOKEN_SECRET configured, tokens are only valid until a restart");
+            Ok(TokenKey::random())
+        }
+    }
+}
+
+fn load_upload_tokens(key: TokenKey) -> Result<UploadTokens, String> {
+    let ttl_secs = env_setting("UPLOAD_TOKEN_TTL_SECS")?.unwrap_or(DEFAULT_UPLOAD_TOKEN_TTL_SECS);
+    Ok(UploadTokens::new(key, Duration::from_secs(ttl_secs)))
+}
+
+fn load_share_links(key: TokenKey) -> Result<ShareLinks, String> {
+    let default_ttl_secs =
+        env_setting("SHARE_LINK_TTL_SECS")?.unwrap_or(DEFAULT_SHARE_LINK_TTL_SECS);
+    let max_ttl_secs =
+        env_setting("MAX_SHARE_LINK_TTL_SECS")?.unwrap_or(DEFAULT_MAX_SHARE_LINK_TTL_SECS);
+    if default_ttl_secs == 0 || default_ttl_secs > max_ttl_secs {
+        return Err(
+            "SHARE_LINK_TTL_SECS must be between 1 and MAX_SHARE_LINK_TTL_SECS".to_string(),
+        );
+    }
+    Ok(ShareLinks::new(
+        key,
+        Duration::from_secs(default_ttl_secs),
+        Duration::from_secs(max_ttl_secs),
+    ))
+}
+
+/// Every limit is off unless its rate is set. Bandwidth buckets hold one second of traffic.
+fn load_throttle() -> Result<Throttle, String> {
+    let mut throttle = Throttle::default();
+    if let Some(rate) = env_setting::<u64>("START_UPLOAD_RATE_PER_SEC")? {
+        let burst = env_setting("START_UPLOAD_BURST")?.unwrap_or(rate);
+        throttle.start_upload = Some(RateLimiter::new(rate, burst));
+    }
+    if let Some(rate) = env_setting::<u64>("USER_BANDWIDTH_BYTES_PER_SEC")? {
+        throttle.user_bandwidth = Some(RateLimiter::new(rate, rate));
+    }
+    if let Some(rate) = env_setting::<u64>("GLOBAL_BANDWIDTH_BYTES_PER_SEC")? {
+        throttle.global_bandwidth = Some(RateLimiter::new(rate, rate));
+    }
+    throttle.trust_proxy = env_setting("TRUST_PROXY_HEADERS")?.unwrap_or(false);
+    Ok(throttle)
+}
+
+/// Every chunk request may hold up to `MULTIPART_LIMIT` in memory, the global limit
+/// bounds how many do at once.
+fn load_concurrency_limits() -> Result<ConcurrencyLimits, String> {
+    let global_max = env_setting("MAX_CHUNKS_IN_FLIGHT")?.unwrap_or(DEFAULT_MAX_CHUNKS_IN_FLIGHT);
+    let per_upload_max = env_setting("MAX_CHUNKS_IN_FLIGHT_PER_UPLOAD")?
+        .unwrap_or(DEFAULT_MAX_CHUNKS_IN_FLIGHT_PER_UPLOAD);
+    let queue_timeout_ms =
+        env_setting("CHUNK_QUEUE_TIMEOUT_MS")?.unwrap_or(DEFAULT_CHUNK_QUEUE_TIMEOUT_MS);
+    Ok(ConcurrencyLimits::new(
+        global_max,
+        per_upload_max,
+        Duration::from_millis(queue_timeout_ms),
+    ))
+}
+
+fn open_sessions(pool: &DbPool, ttl_secs: u64) -> Result<Arc<dyn SessionStore>, String> {
+    let kind = env_setting::<String>("SESSION_STORE")?.unwrap_or_else(|| "database".to_string());
+    let redis_url = env_setting::<String>("REDIS_URL")?;
+    session::open_session_store(&kind, pool, redis_url.as_deref(), ttl_secs).map(Arc::from)
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     pretty_env_logger::init();
@@ -25,55 +254,170 @@ async fn main() -> std::io::Result<()> {
     let container = std::env::var("STORAGE_CONTAINER").expect("missing STORAGE_CONTAINER");
     //let blob_name = std::env::var("STORAGE_BLOB_NAME").expect("missing STORAGE_BLOB_NAME");
 
-    let config = Config::new(&account, &container);
+    let config = match load_config(&account, &container) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("load config failed: {}", e);
+            return Ok(());
+        }
+    };
 
-    let con_manager = SqliteConnectionManager::memory();
-    let pool_res = r2d2::Pool::new(con_manager);
-    if let Err(e) = pool_res {
-        error!("create pool failed: {:?}", e);
-        return Ok(());
+    let authenticator = match open_authenticator() {
+        Ok(authenticator) => Data::new(authenticator),
+        Err(e) => {
+            error!("load authentication config failed: {}", e);
+            return Ok(());
+        }
+    };
+    if !authenticator.enabled() {
+        warn!("no API_KEYS or JWT keys configured, all requests are anonymous");
     }
-    let pool = pool_res.unwrap();
+
+    let token_key = match load_token_key() {
+        Ok(token_key) => token_key,
+        Err(e) => {
+            error!("load token config failed: {}", e);
+            return Ok(());
+        }
+    };
+    let upload_tokens = match load_upload_tokens(token_key.clone()) {
+        Ok(upload_tokens) => Data::new(upload_tokens),
+        Err(e) => {
+            error!("load token config failed: {}", e);
+            return Ok(());
+        }
+    };
+    let share_links = match load_share_links(token_key) {
+        Ok(share_links) => Data::new(share_links),
+        Err(e) => {
+            error!("load share link config failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    let access_policy = match env_setting::<PathBuf>("ACCESS_RULES_FILE") {
+        Ok(Some(rules_file)) => AccessPolicy::load(&rules_file),
+        Ok(None) => Ok(AccessPolicy::default()),
+        Err(e) => Err(e),
+    };
+    let access_policy = match access_policy {
+        Ok(access_policy) => Data::new(access_policy),
+        Err(e) => {
+            error!("load access rules failed: {}", e);
+            return Ok(());
+        }
+    };
+    if !access_policy.enabled() {
+        warn!("no ACCESS_RULES_FILE configured, every caller may write anywhere");
+    }
+
+    let throttle = match load_throttle() {
+        Ok(throttle) => Data::new(throttle),
+        Err(e) => {
+            error!("load rate limits failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    let concurrency = match load_concurrency_limits() {
+        Ok(concurrency) => Data::new(concurrency),
+        Err(e) => {
+            error!("load concurrency limits failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    let database_url = std::env::var("DATABASE_URL").ok();
+    let pool = match DbPool::open(database_url.as_deref()) {
+        Ok(pool) => pool,
+        Err(e) => {
+            error!("create pool failed: {}", e);
+            return Ok(());
+        }
+    };
     debug!("create pool success");
-    let ret = pool.get().unwrap().execute(
-        r#"
-            CREATE TABLE  temp_file_uploader(
-            id   INTEGER PRIMARY KEY,
-            upload_id TEXT NOT NULL UNIQUE,
-            file_name TEXT NOT NULL,
-            file_size INTEGER NOT NULL,
-            file_hash TEXT NOT NULL,
-            content_type TEXT NOT NULL,
-            blob_access_token TEXT NOT NULL,
-            blob_file_hash TEXT NOT NULL,
-            created_dt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
-        );
-        CREATE INDEX temp_file_uploader_idxs ON temp_file_uploader(upload_id);
-        "#,
-        (), // empty list of parameters.
-    );
+    if let Err(e) = pool.migrate().await {
+        error!("migrate database failed: {:?}", e);
+        return Ok(());
+    }
+    debug!("migrate database success");
 
-    if let Err(e) = ret {
-        error!("create table failed: {:?}", e);
+    // uploads idle for longer than this expire
+    let session_ttl_secs = match env_setting("SESSION_TTL_SECS") {
+        Ok(ttl_secs) => ttl_secs.unwrap_or(DEFAULT_SESSION_TTL_SECS),
+        Err(e) => {
+            error!("load config failed: {}", e);
+            return Ok(());
+        }
+    };
+    // direct uploads send nothing to the server until they finish, they must not expire
+    // while their chunk URLs are still valid
+    if config.direct_uploads && config.direct_upload_url_ttl_secs > session_ttl_secs {
+        error!("load config failed: DIRECT_UPLOAD_URL_TTL_SECS must not exceed SESSION_TTL_SECS");
         return Ok(());
     }
-    debug!("create table success");
+    let sessions = match open_sessions(&pool, session_ttl_secs) {
+        Ok(sessions) => sessions,
+        Err(e) => {
+            error!("open session store failed: {}", e);
+            return Ok(());
+        }
+    };
+
+    let default_creds = Arc::new(DefaultAzureCredential::default());
+    let credentials = StorageCredentials::token_credential(default_creds);
+    let spool = match open_spool(
+        credentials.clone(),
+        database_url.as_deref(),
+        sessions.is_shared(),
+    ) {
+        Ok(spool) => Arc::new(spool),
+        Err(e) => {
+            error!("open spool failed: {}", e);
+            return Ok(());
+        }
+    };
+    if spool.enabled() {
+        // also picks up blocks spooled before a restart
+        actix_web::rt::spawn(Spool::run_worker(
+            spool.clone(),
+            config.clone(),
+            SPOOL_FLUSH_INTERVAL,
+        ));
+    }
 
-    let shared_credentails = Data::new(SharedData {
-        shared_data_map: Arc::new(Mutex::new(HashMap::new())),
-    });
+    actix_web::rt::spawn(apis::run_sweeper(
+        pool.clone(),
+        sessions.clone(),
+        spool.clone(),
+        config.clone(),
+        credentials.clone(),
+        Duration::from_secs(session_ttl_secs),
+        SWEEP_INTERVAL,
+    ));
+    let sessions = Data::from(sessions);
+    let spool = Data::from(spool);
+    let shared_credentails = Data::new(SharedData { credentials });
     let multipart_config = MultipartFormConfig::default();
     let multipart_config = multipart_config
-        .total_limit(100 * 1024 * 1024)
-        .memory_limit(100 * 1024 * 1024);
+        .total_limit(MULTIPART_LIMIT as usize)
+        .memory_limit(MULTIPART_LIMIT as usize);
 
     HttpServer::new(move || {
         App::new()
             .app_data(Data::new(multipart_config.clone()))
             .app_data(shared_credentails.clone())
+            .app_data(authenticator.clone())
+            .app_data(upload_tokens.clone())
+            .app_data(share_links.clone())
+            .app_data(access_policy.clone())
+            .app_data(throttle.clone())
+            .app_data(concurrency.clone())
             //.app_data(Data::new(PayloadConfig::new(128 * 1024 * 1024).clone()))
             .app_data(Data::new(config.clone()))
             .app_data(Data::new(pool.clone()))
+            .app_data(sessions.clone())
+            .app_data(spool.clone())
             .wrap(Logger::default())
             .wrap(Logger::new(
                 r#"%a %t "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#,
@@ -81,8 +425,21 @@ async fn main() -> std::io::Result<()> {
             .service(
                 web::scope("/api/v1")
                     .route("/start_upload", web::post().to(apis::start_upload))
-                    .route("/continue_upload", web::post().to(apis::continue_upload))
-                    .route("/finish_upload", web::post().to(apis::finish_upload)),
+                    .service(
+                        web::resource("/continue_upload")
+                            .wrap(GlobalChunkLimit(concurrency.clone()))
+                            .wrap(BandwidthLimit(throttle.clone()))
+                            .route(web::post().to(apis::continue_upload)),
+                    )
+                    .route("/finish_upload", web::post().to(apis::finish_upload))
+                    .route("/abort_upload", web::post().to(apis::abort_upload))
+                    .route("/concurrency", web::get().to(apis::concurrency_stats))
+                    .route("/files", web::get().to(apis::list_files))
+                    .route("/files/{id}", web::get().to(apis::download_file))
+                    .route("/files/{id}", web::delete().to(apis::delete_file))
+                    .route("/files/{id}/restore", web::post().to(apis::restore_file))
+                    .route("/files/{id}/share", web::post().to(apis::create_share))
+                    .route("/shares/{token}", web::get().to(apis::download_share)),
             )
             .service(
                 Files::new("statics", "./statics")
@@ -90,7 +447,7 @@ async fn main() -> std::io::Result<()> {
                     .index_file("index.html"),
             )
     })
-        .bind(("0.0.0.0", 8888))?
-        .run()
-        .await
+    .bind(("0.0.0.0", 8888))?
+    .run()
+    .await
 }
@@ -0,0 +1,259 @@
+use crate::db::Dialect;
+
+/// A schema change applied once to the metadata database, recorded in `schema_migrations`.
+///
+/// The same SQL serves SQLite and Postgres. Types that differ between them are written as
+/// tokens, see `Migration::render`. Append new migrations, never edit applied ones.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+const TYPE_TOKENS: &[(&str, &str, &str)] = &[
+    // (token, sqlite, postgres)
+    ("{id}", "INTEGER PRIMARY KEY", "BIGSERIAL PRIMARY KEY"),
+    ("{bool}", "INTEGER", "BOOLEAN"),
+    ("{blob}", "BLOB", "BYTEA"),
+    ("{timestamp}", "DATETIME", "TIMESTAMPTZ"),
+    // current unix seconds
+    (
+        "{now}",
+        "CAST(strftime('%s', 'now') AS INTEGER)",
+        "CAST(EXTRACT(EPOCH FROM NOW()) AS BIGINT)",
+    ),
+    // unix seconds of a created_dt column
+    (
+        "{created_dt_secs}",
+        "CAST(strftime('%s', created_dt) AS INTEGER)",
+        "CAST(EXTRACT(EPOCH FROM created_dt) AS BIGINT)",
+    ),
+];
+
+impl Migration {
+    pub fn render(&self, dialect: Dialect) -> String {
+        let mut sql = self.sql.to_string();
+        for (token, sqlite, postgres) in TYPE_TOKENS {
+            let replacement = match dialect {
+                Dialect::Sqlite => sqlite,
+                Dialect::Postgres => postgres,
+            };
+            sql = sql.replace(token, replacement);
+        }
+        sql
+    }
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial schema",
+        sql: r#"
+            CREATE TABLE temp_file_uploader(
+                id {id},
+                upload_id TEXT NOT NULL UNIQUE,
+                file_name TEXT NOT NULL,
+                file_size BIGINT NOT NULL,
+                file_hash TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                blob_access_token TEXT NOT NULL,
+                blob_file_hash TEXT NOT NULL,
+                blob_name TEXT NOT NULL UNIQUE,
+                conflict_mode TEXT NOT NULL,
+                block_size BIGINT NOT NULL,
+                chunk_size BIGINT NOT NULL,
+                staged_size BIGINT NOT NULL DEFAULT 0,
+                created_dt {timestamp} NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX temp_file_uploader_idxs ON temp_file_uploader(upload_id);
+
+            CREATE TABLE upload_buffers(
+                upload_id TEXT PRIMARY KEY,
+                data_offset BIGINT NOT NULL,
+                data {blob} NOT NULL
+            );
+
+            CREATE TABLE file_contents(
+                content_id TEXT PRIMARY KEY,
+                file_hash TEXT NOT NULL,
+                file_size BIGINT NOT NULL,
+                blob_name TEXT NOT NULL UNIQUE,
+                verified {bool} NOT NULL,
+                ref_count BIGINT NOT NULL,
+                created_dt {timestamp} NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX file_contents_hash_idxs ON file_contents(file_hash, file_size);
+
+            CREATE TABLE uploaded_files(
+                upload_id TEXT PRIMARY KEY,
+                file_name TEXT NOT NULL,
+                file_size BIGINT NOT NULL,
+                file_hash TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                content_id TEXT NOT NULL REFERENCES file_contents(content_id),
+                deduplicated {bool} NOT NULL,
+                created_dt {timestamp} NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX uploaded_files_content_idxs ON uploaded_files(content_id);
+        "#,
+    },
+    Migration {
+        version: 2,
+        name: "upload chunk ledger",
+        sql: r#"
+            CREATE TABLE upload_chunks(
+                upload_id TEXT NOT NULL,
+                chunk_index BIGINT NOT NULL,
+                chunk_offset BIGINT NOT NULL,
+                chunk_size BIGINT NOT NULL,
+                checksum TEXT NOT NULL,
+                block_id TEXT NOT NULL,
+                received_at BIGINT NOT NULL,
+                PRIMARY KEY (upload_id, chunk_index)
+            );
+        "#,
+    },
+    Migration {
+        version: 3,
+        name: "upload state",
+        // rebuilt because SQLite cannot drop the UNIQUE constraint of blob_name, which
+        // becomes a partial index so finished uploads no longer hold their name
+        sql: r#"
+            CREATE TABLE temp_file_uploader_v3(
+                id {id},
+                upload_id TEXT NOT NULL UNIQUE,
+                file_name TEXT NOT NULL,
+                file_size BIGINT NOT NULL,
+                file_hash TEXT NOT NULL,
+                content_type TEXT NOT NULL,
+                blob_access_token TEXT NOT NULL,
+                blob_file_hash TEXT NOT NULL,
+                blob_name TEXT NOT NULL,
+                conflict_mode TEXT NOT NULL,
+                block_size BIGINT NOT NULL,
+                chunk_size BIGINT NOT NULL,
+                staged_size BIGINT NOT NULL DEFAULT 0,
+                state TEXT NOT NULL,
+                updated_at BIGINT NOT NULL,
+                created_dt {timestamp} NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            INSERT INTO temp_file_uploader_v3(
+                upload_id, file_name, file_size, file_hash, content_type, blob_access_token,
+                blob_file_hash, blob_name, conflict_mode, block_size, chunk_size, staged_size,
+                state, updated_at, created_dt
+            )
+            SELECT
+                upload_id, file_name, file_size, file_hash, content_type, blob_access_token,
+                blob_file_hash, blob_name, conflict_mode, block_size, chunk_size, staged_size,
+                CASE WHEN staged_size > 0 THEN 'uploading' ELSE 'created' END, {now}, created_dt
+            FROM temp_file_uploader;
+            DROP TABLE temp_file_uploader;
+            ALTER TABLE temp_file_uploader_v3 RENAME TO temp_file_uploader;
+            CREATE INDEX temp_file_uploader_idxs ON temp_file_uploader(upload_id);
+            CREATE UNIQUE INDEX temp_file_uploader_active_blob_idxs ON temp_file_uploader(blob_name)
+                WHERE state IN ('created', 'uploading', 'finalizing');
+            CREATE INDEX temp_file_uploader_state_idxs ON temp_file_uploader(state, updated_at);
+        "#,
+    },
+    Migration {
+        version: 4,
+        name: "upload owner",
+        // rows from before authentication belong to the anonymous caller
+        sql: r#"
+            ALTER TABLE temp_file_uploader ADD COLUMN owner TEXT NOT NULL DEFAULT 'anonymous';
+            ALTER TABLE uploaded_files ADD COLUMN owner TEXT NOT NULL DEFAULT 'anonymous';
+            CREATE INDEX uploaded_files_owner_idxs ON uploaded_files(owner);
+        "#,
+    },
+    Migration {
+        version: 5,
+        name: "storage quotas",
+        sql: r#"
+            ALTER TABLE temp_file_uploader ADD COLUMN tenant TEXT;
+            ALTER TABLE uploaded_files ADD COLUMN tenant TEXT;
+            CREATE INDEX uploaded_files_tenant_idxs ON uploaded_files(tenant);
+
+            CREATE TABLE upload_reservations(
+                upload_id TEXT PRIMARY KEY,
+                owner TEXT NOT NULL,
+                tenant TEXT,
+                file_size BIGINT NOT NULL
+            );
+            CREATE INDEX upload_reservations_owner_idxs ON upload_reservations(owner);
+            CREATE INDEX upload_reservations_tenant_idxs ON upload_reservations(tenant);
+
+            CREATE TABLE quota_limits(
+                subject TEXT PRIMARY KEY,
+                max_bytes BIGINT NOT NULL
+            );
+
+            CREATE TABLE quota_subjects(
+                subject TEXT PRIMARY KEY,
+                updates BIGINT NOT NULL
+            );
+        "#,
+    },
+    Migration {
+        version: 6,
+        name: "share links",
+        // only links with a download limit get a row, everything else is in the link
+        sql: r#"
+            CREATE TABLE share_links(
+                share_id TEXT PRIMARY KEY,
+                upload_id TEXT NOT NULL,
+                owner TEXT NOT NULL,
+                max_downloads BIGINT NOT NULL,
+                downloads BIGINT NOT NULL,
+                expires_at BIGINT NOT NULL,
+                created_dt {timestamp} NOT NULL DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE INDEX share_links_expires_idxs ON share_links(expires_at);
+        "#,
+    },
+    Migration {
+        version: 7,
+        name: "uploaded file times",
+        // unix seconds compare the same way in both databases, unlike created_dt
+        sql: r#"
+            ALTER TABLE uploaded_files ADD COLUMN created_at BIGINT NOT NULL DEFAULT 0;
+            UPDATE uploaded_files SET created_at = {created_dt_secs};
+            CREATE INDEX uploaded_files_owner_created_idxs ON uploaded_files(owner, created_at);
+        "#,
+    },
+    Migration {
+        version: 8,
+        name: "file deletion",
+        sql: r#"
+            ALTER TABLE uploaded_files ADD COLUMN state TEXT NOT NULL DEFAULT 'active';
+            ALTER TABLE uploaded_files ADD COLUMN deleted_at BIGINT;
+            ALTER TABLE uploaded_files ADD COLUMN purge_at BIGINT;
+            CREATE INDEX uploaded_files_purge_idxs ON uploaded_files(state, purge_at);
+        "#,
+    },
+    Migration {
+        version: 9,
+        name: "metadata and tags",
+        // JSON objects of string values
+        sql: r#"
+            ALTER TABLE temp_file_uploader ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
+            ALTER TABLE temp_file_uploader ADD COLUMN tags TEXT NOT NULL DEFAULT '{}';
+            ALTER TABLE uploaded_files ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}';
+            ALTER TABLE uploaded_files ADD COLUMN tags TEXT NOT NULL DEFAULT '{}';
+        "#,
+    },
+    Migration {
+        version: 10,
+        name: "access tiers",
+        sql: r#"
+            ALTER TABLE temp_file_uploader ADD COLUMN access_tier TEXT;
+            ALTER TABLE uploaded_files ADD COLUMN access_tier TEXT;
+        "#,
+    },
+    Migration {
+        version: 11,
+        name: "direct uploads",
+        sql: r#"
+            ALTER TABLE temp_file_uploader ADD COLUMN transfer_mode TEXT NOT NULL DEFAULT 'proxied';
+        "#,
+    },
+];
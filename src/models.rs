@@ -1,15 +1,19 @@
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
 
 use actix_multipart::form::bytes::Bytes;
-use actix_multipart::form::MultipartForm;
 use actix_multipart::form::text::Text;
-use actix_web::{HttpResponse, ResponseError};
-use actix_web::http::header::ContentType;
+use actix_multipart::form::MultipartForm;
+use actix_web::http::header::{ContentType, RETRY_AFTER};
 use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
 use azure_storage::StorageCredentials;
-use r2d2_sqlite::SqliteConnectionManager;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::retry::{CircuitBreaker, RetryPolicy};
+use crate::tokens::UploadToken;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct UploadInfo {
@@ -20,21 +24,396 @@ pub struct UploadInfo {
     pub content_type: String,
     pub blob_access_token: String,
     pub blob_file_hash: String,
+    pub blob_name: String,
+    pub conflict_mode: ConflictMode,
+    /// size of the blocks written to storage, chunks are split or coalesced to it
+    pub block_size: u64,
+    /// chunk size negotiated in `start_upload`, larger chunks are rejected
+    pub chunk_size: u64,
+    /// bytes appended to the staging blob so far, the next block goes to this offset
+    pub staged_size: u64,
+    pub state: UploadState,
+    /// `Caller::id` of whoever started the upload, nobody else may touch it
+    #[serde(default)]
+    pub owner: String,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    /// set on the blob when it is promoted
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// the default tier of the account when `None`
+    #[serde(default)]
+    pub access_tier: Option<AccessTier>,
+    #[serde(default)]
+    pub transfer_mode: TransferMode,
+}
+
+/// Bytes held back after the last whole block, starting at `offset` in the staged blob.
+/// They are saved after every chunk, even without bytes to hold back, so `offset`
+/// always tells where the data of the next chunk starts.
+#[derive(Clone, Debug, Default)]
+pub struct PendingData {
+    pub offset: u64,
+    pub data: Vec<u8>,
+}
+
+/// One chunk received by `continue_upload`, as recorded in the `upload_chunks` ledger.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadChunk {
+    pub upload_id: String,
+    pub chunk_index: u64,
+    /// position of the chunk in the file
+    pub chunk_offset: u64,
+    pub chunk_size: u64,
+    /// hex MD5 of the chunk data, computed by the server
+    pub checksum: String,
+    /// id of the storage block the chunk starts in
+    pub block_id: String,
+    /// unix seconds
+    pub received_at: i64,
+}
+
+/// A physical blob in the container. Several uploaded files may point to the same
+/// content when their verified hash matches, `ref_count` tracks how many do.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileContent {
+    pub content_id: String,
+    pub file_hash: String,
+    pub file_size: u64,
+    pub blob_name: String,
+    pub verified: bool,
+    pub ref_count: u64,
+}
+
+/// A finished upload as the user sees it, linked to the content holding its data.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct UploadedFile {
+    pub upload_id: String,
+    pub file_name: String,
+    pub file_size: u64,
+    pub file_hash: String,
+    pub content_type: String,
+    pub content_id: String,
+    pub deduplicated: bool,
+    pub owner: String,
+    pub tenant: Option<String>,
+    /// unix seconds
+    pub created_at: i64,
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// as set when the blob was promoted
+    #[serde(default)]
+    pub access_tier: Option<AccessTier>,
+}
+
+/// What `start_upload` does when the target blob name is already taken,
+/// either by an existing object or by another upload still in progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictMode {
+    /// reject the upload with 409 Conflict
+    Fail,
+    /// replace the existing object
+    Overwrite,
+    /// pick the first free name with a numeric suffix, e.g. `report_1.csv`
+    Rename,
+}
+
+impl ConflictMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ConflictMode::Fail => "fail",
+            ConflictMode::Overwrite => "overwrite",
+            ConflictMode::Rename => "rename",
+        }
+    }
+}
+
+/// Azure access tier a finished blob is stored in. `Archive` blobs have to be rehydrated
+/// before they can be read, downloads fail until then. The storage client cannot set the
+/// `Cold` tier, it is rejected by name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessTier {
+    Hot,
+    Cool,
+    Archive,
+}
+
+impl AccessTier {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessTier::Hot => "hot",
+            AccessTier::Cool => "cool",
+            AccessTier::Archive => "archive",
+        }
+    }
+}
+
+/// How the data of an upload gets to storage.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    /// chunks are sent to `continue_upload` and written to storage by the server
+    #[default]
+    Proxied,
+    /// chunks are put straight into storage as blocks with the SAS URLs returned by
+    /// `start_upload`, the server only commits and verifies them in `finish_upload`
+    Direct,
+}
+
+impl TransferMode {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TransferMode::Proxied => "proxied",
+            TransferMode::Direct => "direct",
+        }
+    }
+}
+
+impl FromStr for TransferMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "proxied" => Ok(TransferMode::Proxied),
+            "direct" => Ok(TransferMode::Direct),
+            _ => Err(format!("unknown transfer mode: {}", s)),
+        }
+    }
+}
+
+impl FromStr for AccessTier {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "hot" => Ok(AccessTier::Hot),
+            "cool" => Ok(AccessTier::Cool),
+            "archive" => Ok(AccessTier::Archive),
+            "cold" => Err("access tier cold is not supported, use cool or archive".to_string()),
+            _ => Err(format!("unknown access tier: {}", s)),
+        }
+    }
+}
+
+/// Goes through `FromStr`, so requests and rules files get its errors.
+impl<'de> Deserialize<'de> for AccessTier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Picks the access tier of uploads that do not ask for one. Every condition set must
+/// hold, the first matching rule wins.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AccessTierRule {
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    /// exact, or `type/*` for every subtype
+    #[serde(default)]
+    pub content_type: Option<String>,
+    pub tier: AccessTier,
+}
+
+impl AccessTierRule {
+    pub fn matches(&self, file_size: u64, content_type: &str) -> bool {
+        self.min_size.is_none_or(|min_size| file_size >= min_size)
+            && self.max_size.is_none_or(|max_size| file_size <= max_size)
+            && self
+                .content_type
+                .as_deref()
+                .is_none_or(|pattern| match pattern.strip_suffix("/*") {
+                    Some(major) => content_type.split('/').next() == Some(major),
+                    None => content_type == pattern,
+                })
+    }
+}
+
+impl FromStr for ConflictMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "fail" => Ok(ConflictMode::Fail),
+            "overwrite" => Ok(ConflictMode::Overwrite),
+            "rename" => Ok(ConflictMode::Rename),
+            _ => Err(format!("unknown conflict mode: {}", s)),
+        }
+    }
+}
+
+/// Lifecycle of an upload. `Created`, `Uploading` and `Finalizing` are active, the
+/// others are terminal: the upload takes no more chunks and its blob name is free again.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UploadState {
+    Created,
+    Uploading,
+    Finalizing,
+    Completed,
+    Failed,
+    Aborted,
+    Expired,
+}
+
+pub const ACTIVE_STATES: &[UploadState] = &[
+    UploadState::Created,
+    UploadState::Uploading,
+    UploadState::Finalizing,
+];
+
+impl UploadState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UploadState::Created => "created",
+            UploadState::Uploading => "uploading",
+            UploadState::Finalizing => "finalizing",
+            UploadState::Completed => "completed",
+            UploadState::Failed => "failed",
+            UploadState::Aborted => "aborted",
+            UploadState::Expired => "expired",
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        ACTIVE_STATES.contains(self)
+    }
+
+    /// States an upload may move to `self` from. Repeating `Uploading` or `Finalizing` is
+    /// allowed, so every chunk and a retried `finish_upload` pass the same check.
+    pub fn allowed_from(&self) -> &'static [UploadState] {
+        match self {
+            UploadState::Created => &[],
+            UploadState::Uploading => &[UploadState::Created, UploadState::Uploading],
+            UploadState::Finalizing | UploadState::Failed | UploadState::Expired => ACTIVE_STATES,
+            UploadState::Completed => &[UploadState::Finalizing],
+            // once finalizing the data is being published, it is too late to abort
+            UploadState::Aborted => &[UploadState::Created, UploadState::Uploading],
+        }
+    }
+}
+
+impl std::fmt::Display for UploadState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for FileState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "active" => Ok(FileState::Active),
+            "trashed" => Ok(FileState::Trashed),
+            "deleted" => Ok(FileState::Deleted),
+            _ => Err(format!("unknown file state: {}", s)),
+        }
+    }
+}
+
+impl FromStr for UploadState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "created" => Ok(UploadState::Created),
+            "uploading" => Ok(UploadState::Uploading),
+            "finalizing" => Ok(UploadState::Finalizing),
+            "completed" => Ok(UploadState::Completed),
+            "failed" => Ok(UploadState::Failed),
+            "aborted" => Ok(UploadState::Aborted),
+            "expired" => Ok(UploadState::Expired),
+            _ => Err(format!("unknown upload state: {}", s)),
+        }
+    }
+}
+
+/// Network conditions a client declares so the server can pick a chunk size for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectionProfile {
+    /// slow or flaky links, small chunks lose less on a retry
+    Mobile,
+    Standard,
+    /// fast and stable links, as few requests as policy allows
+    Datacenter,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Config {
     pub account: String,
     pub container: String,
+    pub conflict_mode: ConflictMode,
+    pub min_chunk_size: u64,
+    pub max_chunk_size: u64,
+    /// largest request body the WAF in front of the server lets through
+    pub waf_max_request_size: Option<u64>,
+    pub retry_policy: RetryPolicy,
+    /// quota of users and tenants without a row in `quota_limits`, `None` for no limit
+    pub user_quota_bytes: Option<u64>,
+    pub tenant_quota_bytes: Option<u64>,
+    /// how long deleted files stay restorable, 0 deletes right away
+    pub trash_period_secs: u64,
+    /// access tier of uploads not asking for one, by the first matching rule
+    pub access_tier_rules: Vec<AccessTierRule>,
+    pub default_access_tier: Option<AccessTier>,
+    /// whether `start_upload` hands out SAS URLs for `TransferMode::Direct`
+    pub direct_uploads: bool,
+    /// how long the SAS URLs of a direct upload stay valid
+    pub direct_upload_url_ttl_secs: u64,
+    /// shared by all workers, clones of the config point to the same breaker
+    #[serde(skip)]
+    pub circuit_breaker: Arc<CircuitBreaker>,
 }
 
 impl Config {
-    pub fn new(account: &String, container: &String) -> Config {
+    pub fn new(account: &str, container: &str) -> Config {
         Config {
-            account: account.clone(),
-            container: container.clone(),
+            account: account.to_string(),
+            container: container.to_string(),
+            conflict_mode: ConflictMode::Fail,
+            min_chunk_size: MIN_CHUNK_SIZE,
+            max_chunk_size: MAX_CHUNK_SIZE,
+            waf_max_request_size: None,
+            retry_policy: RetryPolicy::default(),
+            user_quota_bytes: None,
+            tenant_quota_bytes: None,
+            trash_period_secs: 0,
+            access_tier_rules: Vec::new(),
+            default_access_tier: None,
+            direct_uploads: false,
+            direct_upload_url_ttl_secs: DEFAULT_DIRECT_UPLOAD_URL_TTL_SECS,
+            circuit_breaker: Arc::new(CircuitBreaker::default()),
         }
     }
+
+    /// The tier an upload asked for, otherwise the one of the first matching rule, otherwise
+    /// the default tier.
+    pub fn access_tier_for(
+        &self,
+        requested: Option<AccessTier>,
+        file_size: u64,
+        content_type: &str,
+    ) -> Option<AccessTier> {
+        requested
+            .or_else(|| {
+                self.access_tier_rules
+                    .iter()
+                    .find(|rule| rule.matches(file_size, content_type))
+                    .map(|rule| rule.tier)
+            })
+            .or(self.default_access_tier)
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -47,6 +426,24 @@ pub struct StartUploadRequest {
     pub file_hash: String,
     #[serde(rename = "content_type")]
     pub content_type: String,
+    #[serde(rename = "conflict_mode", default)]
+    pub conflict_mode: Option<ConflictMode>,
+    #[serde(rename = "preferred_chunk_size", default)]
+    pub preferred_chunk_size: Option<u64>,
+    #[serde(rename = "connection_profile", default)]
+    pub connection_profile: Option<ConnectionProfile>,
+    /// blob metadata, see `attributes::validate_metadata`
+    #[serde(default)]
+    pub metadata: BTreeMap<String, String>,
+    /// blob index tags, see `attributes::validate_tags`
+    #[serde(default)]
+    pub tags: BTreeMap<String, String>,
+    /// chosen by `Config::access_tier_for` when unset
+    #[serde(default)]
+    pub access_tier: Option<AccessTier>,
+    /// `TransferMode::Proxied` when unset
+    #[serde(default)]
+    pub transfer_mode: Option<TransferMode>,
 }
 
 #[derive(Debug, MultipartForm)]
@@ -55,6 +452,17 @@ pub struct ContinueUploadRequest {
     pub upload_id: Text<String>,
     #[multipart(limit = "128MiB")]
     pub chunk_data: Option<Bytes>,
+    /// position of the chunk in the upload starting at 0, lets a retried chunk be
+    /// recognized as already stored
+    #[multipart(limit = "1KiB")]
+    pub chunk_index: Option<Text<u64>>,
+    /// optional digests of `chunk_data`, hex or base64 encoded
+    #[multipart(limit = "1KiB")]
+    pub chunk_md5: Option<Text<String>>,
+    #[multipart(limit = "1KiB")]
+    pub chunk_crc32c: Option<Text<String>>,
+    #[multipart(limit = "1KiB")]
+    pub chunk_sha256: Option<Text<String>>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -63,29 +471,80 @@ pub struct FinishUploadRequest {
     pub upload_id: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AbortUploadRequest {
+    #[serde(rename = "upload_id")]
+    pub upload_id: String,
+}
+
 pub const MAX_CHUNK_SIZE: u64 = 1024 * 1024 * 16;
+pub const MIN_CHUNK_SIZE: u64 = 1024 * 256;
+
+/// Lifetime of the SAS URLs of a direct upload, long enough for a large file on a slow link.
+pub const DEFAULT_DIRECT_UPLOAD_URL_TTL_SECS: u64 = 6 * 60 * 60;
+
+/// Limit of a whole multipart request to `continue_upload`.
+pub const MULTIPART_LIMIT: u64 = 1024 * 1024 * 100;
+/// Room left in a request for the multipart boundaries and the other form fields.
+pub const MULTIPART_OVERHEAD: u64 = 1024 * 64;
+
+/// How many `_{n}` suffixes `ConflictMode::Rename` tries before giving up.
+pub const MAX_RENAME_ATTEMPTS: u32 = 100;
 
 #[derive(Clone, Debug, derive_more::Display, Serialize, Deserialize)]
+#[display(fmt = "{}", error)]
 pub struct ErrorResponse {
     pub error: String,
+    #[serde(skip)]
+    pub status: StatusCode,
+    /// seconds sent back in `Retry-After`
+    #[serde(skip)]
+    pub retry_after: Option<u64>,
 }
 
 impl ErrorResponse {
     pub fn new(error: &str) -> ErrorResponse {
+        ErrorResponse::with_status(StatusCode::INTERNAL_SERVER_ERROR, error)
+    }
+
+    pub fn with_status(status: StatusCode, error: &str) -> ErrorResponse {
+        ErrorResponse {
+            error: error.to_string(),
+            status,
+            retry_after: None,
+        }
+    }
+
+    /// 503 telling the client when to try again.
+    pub fn unavailable(error: &str, retry_after: Duration) -> ErrorResponse {
+        ErrorResponse {
+            error: error.to_string(),
+            status: StatusCode::SERVICE_UNAVAILABLE,
+            retry_after: Some(retry_after.as_secs().max(1)),
+        }
+    }
+
+    /// 429 telling the client when to try again.
+    pub fn too_many_requests(error: &str, retry_after: Duration) -> ErrorResponse {
         ErrorResponse {
             error: error.to_string(),
+            status: StatusCode::TOO_MANY_REQUESTS,
+            retry_after: Some(retry_after.as_secs_f64().ceil().max(1.0) as u64),
         }
     }
 }
 
 impl ResponseError for ErrorResponse {
     fn status_code(&self) -> StatusCode {
-        StatusCode::INTERNAL_SERVER_ERROR
+        self.status
     }
     fn error_response(&self) -> HttpResponse {
-        HttpResponse::build(self.status_code())
-            .insert_header(ContentType::json())
-            .json(self)
+        let mut builder = HttpResponse::build(self.status_code());
+        builder.insert_header(ContentType::json());
+        if let Some(retry_after) = self.retry_after {
+            builder.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        builder.json(self)
     }
 }
 
@@ -93,6 +552,150 @@ impl ResponseError for ErrorResponse {
 pub struct UploadResponse {
     pub upload_id: String,
     pub chunk_size: Option<u64>,
+    pub blob_name: Option<String>,
+    pub deduplicated: Option<bool>,
+    pub state: Option<UploadState>,
+    /// send as `X-Upload-Token` with the next `continue_upload` or `finish_upload`
+    pub upload_token: Option<UploadToken>,
+    /// bytes the caller may still upload, `None` without a quota
+    pub quota_remaining: Option<u64>,
+    /// where to put each chunk of a direct upload
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_urls: Option<Vec<ChunkUrl>>,
+    /// unix seconds
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_urls_expire_at: Option<i64>,
+}
+
+/// Pre-signed URL taking one chunk of a direct upload with `PUT`, the body being the
+/// `size` bytes of the file starting at `offset`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ChunkUrl {
+    pub chunk_index: u64,
+    pub offset: u64,
+    pub size: u64,
+    pub url: String,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileSort {
+    #[default]
+    Created,
+    Name,
+    Size,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc,
+}
+
+/// Lifecycle of an uploaded file. Deleted files go to the trash for the trash period, or
+/// straight to `Deleted` without one. `Deleted` rows stay as a record, their blob is gone
+/// once no other file links to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileState {
+    #[default]
+    Active,
+    Trashed,
+    Deleted,
+}
+
+impl FileState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FileState::Active => "active",
+            FileState::Trashed => "trashed",
+            FileState::Deleted => "deleted",
+        }
+    }
+}
+
+/// Whether the server checked the hash of a file's content.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileStatus {
+    Verified,
+    Unverified,
+}
+
+/// Query of `GET /files`, every filter is optional. Times are unix seconds, ranges include
+/// their lower and exclude their upper bound.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ListFilesQuery {
+    /// the caller by default, `*` for everyone; others need `admin`
+    #[serde(default)]
+    pub owner: Option<String>,
+    #[serde(default)]
+    pub name_prefix: Option<String>,
+    /// exact, or `type/*` for every subtype
+    #[serde(default)]
+    pub content_type: Option<String>,
+    #[serde(default)]
+    pub min_size: Option<u64>,
+    #[serde(default)]
+    pub max_size: Option<u64>,
+    #[serde(default)]
+    pub created_after: Option<i64>,
+    #[serde(default)]
+    pub created_before: Option<i64>,
+    #[serde(default)]
+    pub status: Option<FileStatus>,
+    /// `trashed` lists the trash
+    #[serde(default)]
+    pub state: FileState,
+    #[serde(default)]
+    pub sort: FileSort,
+    #[serde(default)]
+    pub order: SortOrder,
+    #[serde(default)]
+    pub limit: Option<u64>,
+    #[serde(default)]
+    pub offset: u64,
+}
+
+pub const DEFAULT_LIST_LIMIT: u64 = 50;
+pub const MAX_LIST_LIMIT: u64 = 1000;
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileEntry {
+    #[serde(flatten)]
+    pub file: UploadedFile,
+    pub blob_name: String,
+    pub status: FileStatus,
+    pub state: FileState,
+    /// unix seconds
+    pub deleted_at: Option<i64>,
+    /// when a trashed file is deleted for good, unix seconds
+    pub purge_at: Option<i64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteFileQuery {
+    /// skip the trash, also empties it of this file
+    #[serde(default)]
+    pub permanent: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DeleteFileResponse {
+    pub upload_id: String,
+    pub state: FileState,
+    pub purge_at: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FileListResponse {
+    pub files: Vec<FileEntry>,
+    /// files matching the filters, over all pages
+    pub total: u64,
+    pub limit: u64,
+    pub offset: u64,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -101,13 +704,69 @@ pub struct FinishResponse {
     pub upload_id: String,
     #[serde(rename = "file_hash")]
     pub file_hash: String,
+    #[serde(rename = "verified")]
+    pub verified: bool,
+    #[serde(rename = "state")]
+    pub state: UploadState,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct CreateShareRequest {
+    /// lifetime of the link, the configured default when unset
+    #[serde(default)]
+    pub expires_in_secs: Option<u64>,
+    #[serde(default)]
+    pub max_downloads: Option<u32>,
+    /// asked for in the `X-Share-Password` header when set
+    #[serde(default)]
+    pub password: Option<String>,
 }
 
-pub type DbPool = r2d2::Pool<SqliteConnectionManager>;
+/// Not `Debug`, whoever holds the url can download the file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ShareResponse {
+    pub share_id: String,
+    pub upload_id: String,
+    pub url: String,
+    /// unix seconds
+    pub expires_at: i64,
+    pub max_downloads: Option<u32>,
+    pub password_protected: bool,
+}
 
 pub type WebAPIResult<T> = Result<T, ErrorResponse>;
 
+/// Storage credentials shared by all uploads, every replica builds its own.
 #[derive(Debug, Clone)]
 pub struct SharedData {
-    pub shared_data_map: Arc<Mutex<HashMap<String, StorageCredentials>>>,
+    pub credentials: StorageCredentials,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn access_tier_parsed() {
+        assert_eq!("Cool".parse::<AccessTier>(), Ok(AccessTier::Cool));
+        assert_eq!(
+            serde_json::from_str::<AccessTier>(r#""archive""#).unwrap(),
+            AccessTier::Archive
+        );
+        assert_eq!(serde_json::to_string(&AccessTier::Hot).unwrap(), r#""hot""#);
+        assert_eq!(
+            "warm".parse::<AccessTier>(),
+            Err("unknown access tier: warm".to_string())
+        );
+    }
+
+    #[test]
+    fn cold_access_tier_rejected_by_name() {
+        let e = "cold".parse::<AccessTier>().unwrap_err();
+        assert_eq!(e, "access tier cold is not supported, use cool or archive");
+        let e = serde_json::from_str::<Vec<AccessTierRule>>(r#"[{"tier": "cold"}]"#)
+            .unwrap_err()
+            .to_string();
+        assert!(e.starts_with("access tier cold is not supported"), "{}", e);
+    }
 }
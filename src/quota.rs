@@ -0,0 +1,338 @@
+use actix_web::http::StatusCode;
+use tracing::error;
+
+use crate::db::{DbError, DbPool, Value};
+use crate::models::{Config, ErrorResponse, UploadInfo, WebAPIResult};
+
+// Usage of a user or tenant is what its uploaded files hold plus what its running uploads
+// declared. Deduplicated files reuse content the owner already stored and do not count,
+// neither do deleted files. Files in the trash still hold their blob and count.
+// Running uploads hold a row in upload_reservations from start_upload until they are
+// over, deleting it twice is harmless.
+
+/// Turns the reservation into stored bytes when run in the transaction recording the
+/// uploaded file, see `catalog::publish_uploaded_file`.
+pub const RELEASE_RESERVATION: &str = "DELETE FROM upload_reservations WHERE upload_id = $1;";
+
+fn quota_error(context: &str, e: DbError) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+fn user_subject(owner: &str) -> String {
+    format!("user:{}", owner)
+}
+
+fn tenant_subject(tenant: &str) -> String {
+    format!("tenant:{}", tenant)
+}
+
+/// Bytes used by `column = $param`, `column` being `owner` or `tenant`.
+fn usage_sql(column: &str, param: &str) -> String {
+    format!(
+        r#"(
+            SELECT CAST(COALESCE(SUM(file_size), 0) AS BIGINT) FROM uploaded_files
+            WHERE {column} = {param} AND NOT deduplicated AND state <> 'deleted'
+        ) + (
+            SELECT CAST(COALESCE(SUM(file_size), 0) AS BIGINT) FROM upload_reservations
+            WHERE {column} = {param}
+        )"#,
+        column = column,
+        param = param
+    )
+}
+
+fn max_bytes(limit: Option<u64>) -> i64 {
+    limit.map_or(i64::MAX, |limit| i64::try_from(limit).unwrap_or(i64::MAX))
+}
+
+/// The limit from `quota_limits`, or `default` when the subject has none.
+async fn limit(pool: &DbPool, subject: &str, default: Option<u64>) -> WebAPIResult<Option<u64>> {
+    let res = pool
+        .query_opt(
+            "SELECT max_bytes FROM quota_limits WHERE subject = $1;",
+            &[subject.into()],
+        )
+        .await;
+    match res {
+        Ok(Some(row)) => row
+            .get::<u64>(0)
+            .map(Some)
+            .map_err(|e| quota_error("query quota limit failed", e)),
+        Ok(None) => Ok(default),
+        Err(e) => Err(quota_error("query quota limit failed", e)),
+    }
+}
+
+async fn usage(pool: &DbPool, column: &str, value: &str) -> WebAPIResult<u64> {
+    let res = pool
+        .query_opt(
+            &format!("SELECT {};", usage_sql(column, "$1")),
+            &[value.into()],
+        )
+        .await;
+    match res {
+        Ok(Some(row)) => row
+            .get::<u64>(0)
+            .map_err(|e| quota_error("query quota usage failed", e)),
+        Ok(None) => Ok(0),
+        Err(e) => Err(quota_error("query quota usage failed", e)),
+    }
+}
+
+async fn limits(
+    pool: &DbPool,
+    config: &Config,
+    owner: &str,
+    tenant: Option<&str>,
+) -> WebAPIResult<(Option<u64>, Option<u64>)> {
+    let user_limit = limit(pool, &user_subject(owner), config.user_quota_bytes).await?;
+    let tenant_limit = match tenant {
+        Some(tenant) => limit(pool, &tenant_subject(tenant), config.tenant_quota_bytes).await?,
+        None => None,
+    };
+    Ok((user_limit, tenant_limit))
+}
+
+/// Bytes `owner` may still upload, the smaller of what is left of the user and the tenant
+/// quota. `None` when neither has a limit.
+pub async fn remaining(
+    pool: &DbPool,
+    config: &Config,
+    owner: &str,
+    tenant: Option<&str>,
+) -> WebAPIResult<Option<u64>> {
+    let (user_limit, tenant_limit) = limits(pool, config, owner, tenant).await?;
+    let mut remaining = None;
+    if let Some(user_limit) = user_limit {
+        let used = usage(pool, "owner", owner).await?;
+        remaining = Some(user_limit.saturating_sub(used));
+    }
+    if let (Some(tenant_limit), Some(tenant)) = (tenant_limit, tenant) {
+        let left = tenant_limit.saturating_sub(usage(pool, "tenant", tenant).await?);
+        remaining = Some(remaining.map_or(left, |remaining: u64| remaining.min(left)));
+    }
+    Ok(remaining)
+}
+
+/// Reserves the declared size of a new upload against the quota of its owner and tenant,
+/// rejecting it with 507 when either would be exceeded.
+///
+/// Concurrent reservations of the same user or tenant are serialized by touching its row
+/// in `quota_subjects` first, so two uploads cannot both take the last free bytes.
+pub async fn reserve(pool: &DbPool, config: &Config, upload_info: &UploadInfo) -> WebAPIResult<()> {
+    let owner = upload_info.owner.as_str();
+    let tenant = upload_info.tenant.as_deref();
+    // BIGINT holds the sizes, anything larger could never fit a quota
+    let file_size = match i64::try_from(upload_info.file_size) {
+        Ok(file_size) => file_size,
+        Err(_) => {
+            error!(
+                "upload {} of {} bytes too large for a quota",
+                upload_info.upload_id, upload_info.file_size
+            );
+            return Err(ErrorResponse::with_status(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "file_size too large",
+            ));
+        }
+    };
+    let (user_limit, tenant_limit) = limits(pool, config, owner, tenant).await?;
+    // the limit minus the new size cannot overflow, the usage plus it could
+    let insert = format!(
+        r#"
+            INSERT INTO upload_reservations(upload_id, owner, tenant, file_size)
+            SELECT CAST($1 AS TEXT), CAST($2 AS TEXT), CAST($3 AS TEXT), CAST($4 AS BIGINT)
+            WHERE {} <= CAST($5 AS BIGINT) - CAST($4 AS BIGINT)
+                AND {} <= CAST($6 AS BIGINT) - CAST($4 AS BIGINT);
+        "#,
+        usage_sql("owner", "$2"),
+        usage_sql("tenant", "$3"),
+    );
+    let mut subjects = vec![user_subject(owner)];
+    subjects.extend(tenant.map(tenant_subject));
+    let mut statements = Vec::new();
+    // always the user before the tenant, the same order for everyone avoids deadlocks
+    for subject in &subjects {
+        statements.push((
+            r#"
+                INSERT INTO quota_subjects(subject, updates) VALUES ($1, 0)
+                ON CONFLICT (subject) DO NOTHING;
+            "#,
+            vec![Value::from(subject)],
+        ));
+        statements.push((
+            "UPDATE quota_subjects SET updates = updates + 1 WHERE subject = $1;",
+            vec![Value::from(subject)],
+        ));
+    }
+    statements.push((
+        insert.as_str(),
+        vec![
+            (&upload_info.upload_id).into(),
+            owner.into(),
+            tenant.into(),
+            file_size.into(),
+            // without a limit, and for no tenant whose usage is always 0, nothing is rejected
+            max_bytes(user_limit).into(),
+            max_bytes(tenant_limit).into(),
+        ],
+    ));
+    let counts = pool
+        .transaction(&statements)
+        .await
+        .map_err(|e| quota_error("reserve quota failed", e))?;
+    if counts.last() == Some(&0) {
+        let remaining = remaining(pool, config, owner, tenant).await?.unwrap_or(0);
+        error!(
+            "upload {} of {} bytes over quota of {} ({} remaining)",
+            upload_info.upload_id, upload_info.file_size, owner, remaining
+        );
+        return Err(ErrorResponse::with_status(
+            StatusCode::INSUFFICIENT_STORAGE,
+            &format!("quota exceeded, {} bytes remaining", remaining),
+        ));
+    }
+    Ok(())
+}
+
+/// Gives back the bytes reserved by an upload that ended without storing anything.
+pub async fn release(pool: &DbPool, upload_id: &str) -> WebAPIResult<()> {
+    pool.execute(RELEASE_RESERVATION, &[upload_id.into()])
+        .await
+        .map(|_| ())
+        .map_err(|e| quota_error("release quota failed", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::UploadState;
+
+    async fn pool() -> DbPool {
+        let pool = DbPool::open(None).unwrap();
+        pool.migrate().await.unwrap();
+        pool
+    }
+
+    fn config() -> Config {
+        Config {
+            user_quota_bytes: Some(100),
+            tenant_quota_bytes: Some(150),
+            ..Config::new("account", "container")
+        }
+    }
+
+    fn upload_info(upload_id: &str, owner: &str, file_size: u64) -> UploadInfo {
+        serde_json::from_value(serde_json::json!({
+            "upload_id": upload_id,
+            "file_name": "a.bin",
+            "file_size": file_size,
+            "file_hash": "",
+            "content_type": "application/octet-stream",
+            "blob_access_token": "",
+            "blob_file_hash": "",
+            "blob_name": "a.bin",
+            "conflict_mode": "fail",
+            "block_size": 4,
+            "chunk_size": 4,
+            "staged_size": 0,
+            "state": UploadState::Created,
+            "owner": owner,
+            "tenant": "team",
+        }))
+        .unwrap()
+    }
+
+    fn status(res: WebAPIResult<()>) -> StatusCode {
+        res.unwrap_err().status
+    }
+
+    #[actix_web::test]
+    async fn upload_up_to_the_limit_reserved() {
+        let (pool, config) = (pool().await, config());
+        reserve(&pool, &config, &upload_info("u1", "alice", 60))
+            .await
+            .unwrap();
+        reserve(&pool, &config, &upload_info("u2", "alice", 40))
+            .await
+            .unwrap();
+        assert_eq!(
+            remaining(&pool, &config, "alice", Some("team"))
+                .await
+                .unwrap(),
+            Some(0)
+        );
+    }
+
+    #[actix_web::test]
+    async fn upload_over_the_limit_rejected() {
+        let (pool, config) = (pool().await, config());
+        reserve(&pool, &config, &upload_info("u1", "alice", 60))
+            .await
+            .unwrap();
+        let e = reserve(&pool, &config, &upload_info("u2", "alice", 41))
+            .await
+            .unwrap_err();
+        assert_eq!(e.status, StatusCode::INSUFFICIENT_STORAGE);
+        assert_eq!(e.error, "quota exceeded, 40 bytes remaining");
+        // the tenant limit counts every member
+        assert_eq!(
+            status(reserve(&pool, &config, &upload_info("u3", "bob", 91)).await),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        assert_eq!(
+            remaining(&pool, &config, "bob", Some("team"))
+                .await
+                .unwrap(),
+            Some(90)
+        );
+    }
+
+    #[actix_web::test]
+    async fn released_reservation_frees_quota() {
+        let (pool, config) = (pool().await, config());
+        reserve(&pool, &config, &upload_info("u1", "alice", 100))
+            .await
+            .unwrap();
+        assert_eq!(
+            status(reserve(&pool, &config, &upload_info("u2", "alice", 1)).await),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+        release(&pool, "u1").await.unwrap();
+        release(&pool, "u1").await.unwrap();
+        reserve(&pool, &config, &upload_info("u2", "alice", 100))
+            .await
+            .unwrap();
+    }
+
+    #[actix_web::test]
+    async fn oversized_upload_does_not_overflow() {
+        let (pool, config) = (pool().await, config());
+        assert_eq!(
+            status(reserve(&pool, &config, &upload_info("u1", "alice", u64::MAX)).await),
+            StatusCode::PAYLOAD_TOO_LARGE
+        );
+        reserve(&pool, &config, &upload_info("u2", "alice", 10))
+            .await
+            .unwrap();
+        assert_eq!(
+            status(reserve(&pool, &config, &upload_info("u3", "alice", i64::MAX as u64)).await),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+
+        // no limit at all still takes the largest size next to existing usage
+        let unlimited = Config::new("account", "container");
+        reserve(
+            &pool,
+            &unlimited,
+            &upload_info("u4", "alice", i64::MAX as u64 - 10),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            status(reserve(&pool, &unlimited, &upload_info("u5", "alice", 1)).await),
+            StatusCode::INSUFFICIENT_STORAGE
+        );
+    }
+}
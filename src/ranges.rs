@@ -0,0 +1,146 @@
+use actix_web::http::header::{EntityTag, HeaderValue};
+
+/// Outcome of a `Range` header against a resource of a known size.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ByteRange {
+    /// no usable range, send everything with 200
+    Full,
+    /// bytes `start..end` with 206
+    Partial { start: u64, end: u64 },
+    /// 416, the range lies behind the end
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range: `a-b`, `a-` or `-n` for the last n bytes. Several
+/// ranges or other units are answered with the full resource, which RFC 9110 allows.
+pub fn parse_range(header: &str, size: u64) -> ByteRange {
+    let spec = match header.trim().strip_prefix("bytes=") {
+        Some(spec) if !spec.contains(',') => spec.trim(),
+        _ => return ByteRange::Full,
+    };
+    let (first, last) = match spec.split_once('-') {
+        Some(parts) => parts,
+        None => return ByteRange::Full,
+    };
+    let (first, last) = (first.trim(), last.trim());
+    if first.is_empty() {
+        // suffix range
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if size == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial {
+                start: size.saturating_sub(n),
+                end: size,
+            },
+            Err(_) => ByteRange::Full,
+        };
+    }
+    let start = match first.parse::<u64>() {
+        Ok(start) => start,
+        Err(_) => return ByteRange::Full,
+    };
+    let end = if last.is_empty() {
+        size
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= start => last.saturating_add(1).min(size),
+            _ => return ByteRange::Full,
+        }
+    };
+    if start >= size {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial { start, end }
+}
+
+/// Whether a precondition header like `If-None-Match` lists `etag`.
+pub fn etag_matches(header: Option<&HeaderValue>, etag: &EntityTag) -> bool {
+    let header = match header.and_then(|header| header.to_str().ok()) {
+        Some(header) => header,
+        None => return false,
+    };
+    header
+        .split(',')
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.parse::<EntityTag>().is_ok_and(|tag| tag.weak_eq(etag)))
+}
+
+/// `If-Range` holds one strong ETag, dates never match since files carry none.
+pub fn if_range_matches(header: Option<&HeaderValue>, etag: &EntityTag) -> bool {
+    header
+        .and_then(|header| header.to_str().ok())
+        .and_then(|tag| tag.trim().parse::<EntityTag>().ok())
+        .is_some_and(|tag| tag.strong_eq(etag))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn partial(start: u64, end: u64) -> ByteRange {
+        ByteRange::Partial { start, end }
+    }
+
+    #[test]
+    fn closed_and_open_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), partial(0, 100));
+        assert_eq!(parse_range("bytes=100-", 1000), partial(100, 1000));
+        assert_eq!(parse_range(" bytes= 5 - 9 ", 1000), partial(5, 10));
+        // the end is clamped to the size
+        assert_eq!(parse_range("bytes=900-2000", 1000), partial(900, 1000));
+    }
+
+    #[test]
+    fn suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), partial(900, 1000));
+        assert_eq!(parse_range("bytes=-2000", 1000), partial(0, 1000));
+        assert_eq!(parse_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=-1", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn ranges_behind_the_end() {
+        assert_eq!(parse_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn unusable_ranges_send_everything() {
+        for header in [
+            "bytes=0-1,5-6",
+            "items=0-1",
+            "bytes=5-1",
+            "bytes=a-b",
+            "bytes=5",
+            "bytes=-x",
+            "",
+        ] {
+            assert_eq!(parse_range(header, 1000), ByteRange::Full, "{}", header);
+        }
+    }
+
+    #[test]
+    fn etag_lists() {
+        let etag = EntityTag::new_strong("abc".to_string());
+        let header = |value: &'static str| Some(HeaderValue::from_static(value));
+        assert!(etag_matches(header(r#""abc""#).as_ref(), &etag));
+        assert!(etag_matches(header(r#""x", W/"abc""#).as_ref(), &etag));
+        assert!(etag_matches(header("*").as_ref(), &etag));
+        assert!(!etag_matches(header(r#""x""#).as_ref(), &etag));
+        assert!(!etag_matches(None, &etag));
+    }
+
+    #[test]
+    fn if_range_needs_the_strong_etag() {
+        let etag = EntityTag::new_strong("abc".to_string());
+        let header = |value: &'static str| Some(HeaderValue::from_static(value));
+        assert!(if_range_matches(header(r#""abc""#).as_ref(), &etag));
+        assert!(!if_range_matches(header(r#"W/"abc""#).as_ref(), &etag));
+        assert!(!if_range_matches(header(r#""x""#).as_ref(), &etag));
+        assert!(!if_range_matches(
+            header("Wed, 21 Oct 2015 07:28:00 GMT").as_ref(),
+            &etag
+        ));
+        assert!(!if_range_matches(None, &etag));
+    }
+}
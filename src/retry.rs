@@ -0,0 +1,340 @@
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use azure_core::error::ErrorKind;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error};
+
+/// Exponential backoff with full jitter for storage calls that failed with a
+/// transient error.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: u32,
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 4,
+            base_delay_ms: 200,
+            max_delay_ms: 5_000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The longest wait after `attempt` failed, the actual wait is picked below it.
+    fn max_delay(&self, attempt: u32) -> Duration {
+        Duration::from_millis(
+            self.base_delay_ms
+                .saturating_mul(1u64 << attempt.min(16))
+                .min(self.max_delay_ms),
+        )
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        let max_delay = self.max_delay(attempt).as_millis() as u64;
+        Duration::from_millis(rand::thread_rng().gen_range(0..=max_delay))
+    }
+}
+
+struct BreakerState {
+    consecutive_failures: u32,
+    open_until: Option<Instant>,
+}
+
+/// Opens after `failure_threshold` storage calls in a row gave up, and then fails calls
+/// fast until `open_duration` has passed. The first call after that is let through as a
+/// probe: success closes the breaker, another failure opens it again.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        CircuitBreaker::new(5, Duration::from_secs(30))
+    }
+}
+
+impl std::fmt::Debug for CircuitBreaker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CircuitBreaker")
+            .field("failure_threshold", &self.failure_threshold)
+            .field("open_duration", &self.open_duration)
+            .finish()
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, open_duration: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold,
+            open_duration,
+            state: Mutex::new(BreakerState {
+                consecutive_failures: 0,
+                open_until: None,
+            }),
+        }
+    }
+
+    /// Returns how long the caller should wait when the breaker is open.
+    pub fn check(&self) -> Result<(), Duration> {
+        self.check_at(Instant::now())
+    }
+
+    fn check_at(&self, now: Instant) -> Result<(), Duration> {
+        let mut state = self.state.lock().unwrap();
+        match state.open_until {
+            Some(open_until) => {
+                if now < open_until {
+                    Err(open_until - now)
+                } else {
+                    // half open, a failure of this probe re-opens immediately
+                    state.open_until = None;
+                    state.consecutive_failures = self.failure_threshold.saturating_sub(1);
+                    Ok(())
+                }
+            }
+            None => Ok(()),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures = 0;
+        state.open_until = None;
+    }
+
+    pub fn record_failure(&self) {
+        self.record_failure_at(Instant::now())
+    }
+
+    fn record_failure_at(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        state.consecutive_failures += 1;
+        if state.consecutive_failures >= self.failure_threshold {
+            error!(
+                "storage circuit breaker open for {:?} after {} failures",
+                self.open_duration, state.consecutive_failures
+            );
+            state.open_until = Some(now + self.open_duration);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum StorageError {
+    /// the circuit breaker is open, retry after the given time
+    Unavailable(Duration),
+    Failed(azure_core::Error),
+}
+
+pub fn http_status(e: &azure_core::Error) -> Option<u16> {
+    match e.kind() {
+        ErrorKind::HttpResponse { status, .. } => Some(u16::from(*status)),
+        _ => None,
+    }
+}
+
+pub fn is_retryable(e: &azure_core::Error) -> bool {
+    match e.kind() {
+        ErrorKind::HttpResponse { status, .. } => {
+            matches!(u16::from(*status), 408 | 429 | 500 | 502 | 503 | 504)
+        }
+        ErrorKind::Io => true,
+        _ => false,
+    }
+}
+
+/// Runs a storage call with the retry policy behind the circuit breaker.
+/// `call` gets the attempt number starting at 1, so it can tell a first try from a retry.
+pub async fn with_retry<T, F, Fut>(
+    policy: &RetryPolicy,
+    breaker: &CircuitBreaker,
+    operation: &str,
+    mut call: F,
+) -> Result<T, StorageError>
+where
+    F: FnMut(u32) -> Fut,
+    Fut: Future<Output = azure_core::Result<T>>,
+{
+    if let Err(retry_after) = breaker.check() {
+        return Err(StorageError::Unavailable(retry_after));
+    }
+    let mut attempt = 1;
+    loop {
+        match call(attempt).await {
+            Ok(value) => {
+                breaker.record_success();
+                return Ok(value);
+            }
+            Err(e) if is_retryable(&e) && attempt < policy.max_attempts => {
+                let delay = policy.delay(attempt);
+                debug!(
+                    "{} attempt {} failed, retry in {:?}: {}",
+                    operation, attempt, delay, e
+                );
+                actix_web::rt::time::sleep(delay).await;
+                attempt += 1;
+            }
+            Err(e) => {
+                // a 404 or 409 says nothing about the health of the backend
+                if is_retryable(&e) {
+                    breaker.record_failure();
+                }
+                return Err(StorageError::Failed(e));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use azure_core::StatusCode;
+
+    use super::*;
+
+    fn http_error(status: StatusCode) -> azure_core::Error {
+        ErrorKind::http_response(status, None).into_error()
+    }
+
+    /// Runs `with_retry` against a call failing with `status` every time, returns the
+    /// number of attempts.
+    async fn attempts_failing_with(
+        status: StatusCode,
+        breaker: &CircuitBreaker,
+    ) -> (u32, Result<(), StorageError>) {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 0,
+            max_delay_ms: 0,
+        };
+        let calls = Cell::new(0);
+        let res = with_retry(&policy, breaker, "test", |attempt| {
+            calls.set(calls.get() + 1);
+            assert_eq!(attempt, calls.get());
+            async move { Err(http_error(status)) }
+        })
+        .await;
+        (calls.get(), res)
+    }
+
+    #[test]
+    fn delay_doubles_up_to_cap() {
+        let policy = RetryPolicy::default();
+        let delays: Vec<u64> = (1..=6)
+            .map(|attempt| policy.max_delay(attempt).as_millis() as u64)
+            .collect();
+        assert_eq!(delays, vec![400, 800, 1_600, 3_200, 5_000, 5_000]);
+        assert_eq!(policy.max_delay(u32::MAX), Duration::from_millis(5_000));
+        for attempt in 1..=6 {
+            assert!(policy.delay(attempt) <= policy.max_delay(attempt));
+        }
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let start = Instant::now();
+        for _ in 0..2 {
+            breaker.record_failure_at(start);
+            assert!(breaker.check_at(start).is_ok());
+        }
+        breaker.record_failure_at(start);
+        assert_eq!(
+            breaker.check_at(start + Duration::from_secs(10)),
+            Err(Duration::from_secs(20))
+        );
+    }
+
+    #[test]
+    fn success_resets_failure_count() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let start = Instant::now();
+        breaker.record_failure_at(start);
+        breaker.record_failure_at(start);
+        breaker.record_success();
+        breaker.record_failure_at(start);
+        breaker.record_failure_at(start);
+        assert!(breaker.check_at(start).is_ok());
+    }
+
+    #[test]
+    fn half_open_probe_success_closes() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(start);
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(breaker.check_at(later).is_ok());
+        breaker.record_success();
+        // closed again, it takes the full threshold to open
+        breaker.record_failure_at(later);
+        breaker.record_failure_at(later);
+        assert!(breaker.check_at(later).is_ok());
+    }
+
+    #[test]
+    fn half_open_probe_failure_reopens() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(30));
+        let start = Instant::now();
+        for _ in 0..3 {
+            breaker.record_failure_at(start);
+        }
+        let later = start + Duration::from_secs(30);
+        assert!(breaker.check_at(later).is_ok());
+        breaker.record_failure_at(later);
+        assert_eq!(breaker.check_at(later), Err(Duration::from_secs(30)));
+    }
+
+    #[actix_web::test]
+    async fn client_errors_not_retried() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        for status in [StatusCode::NotFound, StatusCode::Conflict] {
+            let (calls, res) = attempts_failing_with(status, &breaker).await;
+            assert_eq!(calls, 1);
+            assert!(matches!(res, Err(StorageError::Failed(_))));
+        }
+        // and they do not count against the backend
+        assert!(breaker.check().is_ok());
+    }
+
+    #[actix_web::test]
+    async fn server_errors_retried_then_counted() {
+        let breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let (calls, res) = attempts_failing_with(StatusCode::ServiceUnavailable, &breaker).await;
+        assert_eq!(calls, 3);
+        assert!(matches!(res, Err(StorageError::Failed(_))));
+        let (calls, res) = attempts_failing_with(StatusCode::ServiceUnavailable, &breaker).await;
+        assert_eq!(calls, 0);
+        assert!(matches!(res, Err(StorageError::Unavailable(_))));
+    }
+
+    #[actix_web::test]
+    async fn retried_call_succeeds() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            base_delay_ms: 0,
+            max_delay_ms: 0,
+        };
+        let breaker = CircuitBreaker::default();
+        let res = with_retry(&policy, &breaker, "test", |attempt| async move {
+            if attempt < 3 {
+                Err(http_error(StatusCode::InternalServerError))
+            } else {
+                Ok(attempt)
+            }
+        })
+        .await;
+        assert!(matches!(res, Ok(3)));
+    }
+}
@@ -0,0 +1,973 @@
+use actix_web::http::StatusCode;
+use actix_web::web;
+use async_trait::async_trait;
+use redis::Commands;
+use tracing::error;
+
+use crate::attributes;
+use crate::db::{unix_now, DbError, DbPool, DbRow, Dialect};
+use crate::models::{
+    ConflictMode, ErrorResponse, PendingData, UploadInfo, UploadState, WebAPIResult, ACTIVE_STATES,
+};
+
+/// State of uploads that have been started but not finished yet.
+///
+/// Every replica behind a load balancer must see the same sessions, so that
+/// `continue_upload` works wherever it lands. The metadata database serves a single
+/// instance with SQLite or any number of replicas with Postgres. Redis shares sessions
+/// too, but the ledger, quotas and catalog stay in the metadata database, so it needs
+/// Postgres alongside.
+#[async_trait(?Send)]
+pub trait SessionStore: Send + Sync {
+    /// Stores a new session. Fails with 409 when another session already writes to
+    /// the same blob name.
+    async fn create(&self, upload_info: &UploadInfo) -> WebAPIResult<()>;
+
+    async fn get(&self, upload_id: &str) -> WebAPIResult<Option<UploadInfo>>;
+
+    /// Returns true when a session that has not been finished yet writes to `blob_name`.
+    async fn is_blob_name_reserved(&self, blob_name: &str) -> WebAPIResult<bool>;
+
+    /// Returns the bytes carried over from the previous chunk of an upload.
+    async fn load_pending(&self, upload_id: &str) -> WebAPIResult<Option<PendingData>>;
+
+    /// Moves the staged size forward after a block was written, keeping the carried over bytes.
+    async fn save_staged_size(&self, upload_id: &str, staged_size: u64) -> WebAPIResult<()>;
+
+    /// Records the blocks written so far and the bytes carried over behind them, in one step.
+    async fn save_progress(
+        &self,
+        upload_id: &str,
+        staged_size: u64,
+        pending: &[u8],
+    ) -> WebAPIResult<()>;
+
+    /// Moves an upload to `state`, failing with 409 when its current state does not allow it.
+    /// Moving to a terminal state drops the carried over bytes and frees the blob name.
+    async fn transition(&self, upload_id: &str, state: UploadState) -> WebAPIResult<()>;
+
+    /// Expires active uploads idle for longer than `idle_secs` and returns them, so their
+    /// staged data can be deleted. Uploads that ended that long ago are forgotten.
+    async fn sweep(&self, idle_secs: u64) -> WebAPIResult<Vec<UploadInfo>>;
+
+    /// True when other replicas see the same sessions, so a request for an upload may land
+    /// on a replica that did not start it.
+    fn is_shared(&self) -> bool;
+}
+
+fn session_error<E: std::fmt::Debug>(context: &str, e: E) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+fn upload_not_found(upload_id: &str) -> ErrorResponse {
+    error!("upload not found: {}", upload_id);
+    ErrorResponse::with_status(StatusCode::NOT_FOUND, "upload not found")
+}
+
+fn state_conflict(upload_id: &str, current: UploadState, state: UploadState) -> ErrorResponse {
+    error!(
+        "upload {} is {}, cannot become {}",
+        upload_id, current, state
+    );
+    ErrorResponse::with_status(StatusCode::CONFLICT, &format!("upload is {}", current))
+}
+
+fn blob_name_conflict(blob_name: &str) -> ErrorResponse {
+    error!("upload already in progress for {}", blob_name);
+    ErrorResponse::with_status(StatusCode::CONFLICT, "upload already in progress")
+}
+
+/// Sessions in the metadata database, SQLite for a single instance or Postgres shared by
+/// all replicas.
+pub struct DbSessionStore {
+    pool: DbPool,
+}
+
+impl DbSessionStore {
+    pub fn new(pool: DbPool) -> DbSessionStore {
+        DbSessionStore { pool }
+    }
+}
+
+const UPLOAD_COLUMNS: &str = r#"
+                upload_id,
+                file_name,
+                file_size,
+                file_hash,
+                content_type,
+                blob_access_token,
+                blob_file_hash,
+                blob_name,
+                conflict_mode,
+                block_size,
+                chunk_size,
+                staged_size,
+                state,
+                owner,
+                tenant,
+                metadata,
+                tags,
+                access_tier,
+                transfer_mode"#;
+const UPDATE_STAGED_SIZE: &str =
+    "UPDATE temp_file_uploader SET staged_size = $2 WHERE upload_id = $1;";
+const DELETE_PENDING: &str = "DELETE FROM upload_buffers WHERE upload_id = $1;";
+const TERMINAL_STATES: &[UploadState] = &[
+    UploadState::Completed,
+    UploadState::Failed,
+    UploadState::Aborted,
+    UploadState::Expired,
+];
+
+/// `'created', 'uploading'` for use in `state IN (...)`. The names are our own constants,
+/// so they are safe to inline.
+fn state_list(states: &[UploadState]) -> String {
+    states
+        .iter()
+        .map(|state| format!("'{}'", state.as_str()))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn upload_info_from_row(row: &DbRow) -> Result<UploadInfo, DbError> {
+    let conflict_mode: String = row.get(8)?;
+    let state: String = row.get(12)?;
+    let transfer_mode: String = row.get(18)?;
+    Ok(UploadInfo {
+        upload_id: row.get(0)?,
+        file_name: row.get(1)?,
+        file_size: row.get(2)?,
+        file_hash: row.get(3)?,
+        content_type: row.get(4)?,
+        blob_access_token: row.get(5)?,
+        blob_file_hash: row.get(6)?,
+        blob_name: row.get(7)?,
+        conflict_mode: conflict_mode.parse().unwrap_or(ConflictMode::Fail),
+        block_size: row.get(9)?,
+        chunk_size: row.get(10)?,
+        staged_size: row.get(11)?,
+        state: state.parse().unwrap_or(UploadState::Failed),
+        owner: row.get(13)?,
+        tenant: row.get(14)?,
+        metadata: attributes::from_json(&row.get::<String>(15)?),
+        tags: attributes::from_json(&row.get::<String>(16)?),
+        access_tier: row
+            .get::<Option<String>>(17)?
+            .and_then(|tier| tier.parse().ok()),
+        transfer_mode: transfer_mode.parse().unwrap_or_default(),
+    })
+}
+
+#[async_trait(?Send)]
+impl SessionStore for DbSessionStore {
+    async fn create(&self, upload_info: &UploadInfo) -> WebAPIResult<()> {
+        let res = self
+            .pool
+            .execute(
+                r#"
+            INSERT INTO temp_file_uploader(
+                upload_id,
+                file_name,
+                file_size,
+                file_hash,
+                content_type,
+                blob_access_token,
+                blob_file_hash,
+                blob_name,
+                conflict_mode,
+                block_size,
+                chunk_size,
+                staged_size,
+                state,
+                updated_at,
+                owner,
+                tenant,
+                metadata,
+                tags,
+                access_tier,
+                transfer_mode
+            ) VALUES (
+                $1,
+                $2,
+                $3,
+                $4,
+                $5,
+                $6,
+                $7,
+                $8,
+                $9,
+                $10,
+                $11,
+                $12,
+                $13,
+                $14,
+                $15,
+                $16,
+                $17,
+                $18,
+                $19,
+                $20
+            );
+        "#,
+                &[
+                    (&upload_info.upload_id).into(),
+                    (&upload_info.file_name).into(),
+                    upload_info.file_size.into(),
+                    (&upload_info.file_hash).into(),
+                    (&upload_info.content_type).into(),
+                    (&upload_info.blob_access_token).into(),
+                    (&upload_info.blob_file_hash).into(),
+                    (&upload_info.blob_name).into(),
+                    upload_info.conflict_mode.as_str().into(),
+                    upload_info.block_size.into(),
+                    upload_info.chunk_size.into(),
+                    upload_info.staged_size.into(),
+                    upload_info.state.as_str().into(),
+                    unix_now().into(),
+                    (&upload_info.owner).into(),
+                    upload_info.tenant.as_deref().into(),
+                    attributes::to_json(&upload_info.metadata).into(),
+                    attributes::to_json(&upload_info.tags).into(),
+                    upload_info.access_tier.map(|tier| tier.as_str()).into(),
+                    upload_info.transfer_mode.as_str().into(),
+                ],
+            )
+            .await;
+        match res {
+            Ok(_) => Ok(()),
+            Err(e) if e.is_unique_violation() => Err(blob_name_conflict(&upload_info.blob_name)),
+            Err(e) => Err(session_error("insert failed", e)),
+        }
+    }
+
+    async fn get(&self, upload_id: &str) -> WebAPIResult<Option<UploadInfo>> {
+        let res = self
+            .pool
+            .query_opt(
+                &format!(
+                    "SELECT {} FROM temp_file_uploader WHERE upload_id = $1;",
+                    UPLOAD_COLUMNS
+                ),
+                &[upload_id.into()],
+            )
+            .await;
+        let res = match res {
+            Ok(Some(row)) => upload_info_from_row(&row).map(Some),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        res.map_err(|e| session_error("query failed", e))
+    }
+
+    async fn is_blob_name_reserved(&self, blob_name: &str) -> WebAPIResult<bool> {
+        let res = self
+            .pool
+            .query_opt(
+                &format!(
+                    r#"
+            SELECT COUNT(*) FROM temp_file_uploader WHERE blob_name = $1 AND state IN ({});
+        "#,
+                    state_list(ACTIVE_STATES)
+                ),
+                &[blob_name.into()],
+            )
+            .await;
+        let res = match res {
+            Ok(Some(row)) => row.get::<i64>(0).map(|count| count > 0),
+            Ok(None) => Ok(false),
+            Err(e) => Err(e),
+        };
+        res.map_err(|e| session_error("query failed", e))
+    }
+
+    async fn load_pending(&self, upload_id: &str) -> WebAPIResult<Option<PendingData>> {
+        let res = self
+            .pool
+            .query_opt(
+                "SELECT data_offset, data FROM upload_buffers WHERE upload_id = $1;",
+                &[upload_id.into()],
+            )
+            .await;
+        let res = match res {
+            Ok(Some(row)) => row.get::<u64>(0).and_then(|offset| {
+                Ok(Some(PendingData {
+                    offset,
+                    data: row.get(1)?,
+                }))
+            }),
+            Ok(None) => Ok(None),
+            Err(e) => Err(e),
+        };
+        res.map_err(|e| session_error("load pending data failed", e))
+    }
+
+    async fn save_staged_size(&self, upload_id: &str, staged_size: u64) -> WebAPIResult<()> {
+        self.pool
+            .execute(UPDATE_STAGED_SIZE, &[upload_id.into(), staged_size.into()])
+            .await
+            .map(|_| ())
+            .map_err(|e| session_error("save staged size failed", e))
+    }
+
+    async fn save_progress(
+        &self,
+        upload_id: &str,
+        staged_size: u64,
+        pending: &[u8],
+    ) -> WebAPIResult<()> {
+        self.pool
+            .transaction(&[
+                (
+                    UPDATE_STAGED_SIZE,
+                    vec![upload_id.into(), staged_size.into()],
+                ),
+                (DELETE_PENDING, vec![upload_id.into()]),
+                (
+                    "INSERT INTO upload_buffers(upload_id, data_offset, data) VALUES ($1, $2, $3);",
+                    vec![upload_id.into(), staged_size.into(), pending.into()],
+                ),
+            ])
+            .await
+            .map(|_| ())
+            .map_err(|e| session_error("save upload progress failed", e))
+    }
+
+    async fn transition(&self, upload_id: &str, state: UploadState) -> WebAPIResult<()> {
+        if state.allowed_from().is_empty() {
+            return Err(ErrorResponse::new("invalid state transition"));
+        }
+        // the check and the update are one statement, so racing requests cannot both pass
+        let updated = self
+            .pool
+            .execute(
+                &format!(
+                    r#"
+                UPDATE temp_file_uploader SET state = $2, updated_at = $3
+                WHERE upload_id = $1 AND state IN ({});
+            "#,
+                    state_list(state.allowed_from())
+                ),
+                &[upload_id.into(), state.as_str().into(), unix_now().into()],
+            )
+            .await
+            .map_err(|e| session_error("update state failed", e))?;
+        if updated == 0 {
+            return match self.get(upload_id).await? {
+                Some(upload_info) => Err(state_conflict(upload_id, upload_info.state, state)),
+                None => Err(upload_not_found(upload_id)),
+            };
+        }
+        if !state.is_active() {
+            self.pool
+                .execute(DELETE_PENDING, &[upload_id.into()])
+                .await
+                .map_err(|e| session_error("delete pending data failed", e))?;
+        }
+        Ok(())
+    }
+
+    async fn sweep(&self, idle_secs: u64) -> WebAPIResult<Vec<UploadInfo>> {
+        let now = unix_now();
+        let cutoff = now - idle_secs as i64;
+        let rows = self
+            .pool
+            .query(
+                &format!(
+                    r#"
+            SELECT {} FROM temp_file_uploader WHERE state IN ({}) AND updated_at < $1;
+        "#,
+                    UPLOAD_COLUMNS,
+                    state_list(ACTIVE_STATES)
+                ),
+                &[cutoff.into()],
+            )
+            .await
+            .map_err(|e| session_error("query idle uploads failed", e))?;
+        let mut expired = Vec::new();
+        for row in rows {
+            let mut upload_info = upload_info_from_row(&row)
+                .map_err(|e| session_error("query idle uploads failed", e))?;
+            // skipped when a chunk arrived since the query
+            let updated = self
+                .pool
+                .execute(
+                    &format!(
+                        r#"
+                UPDATE temp_file_uploader SET state = $2, updated_at = $3
+                WHERE upload_id = $1 AND state IN ({}) AND updated_at < $4;
+            "#,
+                        state_list(ACTIVE_STATES)
+                    ),
+                    &[
+                        (&upload_info.upload_id).into(),
+                        UploadState::Expired.as_str().into(),
+                        now.into(),
+                        cutoff.into(),
+                    ],
+                )
+                .await
+                .map_err(|e| session_error("expire upload failed", e))?;
+            if updated > 0 {
+                self.pool
+                    .execute(DELETE_PENDING, &[(&upload_info.upload_id).into()])
+                    .await
+                    .map_err(|e| session_error("delete pending data failed", e))?;
+                upload_info.state = UploadState::Expired;
+                expired.push(upload_info);
+            }
+        }
+        let terminal = state_list(TERMINAL_STATES);
+        let delete_buffers = format!(
+            r#"
+                DELETE FROM upload_buffers WHERE upload_id IN (
+                    SELECT upload_id FROM temp_file_uploader
+                    WHERE state IN ({}) AND updated_at < $1
+                );
+            "#,
+            terminal
+        );
+        let delete_uploads = format!(
+            "DELETE FROM temp_file_uploader WHERE state IN ({}) AND updated_at < $1;",
+            terminal
+        );
+        self.pool
+            .transaction(&[
+                (delete_buffers.as_str(), vec![cutoff.into()]),
+                (delete_uploads.as_str(), vec![cutoff.into()]),
+            ])
+            .await
+            .map_err(|e| session_error("purge uploads failed", e))?;
+        Ok(expired)
+    }
+
+    fn is_shared(&self) -> bool {
+        self.pool.dialect() == Dialect::Postgres
+    }
+}
+
+/// Sessions in Redis, shared by all replicas:
+/// - `upload:{upload_id}` holds the `UploadInfo` as JSON
+/// - `upload:{upload_id}:pending` holds the carried over bytes and their offset
+/// - `upload_blob:{blob_name}` reserves the blob name for the upload
+/// - `uploads_active` ranks the active uploads by the time of their last write
+///
+/// `sweep` expires active uploads idle for longer than `ttl_secs` through `uploads_active`,
+/// so their staged data is deleted and their quota released. Their keys live twice as long,
+/// leaving the sweep time to find them, and those of ended uploads expire `ttl_secs` after
+/// the last write.
+#[derive(Clone)]
+pub struct RedisSessionStore {
+    pool: r2d2::Pool<redis::Client>,
+    ttl_secs: u64,
+}
+
+fn upload_key(upload_id: &str) -> String {
+    format!("upload:{}", upload_id)
+}
+
+fn pending_key(upload_id: &str) -> String {
+    format!("upload:{}:pending", upload_id)
+}
+
+const ACTIVE_UPLOADS_KEY: &str = "uploads_active";
+
+fn blob_key(blob_name: &str) -> String {
+    format!("upload_blob:{}", blob_name)
+}
+
+impl RedisSessionStore {
+    pub fn open(url: &str, ttl_secs: u64) -> Result<RedisSessionStore, String> {
+        let client = redis::Client::open(url).map_err(|e| format!("{:?}", e))?;
+        let pool = r2d2::Pool::new(client).map_err(|e| format!("{:?}", e))?;
+        Ok(RedisSessionStore { pool, ttl_secs })
+    }
+
+    fn conn(&self) -> WebAPIResult<r2d2::PooledConnection<redis::Client>> {
+        self.pool
+            .get()
+            .map_err(|e| session_error("redis connection failed", e))
+    }
+
+    /// Runs `f` on the blocking thread pool. The redis client and its pool block while
+    /// waiting for the server, which must not stall the async workers.
+    async fn blocking<T, F>(&self, f: F) -> WebAPIResult<T>
+    where
+        F: FnOnce(&RedisSessionStore) -> WebAPIResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let store = self.clone();
+        match web::block(move || f(&store)).await {
+            Ok(res) => res,
+            Err(e) => Err(session_error("redis call failed", e)),
+        }
+    }
+
+    /// Queues the writes storing `upload_info`. A finished upload keeps its session until
+    /// the TTL runs out, but gives up its blob name and carried over bytes right away.
+    fn queue_put(&self, pipe: &mut redis::Pipeline, upload_info: &UploadInfo, json: String) {
+        let upload_id = &upload_info.upload_id;
+        if upload_info.state.is_active() {
+            pipe.set_ex(upload_key(upload_id), json, self.active_ttl_secs())
+                .ignore()
+                .expire(
+                    blob_key(&upload_info.blob_name),
+                    self.active_ttl_secs() as i64,
+                )
+                .ignore()
+                .zadd(ACTIVE_UPLOADS_KEY, upload_id, unix_now())
+                .ignore();
+        } else {
+            pipe.set_ex(upload_key(upload_id), json, self.ttl_secs)
+                .ignore()
+                .del(blob_key(&upload_info.blob_name))
+                .ignore()
+                .del(pending_key(upload_id))
+                .ignore()
+                .zrem(ACTIVE_UPLOADS_KEY, upload_id)
+                .ignore();
+        }
+    }
+
+    /// Keys of active uploads outlive the idle time after which `sweep` expires them.
+    fn active_ttl_secs(&self) -> u64 {
+        self.ttl_secs.saturating_mul(2)
+    }
+
+    fn put(&self, upload_info: &UploadInfo) -> WebAPIResult<()> {
+        let json = serde_json::to_string(upload_info)
+            .map_err(|e| session_error("serialize session failed", e))?;
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        self.queue_put(&mut pipe, upload_info, json);
+        pipe.query::<()>(&mut *self.conn()?)
+            .map_err(|e| session_error("save session failed", e))
+    }
+
+    /// Read-modify-write of a session under WATCH, retried when another replica changed
+    /// it in between. `change` may queue more writes to commit together with the session.
+    fn update<F>(&self, upload_id: &str, mut change: F) -> WebAPIResult<()>
+    where
+        F: FnMut(&mut UploadInfo, &mut redis::Pipeline) -> WebAPIResult<()>,
+    {
+        let key = upload_key(upload_id);
+        let mut rejected = None;
+        let res = redis::transaction(&mut *self.conn()?, &[&key], |con, pipe| {
+            let json: Option<String> = con.get(&key)?;
+            let mut upload_info: UploadInfo = match json {
+                Some(json) => serde_json::from_str(&json).map_err(|_| {
+                    redis::RedisError::from((redis::ErrorKind::TypeError, "invalid session"))
+                })?,
+                None => {
+                    rejected = Some(upload_not_found(upload_id));
+                    return Ok(Some(()));
+                }
+            };
+            if let Err(e) = change(&mut upload_info, pipe) {
+                rejected = Some(e);
+                return Ok(Some(()));
+            }
+            let json = serde_json::to_string(&upload_info).map_err(|_| {
+                redis::RedisError::from((redis::ErrorKind::TypeError, "invalid session"))
+            })?;
+            self.queue_put(pipe, &upload_info, json);
+            pipe.query::<Option<()>>(con)
+        });
+        match (res, rejected) {
+            (Ok(()), None) => Ok(()),
+            (Ok(()), Some(e)) => Err(e),
+            (Err(e), _) => Err(session_error("save session failed", e)),
+        }
+    }
+
+    /// Expires an upload when its last write is older than `cutoff`, returning it. The
+    /// score is read under WATCH of the session, so a chunk arriving meanwhile wins.
+    fn expire_idle(&self, upload_id: &str, cutoff: i64) -> WebAPIResult<Option<UploadInfo>> {
+        let key = upload_key(upload_id);
+        let res = redis::transaction(&mut *self.conn()?, &[&key], |con, pipe| {
+            let last_write: Option<i64> = con.zscore(ACTIVE_UPLOADS_KEY, upload_id)?;
+            let json: Option<String> = con.get(&key)?;
+            let json = match (last_write, json) {
+                (Some(last_write), Some(json)) if last_write < cutoff => json,
+                (_, None) => {
+                    // the session expired before a sweep got to it
+                    error!("session of idle upload {} is gone", upload_id);
+                    pipe.zrem(ACTIVE_UPLOADS_KEY, upload_id).ignore();
+                    return pipe.query::<Option<()>>(con).map(|res| res.map(|_| None));
+                }
+                _ => return Ok(Some(None)),
+            };
+            let mut upload_info: UploadInfo = serde_json::from_str(&json).map_err(|_| {
+                redis::RedisError::from((redis::ErrorKind::TypeError, "invalid session"))
+            })?;
+            if !UploadState::Expired
+                .allowed_from()
+                .contains(&upload_info.state)
+            {
+                return Ok(Some(None));
+            }
+            upload_info.state = UploadState::Expired;
+            let json = serde_json::to_string(&upload_info).map_err(|_| {
+                redis::RedisError::from((redis::ErrorKind::TypeError, "invalid session"))
+            })?;
+            self.queue_put(pipe, &upload_info, json);
+            pipe.query::<Option<()>>(con)
+                .map(|res| res.map(|_| Some(upload_info.clone())))
+        });
+        res.map_err(|e| session_error("expire upload failed", e))
+    }
+}
+
+#[async_trait(?Send)]
+impl SessionStore for RedisSessionStore {
+    async fn create(&self, upload_info: &UploadInfo) -> WebAPIResult<()> {
+        let upload_info = upload_info.clone();
+        self.blocking(move |store| {
+            let mut conn = store.conn()?;
+            // SET NX makes the reservation atomic across replicas
+            let reserved: bool = redis::cmd("SET")
+                .arg(blob_key(&upload_info.blob_name))
+                .arg(&upload_info.upload_id)
+                .arg("NX")
+                .arg("EX")
+                .arg(store.active_ttl_secs())
+                .query::<Option<String>>(&mut *conn)
+                .map(|res| res.is_some())
+                .map_err(|e| session_error("reserve blob name failed", e))?;
+            if !reserved {
+                return Err(blob_name_conflict(&upload_info.blob_name));
+            }
+            store.put(&upload_info)
+        })
+        .await
+    }
+
+    async fn get(&self, upload_id: &str) -> WebAPIResult<Option<UploadInfo>> {
+        let key = upload_key(upload_id);
+        let json: Option<String> = self
+            .blocking(move |store| {
+                store
+                    .conn()?
+                    .get(key)
+                    .map_err(|e| session_error("query failed", e))
+            })
+            .await?;
+        match json {
+            Some(json) => serde_json::from_str(&json)
+                .map(Some)
+                .map_err(|e| session_error("deserialize session failed", e)),
+            None => Ok(None),
+        }
+    }
+
+    async fn is_blob_name_reserved(&self, blob_name: &str) -> WebAPIResult<bool> {
+        let key = blob_key(blob_name);
+        self.blocking(move |store| {
+            store
+                .conn()?
+                .exists(key)
+                .map_err(|e| session_error("query failed", e))
+        })
+        .await
+    }
+
+    async fn load_pending(&self, upload_id: &str) -> WebAPIResult<Option<PendingData>> {
+        let key = pending_key(upload_id);
+        let (offset, data): (Option<u64>, Option<Vec<u8>>) = self
+            .blocking(move |store| {
+                redis::cmd("HMGET")
+                    .arg(key)
+                    .arg("offset")
+                    .arg("data")
+                    .query(&mut *store.conn()?)
+                    .map_err(|e| session_error("load pending data failed", e))
+            })
+            .await?;
+        Ok(offset.map(|offset| PendingData {
+            offset,
+            data: data.unwrap_or_default(),
+        }))
+    }
+
+    async fn save_staged_size(&self, upload_id: &str, staged_size: u64) -> WebAPIResult<()> {
+        let upload_id = upload_id.to_string();
+        self.blocking(move |store| {
+            store.update(&upload_id, |upload_info, _| {
+                upload_info.staged_size = staged_size;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn save_progress(
+        &self,
+        upload_id: &str,
+        staged_size: u64,
+        pending: &[u8],
+    ) -> WebAPIResult<()> {
+        let upload_id = upload_id.to_string();
+        let pending = pending.to_vec();
+        self.blocking(move |store| {
+            let key = pending_key(&upload_id);
+            store.update(&upload_id, |upload_info, pipe| {
+                upload_info.staged_size = staged_size;
+                pipe.hset(&key, "offset", staged_size)
+                    .ignore()
+                    .hset(&key, "data", &pending)
+                    .ignore()
+                    .expire(&key, store.active_ttl_secs() as i64)
+                    .ignore();
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn transition(&self, upload_id: &str, state: UploadState) -> WebAPIResult<()> {
+        let upload_id = upload_id.to_string();
+        self.blocking(move |store| {
+            store.update(&upload_id, |upload_info, _| {
+                if !state.allowed_from().contains(&upload_info.state) {
+                    return Err(state_conflict(&upload_id, upload_info.state, state));
+                }
+                upload_info.state = state;
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    /// Ended uploads are forgotten by TTL, only idle active ones are left to expire.
+    async fn sweep(&self, idle_secs: u64) -> WebAPIResult<Vec<UploadInfo>> {
+        self.blocking(move |store| {
+            let cutoff = unix_now() - idle_secs as i64;
+            let idle: Vec<String> = store
+                .conn()?
+                .zrangebyscore(ACTIVE_UPLOADS_KEY, "-inf", format!("({}", cutoff))
+                .map_err(|e| session_error("query idle uploads failed", e))?;
+            let mut expired = Vec::new();
+            for upload_id in idle {
+                expired.extend(store.expire_idle(&upload_id, cutoff)?);
+            }
+            Ok(expired)
+        })
+        .await
+    }
+
+    fn is_shared(&self) -> bool {
+        true
+    }
+}
+
+/// Picks the session store from `SESSION_STORE` (`database` by default, or `redis`
+/// with `REDIS_URL` and a Postgres `DATABASE_URL`).
+pub fn open_session_store(
+    kind: &str,
+    pool: &DbPool,
+    redis_url: Option<&str>,
+    ttl_secs: u64,
+) -> Result<Box<dyn SessionStore>, String> {
+    match kind {
+        // `sqlite` was the name before the metadata database could be Postgres
+        "database" | "sqlite" => Ok(Box::new(DbSessionStore::new(pool.clone()))),
+        // sessions shared by replicas whose uploads are recorded in local databases
+        "redis" if pool.dialect() != Dialect::Postgres => {
+            Err("SESSION_STORE=redis needs a Postgres DATABASE_URL".to_string())
+        }
+        "redis" => match redis_url {
+            Some(url) => Ok(Box::new(RedisSessionStore::open(url, ttl_secs)?)),
+            None => Err("SESSION_STORE=redis needs REDIS_URL".to_string()),
+        },
+        _ => Err(format!("unknown session store: {}", kind)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn upload_info(upload_id: &str, blob_name: &str) -> UploadInfo {
+        serde_json::from_value(serde_json::json!({
+            "upload_id": upload_id,
+            "file_name": blob_name,
+            "file_size": 10,
+            "file_hash": "",
+            "content_type": "application/octet-stream",
+            "blob_access_token": "",
+            "blob_file_hash": "",
+            "blob_name": blob_name,
+            "conflict_mode": "overwrite",
+            "block_size": 4,
+            "chunk_size": 4,
+            "staged_size": 0,
+            "state": "created",
+            "owner": "alice",
+            "tags": {"project": "x"},
+        }))
+        .unwrap()
+    }
+
+    async fn db_store() -> DbSessionStore {
+        let pool = DbPool::open(None).unwrap();
+        pool.migrate().await.unwrap();
+        DbSessionStore::new(pool)
+    }
+
+    /// Moves the last write of an upload `secs` into the past.
+    async fn age(store: &DbSessionStore, upload_id: &str, secs: i64) {
+        store
+            .pool
+            .execute(
+                "UPDATE temp_file_uploader SET updated_at = $2 WHERE upload_id = $1;",
+                &[upload_id.into(), (unix_now() - secs).into()],
+            )
+            .await
+            .unwrap();
+    }
+
+    async fn check_round_trip(store: &dyn SessionStore) {
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        let blob_name = format!("{}.bin", upload_id);
+        store
+            .create(&upload_info(&upload_id, &blob_name))
+            .await
+            .unwrap();
+        let stored = store.get(&upload_id).await.unwrap().unwrap();
+        assert_eq!(stored.blob_name, blob_name);
+        assert_eq!(stored.conflict_mode, ConflictMode::Overwrite);
+        assert_eq!(stored.state, UploadState::Created);
+        assert_eq!(stored.tags.get("project").map(String::as_str), Some("x"));
+        assert!(store.is_blob_name_reserved(&blob_name).await.unwrap());
+        assert!(store.get("missing").await.unwrap().is_none());
+
+        // a second upload to the same blob name is refused
+        let e = store
+            .create(&upload_info("other", &blob_name))
+            .await
+            .unwrap_err();
+        assert_eq!(e.status, StatusCode::CONFLICT);
+
+        assert!(store.load_pending(&upload_id).await.unwrap().is_none());
+        store.save_progress(&upload_id, 8, b"ab").await.unwrap();
+        let pending = store.load_pending(&upload_id).await.unwrap().unwrap();
+        assert_eq!((pending.offset, pending.data), (8, b"ab".to_vec()));
+        assert_eq!(store.get(&upload_id).await.unwrap().unwrap().staged_size, 8);
+
+        store
+            .transition(&upload_id, UploadState::Aborted)
+            .await
+            .unwrap();
+        // ending the upload drops the carried over bytes and frees the blob name
+        assert!(store.load_pending(&upload_id).await.unwrap().is_none());
+        assert!(!store.is_blob_name_reserved(&blob_name).await.unwrap());
+    }
+
+    async fn check_transition_conflicts(store: &dyn SessionStore) {
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        store
+            .create(&upload_info(&upload_id, &format!("{}.bin", upload_id)))
+            .await
+            .unwrap();
+        store
+            .transition(&upload_id, UploadState::Uploading)
+            .await
+            .unwrap();
+        store
+            .transition(&upload_id, UploadState::Finalizing)
+            .await
+            .unwrap();
+        // chunks or an abort racing the finish lose
+        for state in [UploadState::Uploading, UploadState::Aborted] {
+            let e = store.transition(&upload_id, state).await.unwrap_err();
+            assert_eq!(e.status, StatusCode::CONFLICT);
+            assert_eq!(e.error, "upload is finalizing");
+        }
+        store
+            .transition(&upload_id, UploadState::Completed)
+            .await
+            .unwrap();
+        let e = store
+            .transition(&upload_id, UploadState::Uploading)
+            .await
+            .unwrap_err();
+        assert_eq!(e.error, "upload is completed");
+        let e = store
+            .transition("missing", UploadState::Aborted)
+            .await
+            .unwrap_err();
+        assert_eq!(e.status, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn db_sessions_round_trip() {
+        check_round_trip(&db_store().await).await;
+    }
+
+    #[actix_web::test]
+    async fn db_transitions_checked_against_current_state() {
+        check_transition_conflicts(&db_store().await).await;
+    }
+
+    #[actix_web::test]
+    async fn db_idle_sessions_expire() {
+        let store = db_store().await;
+        store.create(&upload_info("idle", "a.bin")).await.unwrap();
+        store.create(&upload_info("busy", "b.bin")).await.unwrap();
+        store.save_progress("idle", 4, b"ab").await.unwrap();
+        age(&store, "idle", 120).await;
+
+        let expired = store.sweep(60).await.unwrap();
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].upload_id, "idle");
+        assert_eq!(expired[0].state, UploadState::Expired);
+        assert!(store.load_pending("idle").await.unwrap().is_none());
+        assert!(!store.is_blob_name_reserved("a.bin").await.unwrap());
+        assert_eq!(
+            store.get("busy").await.unwrap().unwrap().state,
+            UploadState::Created
+        );
+
+        // expired once, then forgotten when it has been over for the idle time too
+        assert!(store.sweep(60).await.unwrap().is_empty());
+        assert!(store.get("idle").await.unwrap().is_some());
+        age(&store, "idle", 120).await;
+        assert!(store.sweep(60).await.unwrap().is_empty());
+        assert!(store.get("idle").await.unwrap().is_none());
+    }
+
+    #[actix_web::test]
+    async fn redis_needs_postgres() {
+        let pool = DbPool::open(None).unwrap();
+        let res = open_session_store("redis", &pool, Some("redis://127.0.0.1/"), 60);
+        assert!(res.is_err());
+        assert!(!open_session_store("database", &pool, None, 60)
+            .unwrap()
+            .is_shared());
+    }
+
+    /// Needs a Redis server at `REDIS_URL`, `redis://127.0.0.1/` by default.
+    #[actix_web::test]
+    #[ignore]
+    async fn redis_sessions() {
+        let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+        let store = RedisSessionStore::open(&url, 60).unwrap();
+        check_round_trip(&store).await;
+        check_transition_conflicts(&store).await;
+
+        let upload_id = uuid::Uuid::new_v4().to_string();
+        store
+            .create(&upload_info(&upload_id, &format!("{}.bin", upload_id)))
+            .await
+            .unwrap();
+        actix_web::rt::time::sleep(std::time::Duration::from_millis(1_100)).await;
+        let expired = store.sweep(0).await.unwrap();
+        assert!(expired
+            .iter()
+            .any(|upload_info| upload_info.upload_id == upload_id));
+        assert_eq!(
+            store.get(&upload_id).await.unwrap().unwrap().state,
+            UploadState::Expired
+        );
+    }
+}
@@ -0,0 +1,383 @@
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::db::{unix_now, DbError, DbPool};
+use crate::models::{ErrorResponse, WebAPIResult};
+use crate::tokens::TokenKey;
+
+const SHARE_PASSWORD_HEADER: &str = "X-Share-Password";
+/// purpose share links are signed for, see `TokenKey::sign`
+const SHARE_PURPOSE: &str = "share";
+
+/// What a share link grants, carried in the link itself. Only links with a download
+/// limit have a row in `share_links`, counting their downloads.
+#[derive(Serialize, Deserialize)]
+struct ShareClaims {
+    share_id: String,
+    upload_id: String,
+    /// unix seconds
+    exp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max_downloads: Option<u32>,
+    /// keyed digest of the password, see `password_input`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    password: Option<String>,
+}
+
+/// A share link handed out by `create`.
+pub struct NewShare {
+    pub share_id: String,
+    pub token: String,
+    pub expires_at: i64,
+}
+
+/// Signs and checks share links of completed uploads. Links are verified by their
+/// signature alone and cannot be revoked before they expire, so their lifetime is capped.
+pub struct ShareLinks {
+    key: TokenKey,
+    default_ttl: Duration,
+    max_ttl: Duration,
+}
+
+fn share_error(context: &str, e: DbError) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+/// The password is bound to the share so a digest cannot be moved to another link.
+fn password_input(share_id: &str, password: &str) -> Vec<u8> {
+    format!("share-password:{}:{}", share_id, password).into_bytes()
+}
+
+impl ShareLinks {
+    pub fn new(key: TokenKey, default_ttl: Duration, max_ttl: Duration) -> ShareLinks {
+        ShareLinks {
+            key,
+            default_ttl,
+            max_ttl,
+        }
+    }
+
+    /// Signs a link to `upload_id` valid for `ttl` (the default when `None`), recording
+    /// its download count when `max_downloads` is set.
+    pub async fn create(
+        &self,
+        pool: &DbPool,
+        upload_id: &str,
+        owner: &str,
+        ttl: Option<Duration>,
+        max_downloads: Option<u32>,
+        password: Option<&str>,
+    ) -> WebAPIResult<NewShare> {
+        let ttl = ttl.unwrap_or(self.default_ttl);
+        if ttl.is_zero() || ttl > self.max_ttl {
+            return Err(ErrorResponse::with_status(
+                StatusCode::BAD_REQUEST,
+                &format!(
+                    "expires_in_secs must be between 1 and {}",
+                    self.max_ttl.as_secs()
+                ),
+            ));
+        }
+        if max_downloads == Some(0) {
+            return Err(ErrorResponse::with_status(
+                StatusCode::BAD_REQUEST,
+                "max_downloads must be at least 1",
+            ));
+        }
+        if password == Some("") {
+            return Err(ErrorResponse::with_status(
+                StatusCode::BAD_REQUEST,
+                "password must not be empty",
+            ));
+        }
+        let share_id = uuid::Uuid::new_v4().to_string();
+        let expires_at = unix_now() + ttl.as_secs() as i64;
+        if let Some(max_downloads) = max_downloads {
+            pool.execute(
+                r#"
+                    INSERT INTO share_links(
+                        share_id, upload_id, owner, max_downloads, downloads, expires_at
+                    ) VALUES ($1, $2, $3, $4, 0, $5);
+                "#,
+                &[
+                    (&share_id).into(),
+                    upload_id.into(),
+                    owner.into(),
+                    max_downloads.into(),
+                    expires_at.into(),
+                ],
+            )
+            .await
+            .map_err(|e| share_error("create share link failed", e))?;
+        }
+        let token = self.key.sign(
+            SHARE_PURPOSE,
+            &ShareClaims {
+                share_id: share_id.clone(),
+                upload_id: upload_id.to_string(),
+                exp: expires_at,
+                max_downloads,
+                password: password
+                    .map(|password| self.key.digest(&password_input(&share_id, password))),
+            },
+        );
+        Ok(NewShare {
+            share_id,
+            token,
+            expires_at,
+        })
+    }
+
+    /// Checks a link and its password from the `X-Share-Password` header and counts the
+    /// download against its limit. Returns the shared upload id.
+    pub async fn redeem(
+        &self,
+        pool: &DbPool,
+        req: &HttpRequest,
+        token: &str,
+    ) -> WebAPIResult<String> {
+        let claims = match self.key.open::<ShareClaims>(SHARE_PURPOSE, token) {
+            Some(claims) => claims,
+            None => {
+                error!("share link not signed by us");
+                return Err(ErrorResponse::with_status(
+                    StatusCode::NOT_FOUND,
+                    "invalid share link",
+                ));
+            }
+        };
+        if claims.exp < unix_now() {
+            error!("share link {} expired", claims.share_id);
+            return Err(ErrorResponse::with_status(
+                StatusCode::GONE,
+                "share link expired",
+            ));
+        }
+        if let Some(digest) = &claims.password {
+            let password = req
+                .headers()
+                .get(SHARE_PASSWORD_HEADER)
+                .and_then(|password| password.to_str().ok());
+            let password = match password {
+                Some(password) => password,
+                None => {
+                    return Err(ErrorResponse::with_status(
+                        StatusCode::UNAUTHORIZED,
+                        "share link needs a password",
+                    ));
+                }
+            };
+            if !self
+                .key
+                .verify_digest(&password_input(&claims.share_id, password), digest)
+            {
+                error!("wrong password for share link {}", claims.share_id);
+                return Err(ErrorResponse::with_status(
+                    StatusCode::FORBIDDEN,
+                    "wrong share link password",
+                ));
+            }
+        }
+        if claims.max_downloads.is_some() {
+            let counted = pool
+                .execute(
+                    r#"
+                        UPDATE share_links SET downloads = downloads + 1
+                        WHERE share_id = $1 AND downloads < max_downloads;
+                    "#,
+                    &[(&claims.share_id).into()],
+                )
+                .await
+                .map_err(|e| share_error("count share download failed", e))?;
+            if counted == 0 {
+                error!("share link {} used up", claims.share_id);
+                return Err(ErrorResponse::with_status(
+                    StatusCode::GONE,
+                    "share link download limit reached",
+                ));
+            }
+        }
+        Ok(claims.upload_id)
+    }
+}
+
+/// Drops the download counts of links that expired, they are rejected before their row
+/// is looked at.
+pub async fn sweep_expired(pool: &DbPool) -> WebAPIResult<u64> {
+    pool.execute(
+        "DELETE FROM share_links WHERE expires_at < $1;",
+        &[unix_now().into()],
+    )
+    .await
+    .map_err(|e| share_error("sweep share links failed", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+    use base64::Engine;
+
+    use super::*;
+
+    async fn pool() -> DbPool {
+        let pool = DbPool::open(None).unwrap();
+        pool.migrate().await.unwrap();
+        pool
+    }
+
+    fn links() -> ShareLinks {
+        ShareLinks::new(
+            TokenKey::new(b"secret"),
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        )
+    }
+
+    fn request() -> HttpRequest {
+        TestRequest::default().to_http_request()
+    }
+
+    fn claims(upload_id: &str, exp: i64) -> ShareClaims {
+        ShareClaims {
+            share_id: "s1".to_string(),
+            upload_id: upload_id.to_string(),
+            exp,
+            max_downloads: None,
+            password: None,
+        }
+    }
+
+    async fn status(links: &ShareLinks, pool: &DbPool, token: &str) -> StatusCode {
+        links
+            .redeem(pool, &request(), token)
+            .await
+            .unwrap_err()
+            .status
+    }
+
+    #[actix_web::test]
+    async fn link_redeemed_for_its_upload() {
+        let (links, pool) = (links(), pool().await);
+        let share = links
+            .create(&pool, "u1", "alice", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            links.redeem(&pool, &request(), &share.token).await.unwrap(),
+            "u1"
+        );
+        assert_eq!(
+            links.redeem(&pool, &request(), &share.token).await.unwrap(),
+            "u1"
+        );
+    }
+
+    #[actix_web::test]
+    async fn expired_link_rejected() {
+        let (links, pool) = (links(), pool().await);
+        let token = links.key.sign(SHARE_PURPOSE, &claims("u1", unix_now() - 1));
+        assert_eq!(status(&links, &pool, &token).await, StatusCode::GONE);
+        let e = links
+            .create(
+                &pool,
+                "u1",
+                "alice",
+                Some(Duration::from_secs(3601)),
+                None,
+                None,
+            )
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(e.status, StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn tampered_link_rejected() {
+        let (links, pool) = (links(), pool().await);
+        let share = links
+            .create(&pool, "u1", "alice", None, None, None)
+            .await
+            .unwrap();
+        // the same signature over a link to another upload
+        let (_, signature) = share.token.split_once('.').unwrap();
+        let payload = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&ShareClaims {
+                share_id: share.share_id.clone(),
+                ..claims("u2", share.expires_at)
+            })
+            .unwrap(),
+        );
+        let forged = format!("{}.{}", payload, signature);
+        assert_eq!(status(&links, &pool, &forged).await, StatusCode::NOT_FOUND);
+        let other = ShareLinks::new(
+            TokenKey::new(b"other"),
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+        );
+        let share = other
+            .create(&pool, "u1", "alice", None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            status(&links, &pool, &share.token).await,
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[actix_web::test]
+    async fn token_of_other_purpose_rejected() {
+        let (links, pool) = (links(), pool().await);
+        let token = links.key.sign("upload", &claims("u1", unix_now() + 60));
+        assert_eq!(status(&links, &pool, &token).await, StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn used_up_link_rejected() {
+        let (links, pool) = (links(), pool().await);
+        let share = links
+            .create(&pool, "u1", "alice", None, Some(2), None)
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            links.redeem(&pool, &request(), &share.token).await.unwrap();
+        }
+        let e = links
+            .redeem(&pool, &request(), &share.token)
+            .await
+            .unwrap_err();
+        assert_eq!(e.status, StatusCode::GONE);
+        assert_eq!(e.error, "share link download limit reached");
+    }
+
+    #[actix_web::test]
+    async fn password_checked() {
+        let (links, pool) = (links(), pool().await);
+        let share = links
+            .create(&pool, "u1", "alice", None, None, Some("pw"))
+            .await
+            .unwrap();
+        assert_eq!(
+            status(&links, &pool, &share.token).await,
+            StatusCode::UNAUTHORIZED
+        );
+        let wrong = TestRequest::default()
+            .insert_header((SHARE_PASSWORD_HEADER, "other"))
+            .to_http_request();
+        let e = links.redeem(&pool, &wrong, &share.token).await.unwrap_err();
+        assert_eq!(e.status, StatusCode::FORBIDDEN);
+        let right = TestRequest::default()
+            .insert_header((SHARE_PASSWORD_HEADER, "pw"))
+            .to_http_request();
+        assert_eq!(
+            links.redeem(&pool, &right, &share.token).await.unwrap(),
+            "u1"
+        );
+    }
+}
@@ -0,0 +1,419 @@
+use std::collections::HashMap;
+use std::fs;
+use std::future::Future;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use actix_web::web;
+use azure_storage::StorageCredentials;
+use tracing::{debug, error, info};
+
+use crate::models::{Config, ErrorResponse, WebAPIResult};
+use crate::storage;
+
+const BLOCK_SUFFIX: &str = ".block";
+const TMP_SUFFIX: &str = ".tmp";
+/// How long a client is asked to wait when the spool is full.
+const SPOOL_FULL_RETRY_AFTER: Duration = Duration::from_secs(5);
+
+/// Local write-ahead spool for blocks on their way to the staging blob.
+///
+/// Each block is stored as `{spool_dir}/{upload_id}/{offset}.block`, where `offset` is its
+/// position in the staging blob. That is all a flush needs, so blocks left behind by a
+/// crash or restart are flushed by the background worker like any other.
+pub struct Spool {
+    dir: Option<PathBuf>,
+    max_bytes: u64,
+    used_bytes: AtomicU64,
+    credentials: StorageCredentials,
+    /// one flush at a time per upload, shared by the worker and `finish_upload`
+    locks: Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>,
+}
+
+impl std::fmt::Debug for Spool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Spool")
+            .field("dir", &self.dir)
+            .field("max_bytes", &self.max_bytes)
+            .field("used_bytes", &self.used_bytes)
+            .finish()
+    }
+}
+
+fn spool_error(context: &str, e: std::io::Error) -> ErrorResponse {
+    error!("{}: {:?}", context, e);
+    ErrorResponse::new(context)
+}
+
+/// Runs file system work on the blocking thread pool, so that waiting for the disk does
+/// not stall the async workers.
+async fn blocking<T, F>(context: &str, f: F) -> WebAPIResult<T>
+where
+    F: FnOnce() -> std::io::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    match web::block(f).await {
+        Ok(res) => res.map_err(|e| spool_error(context, e)),
+        Err(e) => {
+            error!("{}: {:?}", context, e);
+            Err(ErrorResponse::new(context))
+        }
+    }
+}
+
+fn block_file_name(offset: u64) -> String {
+    format!("{:020}{}", offset, BLOCK_SUFFIX)
+}
+
+fn dir_size(dir: &Path) -> std::io::Result<u64> {
+    let mut size = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        size += if meta.is_dir() {
+            dir_size(&entry.path())?
+        } else {
+            meta.len()
+        };
+    }
+    Ok(size)
+}
+
+impl Spool {
+    pub fn disabled() -> Spool {
+        Spool {
+            dir: None,
+            max_bytes: 0,
+            used_bytes: AtomicU64::new(0),
+            credentials: StorageCredentials::anonymous(),
+            locks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Opens the spool directory, counting what a previous run left in it. Blocks are
+    /// flushed to storage with `credentials`.
+    pub fn open(
+        dir: PathBuf,
+        max_bytes: u64,
+        credentials: StorageCredentials,
+    ) -> std::io::Result<Spool> {
+        fs::create_dir_all(&dir)?;
+        let used_bytes = dir_size(&dir)?;
+        info!("spool {:?} opened with {} bytes pending", dir, used_bytes);
+        Ok(Spool {
+            dir: Some(dir),
+            max_bytes,
+            used_bytes: AtomicU64::new(used_bytes),
+            credentials,
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.dir.is_some()
+    }
+
+    fn upload_dir(&self, upload_id: &str) -> Option<PathBuf> {
+        self.dir.as_ref().map(|dir| dir.join(upload_id))
+    }
+
+    fn lock_for(&self, upload_id: &str) -> Arc<tokio::sync::Mutex<()>> {
+        self.locks
+            .lock()
+            .unwrap()
+            .entry(upload_id.to_string())
+            .or_default()
+            .clone()
+    }
+
+    /// Durably stores a block. Once this returns the block survives a restart and
+    /// the client can be acknowledged.
+    pub async fn write_block(
+        &self,
+        upload_id: &str,
+        offset: u64,
+        block: Vec<u8>,
+    ) -> WebAPIResult<()> {
+        let dir = match self.upload_dir(upload_id) {
+            Some(dir) => dir,
+            None => return Err(ErrorResponse::new("spool disabled")),
+        };
+        let len = block.len() as u64;
+        let used = self.used_bytes.fetch_add(len, Ordering::SeqCst);
+        if used + len > self.max_bytes {
+            self.used_bytes.fetch_sub(len, Ordering::SeqCst);
+            error!("spool full: {} + {} > {}", used, len, self.max_bytes);
+            return Err(ErrorResponse::unavailable(
+                "spool full",
+                SPOOL_FULL_RETRY_AFTER,
+            ));
+        }
+
+        let path = dir.join(block_file_name(offset));
+        let tmp_path = dir.join(format!("{}{}", block_file_name(offset), TMP_SUFFIX));
+        let res = blocking("spool write failed", move || {
+            let res = fs::create_dir_all(&dir)
+                .and_then(|_| fs::File::create(&tmp_path))
+                .and_then(|mut file| {
+                    file.write_all(&block)?;
+                    file.sync_all()
+                })
+                .and_then(|_| fs::rename(&tmp_path, &path));
+            if res.is_err() {
+                let _ = fs::remove_file(&tmp_path);
+            }
+            res
+        })
+        .await;
+        if res.is_err() {
+            self.used_bytes.fetch_sub(len, Ordering::SeqCst);
+            return res;
+        }
+        debug!("spooled {} bytes at {} for {}", len, offset, upload_id);
+        Ok(())
+    }
+
+    fn block_offsets(dir: &Path) -> std::io::Result<Vec<u64>> {
+        let mut offsets = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name();
+            let name = name.to_string_lossy();
+            if let Some(offset) = name.strip_suffix(BLOCK_SUFFIX) {
+                if let Ok(offset) = offset.parse() {
+                    offsets.push(offset);
+                }
+            }
+        }
+        offsets.sort_unstable();
+        Ok(offsets)
+    }
+
+    /// Appends every spooled block of an upload to its staging blob in offset order.
+    pub async fn flush_upload(&self, config: &Config, upload_id: &str) -> WebAPIResult<()> {
+        let blob_client = storage::blob_client(
+            config,
+            &self.credentials,
+            &storage::staging_blob_name(upload_id),
+        );
+        let blob_client = &blob_client;
+        self.replay(upload_id, move |offset, block| {
+            storage::append_block_at(config, blob_client, offset, block)
+        })
+        .await
+    }
+
+    /// Hands the spooled blocks of an upload to `append` in offset order, each is removed
+    /// from the spool once `append` succeeded. Stops at the first failure.
+    async fn replay<F, Fut>(&self, upload_id: &str, mut append: F) -> WebAPIResult<()>
+    where
+        F: FnMut(u64, Vec<u8>) -> Fut,
+        Fut: Future<Output = WebAPIResult<()>>,
+    {
+        let dir = match self.upload_dir(upload_id) {
+            Some(dir) if dir.exists() => dir,
+            _ => return Ok(()),
+        };
+        let lock = self.lock_for(upload_id);
+        let _guard = lock.lock().await;
+
+        let offsets = {
+            let dir = dir.clone();
+            blocking("spool read failed", move || Self::block_offsets(&dir)).await?
+        };
+        for offset in offsets {
+            let path = dir.join(block_file_name(offset));
+            let block = {
+                let path = path.clone();
+                blocking("spool read failed", move || fs::read(path)).await?
+            };
+            let len = block.len() as u64;
+            append(offset, block).await?;
+            blocking("spool remove failed", move || fs::remove_file(path)).await?;
+            self.used_bytes.fetch_sub(len, Ordering::SeqCst);
+        }
+        // fails while new blocks are arriving, the next pass picks them up
+        if blocking("spool remove failed", move || fs::remove_dir(dir))
+            .await
+            .is_ok()
+        {
+            self.locks.lock().unwrap().remove(upload_id);
+        }
+        Ok(())
+    }
+
+    /// Drops the spooled blocks of an upload that will never be finished.
+    pub async fn discard_upload(&self, upload_id: &str) -> WebAPIResult<()> {
+        let dir = match self.upload_dir(upload_id) {
+            Some(dir) if dir.exists() => dir,
+            _ => return Ok(()),
+        };
+        let lock = self.lock_for(upload_id);
+        let _guard = lock.lock().await;
+
+        let size = blocking("spool remove failed", move || {
+            let size = dir_size(&dir)?;
+            fs::remove_dir_all(&dir)?;
+            Ok(size)
+        })
+        .await?;
+        self.used_bytes.fetch_sub(size, Ordering::SeqCst);
+        self.locks.lock().unwrap().remove(upload_id);
+        debug!("discarded {} spooled bytes of {}", size, upload_id);
+        Ok(())
+    }
+
+    /// Flushes all uploads with spooled blocks, including those left by a previous run.
+    pub async fn flush_all(&self, config: &Config) {
+        let dir = match &self.dir {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        let res = blocking("spool read failed", move || {
+            Ok(fs::read_dir(dir)?
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .map(|entry| entry.file_name().to_string_lossy().to_string())
+                .collect::<Vec<String>>())
+        })
+        .await;
+        let upload_ids = match res {
+            Ok(upload_ids) => upload_ids,
+            Err(_) => return,
+        };
+        for upload_id in upload_ids {
+            if let Err(e) = self.flush_upload(config, &upload_id).await {
+                error!("spool flush of {} failed: {}", upload_id, e);
+            }
+        }
+    }
+
+    /// Background worker moving spooled blocks to storage.
+    pub async fn run_worker(spool: Arc<Spool>, config: Config, interval: Duration) {
+        loop {
+            spool.flush_all(&config).await;
+            actix_web::rt::time::sleep(interval).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+
+    use actix_web::http::StatusCode;
+
+    use super::*;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new() -> TempDir {
+            TempDir(std::env::temp_dir().join(format!("spool-test-{}", uuid::Uuid::new_v4())))
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn open(dir: &TempDir, max_bytes: u64) -> Spool {
+        Spool::open(dir.0.clone(), max_bytes, StorageCredentials::anonymous()).unwrap()
+    }
+
+    /// Replays the blocks of `upload_id`, failing `append` at offset `fail_at`.
+    async fn replay(
+        spool: &Spool,
+        upload_id: &str,
+        fail_at: Option<u64>,
+    ) -> (Vec<(u64, Vec<u8>)>, WebAPIResult<()>) {
+        let appended = RefCell::new(Vec::new());
+        let res = spool
+            .replay(upload_id, |offset, block| {
+                let res = if Some(offset) == fail_at {
+                    Err(ErrorResponse::new("append failed"))
+                } else {
+                    appended.borrow_mut().push((offset, block));
+                    Ok(())
+                };
+                async move { res }
+            })
+            .await;
+        (appended.into_inner(), res)
+    }
+
+    #[actix_web::test]
+    async fn blocks_replayed_in_order_after_restart() {
+        let dir = TempDir::new();
+        let spool = open(&dir, 1024);
+        spool.write_block("u1", 4, b"efgh".to_vec()).await.unwrap();
+        spool.write_block("u1", 0, b"abcd".to_vec()).await.unwrap();
+        spool.write_block("u2", 0, b"xy".to_vec()).await.unwrap();
+        drop(spool);
+
+        let spool = open(&dir, 1024);
+        assert_eq!(spool.used_bytes.load(Ordering::SeqCst), 10);
+        let (appended, res) = replay(&spool, "u1", None).await;
+        assert!(res.is_ok());
+        assert_eq!(appended, vec![(0, b"abcd".to_vec()), (4, b"efgh".to_vec())]);
+        assert!(!dir.0.join("u1").exists());
+        assert_eq!(spool.used_bytes.load(Ordering::SeqCst), 2);
+
+        // nothing left to replay
+        let (appended, res) = replay(&spool, "u1", None).await;
+        assert!(res.is_ok());
+        assert!(appended.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn failed_append_keeps_remaining_blocks() {
+        let dir = TempDir::new();
+        let spool = open(&dir, 1024);
+        for offset in [0, 4, 8] {
+            spool
+                .write_block("u1", offset, vec![offset as u8; 4])
+                .await
+                .unwrap();
+        }
+        let (appended, res) = replay(&spool, "u1", Some(4)).await;
+        assert!(res.is_err());
+        assert_eq!(appended, vec![(0, vec![0; 4])]);
+        drop(spool);
+
+        let spool = open(&dir, 1024);
+        assert_eq!(spool.used_bytes.load(Ordering::SeqCst), 8);
+        let (appended, res) = replay(&spool, "u1", None).await;
+        assert!(res.is_ok());
+        assert_eq!(appended, vec![(4, vec![4; 4]), (8, vec![8; 4])]);
+    }
+
+    #[actix_web::test]
+    async fn full_spool_rejects_blocks() {
+        let dir = TempDir::new();
+        let spool = open(&dir, 6);
+        spool.write_block("u1", 0, vec![0; 4]).await.unwrap();
+        let e = spool.write_block("u1", 4, vec![0; 4]).await.unwrap_err();
+        assert_eq!(e.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(e.retry_after.is_some());
+        assert_eq!(spool.used_bytes.load(Ordering::SeqCst), 4);
+    }
+
+    #[actix_web::test]
+    async fn discarded_blocks_not_replayed() {
+        let dir = TempDir::new();
+        let spool = open(&dir, 1024);
+        spool.write_block("u1", 0, vec![0; 4]).await.unwrap();
+        spool.discard_upload("u1").await.unwrap();
+        assert_eq!(spool.used_bytes.load(Ordering::SeqCst), 0);
+        drop(spool);
+
+        let spool = open(&dir, 1024);
+        let (appended, res) = replay(&spool, "u1", None).await;
+        assert!(res.is_ok());
+        assert!(appended.is_empty());
+    }
+}
@@ -0,0 +1,623 @@
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use azure_core::request_options::Metadata;
+use azure_core::Url;
+use azure_storage::shared_access_signature::service_sas::BlobSasPermissions;
+use azure_storage::StorageCredentials;
+use azure_storage_blobs::blob::CopyStatus;
+use azure_storage_blobs::blob::{BlobBlockType, BlockList, BlockListType};
+use azure_storage_blobs::prelude::{
+    AccessTier as BlobAccessTier, BlobClient, BlockId, ClientBuilder, Hash, Tags,
+};
+use bytes::Bytes;
+use futures::{Stream, StreamExt};
+use time::OffsetDateTime;
+use tracing::{debug, error};
+
+use crate::checksum::{md5_digest, HashAlgorithm, Hasher};
+use crate::chunking;
+use crate::models::{AccessTier, ChunkUrl, Config, ErrorResponse, WebAPIResult};
+use crate::retry::{http_status, with_retry, StorageError};
+
+/// In-progress data is written under this prefix and only copied to the real
+/// blob name by `finish_upload`, so consumers never see a half-written file.
+pub const STAGING_PREFIX: &str = ".uploads/";
+
+const COPY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+const COPY_POLL_ATTEMPTS: u32 = 240;
+const HASH_READ_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+const DOWNLOAD_READ_CHUNK_SIZE: u64 = 4 * 1024 * 1024;
+/// Blocks copied server side by `rebuild_as_block_blob`, Azure takes up to 4000 MiB.
+const REBUILD_BLOCK_SIZE: u64 = 256 * 1024 * 1024;
+/// Lifetime of the URL storage reads the append blob through while rebuilding it.
+const REBUILD_URL_TTL: Duration = Duration::from_secs(60 * 60);
+
+pub fn staging_blob_name(upload_id: &str) -> String {
+    format!("{}{}", STAGING_PREFIX, upload_id)
+}
+
+/// Block blob a proxied upload is copied into when it is published in an access tier,
+/// see `rebuild_as_block_blob`.
+pub fn rebuilt_blob_name(upload_id: &str) -> String {
+    format!("{}{}.blocks", STAGING_PREFIX, upload_id)
+}
+
+pub fn blob_client(
+    config: &Config,
+    credentials: &StorageCredentials,
+    blob_name: &str,
+) -> BlobClient {
+    ClientBuilder::new(&config.account, credentials.clone())
+        .blob_client(&config.container, blob_name)
+}
+
+fn storage_error(context: &str, e: StorageError) -> ErrorResponse {
+    match e {
+        StorageError::Unavailable(retry_after) => {
+            error!(
+                "{}: storage unavailable, retry after {:?}",
+                context, retry_after
+            );
+            ErrorResponse::unavailable("storage unavailable", retry_after)
+        }
+        StorageError::Failed(e) => {
+            error!("{}: {:#?}", context, e);
+            ErrorResponse::new(context)
+        }
+    }
+}
+
+pub async fn blob_exists(
+    config: &Config,
+    credentials: &StorageCredentials,
+    blob_name: &str,
+) -> WebAPIResult<bool> {
+    let blob_client = &blob_client(config, credentials, blob_name);
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "check blob exists",
+        move |_| async move { blob_client.exists().await },
+    )
+    .await
+    .map_err(|e| storage_error("check blob exists failed", e))
+}
+
+pub async fn blob_size(config: &Config, blob_client: &BlobClient) -> WebAPIResult<u64> {
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "get properties",
+        move |_| async move {
+            let props = blob_client.get_properties().await?;
+            Ok(props.blob.properties.content_length)
+        },
+    )
+    .await
+    .map_err(|e| storage_error("get properties failed", e))
+}
+
+pub async fn create_append_blob(
+    config: &Config,
+    blob_client: &BlobClient,
+    content_type: &str,
+) -> WebAPIResult<()> {
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "put append blob",
+        move |_| async move {
+            blob_client
+                .put_append_blob()
+                .content_type(content_type.to_string())
+                .await
+                .map(|_| ())
+        },
+    )
+    .await
+    .map_err(|e| storage_error("put block failed", e))
+}
+
+/// Appends one block at `offset`, with its transactional MD5 so Azure checks the hop
+/// between us and storage. The append position condition makes retries safe: when an
+/// earlier attempt (or a flush before a restart) landed but was not acknowledged, the
+/// append fails with 412 and the bytes at `offset` are read back to tell whether they are
+/// this block. Anything else there, e.g. written by a concurrent request, fails with 409.
+pub async fn append_block_at(
+    config: &Config,
+    blob_client: &BlobClient,
+    offset: u64,
+    block: Vec<u8>,
+) -> WebAPIResult<()> {
+    let md5 = md5_digest(&block);
+    let len = block.len() as u64;
+    let res = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "append block",
+        move |_| {
+            let block = block.clone();
+            async move {
+                let res = blob_client
+                    .append_block(block)
+                    .condition_append_position(offset)
+                    .hash(Hash::MD5(md5))
+                    .await;
+                match res {
+                    Ok(_) => Ok(()),
+                    Err(e) if http_status(&e) == Some(412) => {
+                        let props = blob_client.get_properties().await?;
+                        if props.blob.properties.content_length < offset + len {
+                            return Err(e);
+                        }
+                        let mut stored = Vec::with_capacity(len as usize);
+                        let mut stream =
+                            blob_client.get().range(offset..offset + len).into_stream();
+                        while let Some(res) = stream.next().await {
+                            stored.extend_from_slice(&res?.data.collect().await?);
+                        }
+                        if md5_digest(&stored) == md5 {
+                            debug!("append block at {} landed on an earlier attempt", offset);
+                            Ok(())
+                        } else {
+                            Err(e)
+                        }
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+        },
+    )
+    .await;
+    match res {
+        Err(StorageError::Failed(e)) if http_status(&e) == Some(412) => {
+            error!(
+                "append block at {} out of sync with storage: {:#?}",
+                offset, e
+            );
+            Err(ErrorResponse::with_status(
+                StatusCode::CONFLICT,
+                "staged data is out of sync with storage",
+            ))
+        }
+        res => res.map_err(|e| storage_error("put block failed", e)),
+    }
+}
+
+/// Offset and size of every chunk of a direct upload.
+fn direct_chunks(file_size: u64, chunk_size: u64) -> impl Iterator<Item = (u64, u64, u64)> {
+    (0..file_size.div_ceil(chunk_size)).map(move |index| {
+        let offset = index * chunk_size;
+        (index, offset, chunk_size.min(file_size - offset))
+    })
+}
+
+/// URL of `blob_client` signed with a user delegation SAS granting `permissions` on that
+/// blob only, and when it expires in unix seconds.
+async fn user_delegation_url(
+    config: &Config,
+    credentials: &StorageCredentials,
+    blob_client: &BlobClient,
+    permissions: BlobSasPermissions,
+    ttl: Duration,
+) -> WebAPIResult<(Url, i64)> {
+    let service_client =
+        &ClientBuilder::new(&config.account, credentials.clone()).blob_service_client();
+    let start = OffsetDateTime::now_utc();
+    let expiry = start + ttl;
+    let key = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "get user delegation key",
+        move |_| async move {
+            let res = service_client
+                .get_user_deligation_key(start, expiry)
+                .await?;
+            Ok(res.user_deligation_key)
+        },
+    )
+    .await
+    .map_err(|e| storage_error("get user delegation key failed", e))?;
+
+    let signed_url = async {
+        let sas = blob_client
+            .user_delegation_shared_access_signature(permissions, &key)
+            .await?;
+        blob_client.generate_signed_blob_url(&sas)
+    };
+    match signed_url.await {
+        Ok(url) => Ok((url, expiry.unix_timestamp())),
+        Err(e) => {
+            error!("sign blob url failed: {:#?}", e);
+            Err(ErrorResponse::new("sign blob url failed"))
+        }
+    }
+}
+
+/// One URL per chunk putting it as a block of the staging blob, signed with a user
+/// delegation SAS that only allows writing that blob and expires after `ttl`. Returns the
+/// URLs and when they expire in unix seconds.
+pub async fn direct_chunk_urls(
+    config: &Config,
+    credentials: &StorageCredentials,
+    staging_name: &str,
+    file_size: u64,
+    chunk_size: u64,
+    ttl: Duration,
+) -> WebAPIResult<(Vec<ChunkUrl>, i64)> {
+    let staging_client = blob_client(config, credentials, staging_name);
+    let permissions = BlobSasPermissions {
+        write: true,
+        ..Default::default()
+    };
+    let (signed_url, expires_at) =
+        user_delegation_url(config, credentials, &staging_client, permissions, ttl).await?;
+    let urls = direct_chunks(file_size, chunk_size)
+        .map(|(index, offset, size)| {
+            let mut url = signed_url.clone();
+            url.query_pairs_mut()
+                .append_pair("comp", "block")
+                .append_pair("blockid", &chunking::block_id(index));
+            ChunkUrl {
+                chunk_index: index,
+                offset,
+                size,
+                url: url.to_string(),
+            }
+        })
+        .collect();
+    Ok((urls, expires_at))
+}
+
+/// Commits the blocks a client put directly into the staging blob, in chunk order. Fails
+/// with 422 naming the chunks that are missing or have the wrong size, the upload can be
+/// finished again once they are sent. A list committed by an earlier attempt is kept.
+pub async fn commit_blocks(
+    config: &Config,
+    blob_client: &BlobClient,
+    file_size: u64,
+    chunk_size: u64,
+    content_type: &str,
+) -> WebAPIResult<()> {
+    let res = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "get block list",
+        move |_| async move {
+            let res = blob_client
+                .get_block_list()
+                .block_list_type(BlockListType::All)
+                .await?;
+            Ok(res.block_with_size_list.blocks)
+        },
+    )
+    .await;
+    let blocks = match res {
+        // nothing was put yet
+        Err(StorageError::Failed(e)) if http_status(&e) == Some(404) => Vec::new(),
+        res => res.map_err(|e| storage_error("get block list failed", e))?,
+    };
+    let chunks: Vec<(u64, u64, u64)> = direct_chunks(file_size, chunk_size).collect();
+    let committed = blocks
+        .iter()
+        .filter(|block| matches!(block.block_list_type, BlobBlockType::Committed(_)))
+        .count();
+    if committed == chunks.len() && !chunks.is_empty() {
+        debug!("block list committed on an earlier attempt");
+        return Ok(());
+    }
+    let missing: Vec<String> = chunks
+        .iter()
+        .filter(|(index, _, size)| {
+            let block_id = BlockId::new(chunking::raw_block_id(*index));
+            !blocks.iter().any(|block| match &block.block_list_type {
+                BlobBlockType::Uncommitted(id) => *id == block_id && block.size_in_bytes == *size,
+                _ => false,
+            })
+        })
+        .map(|(index, _, _)| index.to_string())
+        .collect();
+    if !missing.is_empty() {
+        error!("commit blocks: chunks {} missing", missing.join(", "));
+        return Err(ErrorResponse::with_status(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            &format!("chunks missing or incomplete: {}", missing.join(", ")),
+        ));
+    }
+
+    let block_list = BlockList {
+        blocks: chunks
+            .iter()
+            .map(|(index, _, _)| {
+                BlobBlockType::new_uncommitted(BlockId::new(chunking::raw_block_id(*index)))
+            })
+            .collect(),
+    };
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "put block list",
+        |_| {
+            let block_list = block_list.clone();
+            async move {
+                blob_client
+                    .put_block_list(block_list)
+                    .content_type(content_type.to_string())
+                    .await
+                    .map(|_| ())
+            }
+        },
+    )
+    .await
+    .map_err(|e| storage_error("put block list failed", e))
+}
+
+/// Copies the append blob `source_name` of `size` bytes into the block blob `target_name`
+/// with Put Block From URL, so the data never passes through this server. Append blobs
+/// cannot be given an access tier, block blobs can.
+pub async fn rebuild_as_block_blob(
+    config: &Config,
+    credentials: &StorageCredentials,
+    source_name: &str,
+    target_name: &str,
+    size: u64,
+    content_type: &str,
+) -> WebAPIResult<()> {
+    let source_client = blob_client(config, credentials, source_name);
+    let target_client = &blob_client(config, credentials, target_name);
+    // the source is read by storage itself, which needs a URL it may read
+    let permissions = BlobSasPermissions {
+        read: true,
+        ..Default::default()
+    };
+    let (source_url, _) = user_delegation_url(
+        config,
+        credentials,
+        &source_client,
+        permissions,
+        REBUILD_URL_TTL,
+    )
+    .await?;
+    let mut blocks = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let end = (offset + REBUILD_BLOCK_SIZE).min(size);
+        let block_id = BlockId::new(chunking::raw_block_id(blocks.len() as u64));
+        with_retry(
+            &config.retry_policy,
+            &config.circuit_breaker,
+            "put block from url",
+            |_| {
+                let (block_id, source_url) = (block_id.clone(), source_url.clone());
+                async move {
+                    target_client
+                        .put_block_url(block_id, source_url)
+                        .range(offset..end)
+                        .await
+                        .map(|_| ())
+                }
+            },
+        )
+        .await
+        .map_err(|e| storage_error("put block from url failed", e))?;
+        blocks.push(BlobBlockType::new_uncommitted(block_id));
+        offset = end;
+    }
+    let block_list = BlockList { blocks };
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "put block list",
+        |_| {
+            let block_list = block_list.clone();
+            async move {
+                target_client
+                    .put_block_list(block_list)
+                    .content_type(content_type.to_string())
+                    .await
+                    .map(|_| ())
+            }
+        },
+    )
+    .await
+    .map_err(|e| storage_error("put block list failed", e))
+}
+
+/// Deletes a blob, one that is gone already counts as deleted.
+pub async fn delete_blob(config: &Config, blob_client: &BlobClient) -> WebAPIResult<()> {
+    let res = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "delete blob",
+        move |_| async move { blob_client.delete().await.map(|_| ()) },
+    )
+    .await;
+    match res {
+        Err(StorageError::Failed(e)) if http_status(&e) == Some(404) => Ok(()),
+        res => res.map_err(|e| storage_error("delete blob failed", e)),
+    }
+}
+
+async fn read_range(
+    config: &Config,
+    blob_client: &BlobClient,
+    start: u64,
+    end: u64,
+) -> WebAPIResult<Vec<u8>> {
+    with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "read blob",
+        move |_| async move {
+            let mut data = Vec::with_capacity((end - start) as usize);
+            let mut stream = blob_client.get().range(start..end).into_stream();
+            while let Some(res) = stream.next().await {
+                data.extend_from_slice(&res?.data.collect().await?);
+            }
+            Ok(data)
+        },
+    )
+    .await
+    .map_err(|e| storage_error("read blob failed", e))
+}
+
+/// Streams bytes `start..end` of a blob, read in pieces that are each retried on their own.
+pub fn read_stream(
+    config: Config,
+    blob_client: BlobClient,
+    start: u64,
+    end: u64,
+) -> impl Stream<Item = Result<Bytes, ErrorResponse>> {
+    futures::stream::try_unfold(start, move |offset| {
+        let config = config.clone();
+        let blob_client = blob_client.clone();
+        async move {
+            if offset >= end {
+                return Ok(None);
+            }
+            let piece_end = (offset + DOWNLOAD_READ_CHUNK_SIZE).min(end);
+            let data = read_range(&config, &blob_client, offset, piece_end).await?;
+            Ok::<_, ErrorResponse>(Some((Bytes::from(data), piece_end)))
+        }
+    })
+}
+
+/// Reads the whole blob back and returns its hex digest.
+pub async fn blob_hash(
+    config: &Config,
+    blob_client: &BlobClient,
+    blob_size: u64,
+    algorithm: HashAlgorithm,
+) -> WebAPIResult<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut offset = 0;
+    while offset < blob_size {
+        let end = (offset + HASH_READ_CHUNK_SIZE).min(blob_size);
+        hasher.update(&read_range(config, blob_client, offset, end).await?);
+        offset = end;
+    }
+    Ok(hasher.finalize_hex())
+}
+
+fn blob_metadata(metadata: &BTreeMap<String, String>) -> Metadata {
+    let mut blob_metadata = Metadata::new();
+    for (name, value) in metadata {
+        blob_metadata.insert(name.clone(), value.clone());
+    }
+    blob_metadata
+}
+
+fn blob_access_tier(tier: AccessTier) -> BlobAccessTier {
+    match tier {
+        AccessTier::Hot => BlobAccessTier::Hot,
+        AccessTier::Cool => BlobAccessTier::Cool,
+        AccessTier::Archive => BlobAccessTier::Archive,
+    }
+}
+
+/// Copies the staging blob to its final name with `metadata` in `access_tier`, waits for
+/// the copy to complete, sets `tags` and removes the staging blob.
+pub async fn promote(
+    config: &Config,
+    credentials: &StorageCredentials,
+    staging_name: &str,
+    blob_name: &str,
+    metadata: &BTreeMap<String, String>,
+    tags: &BTreeMap<String, String>,
+    access_tier: Option<AccessTier>,
+) -> WebAPIResult<()> {
+    let staging_client = &blob_client(config, credentials, staging_name);
+    let final_client = &blob_client(config, credentials, blob_name);
+
+    let source_url = match staging_client.url() {
+        Ok(url) => url,
+        Err(e) => {
+            error!("staging url failed: {:#?}", e);
+            return Err(ErrorResponse::new("staging url failed"));
+        }
+    };
+    let copy_res = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "copy blob",
+        |_| {
+            let source_url = source_url.clone();
+            async move {
+                let mut copy = final_client
+                    .copy(source_url)
+                    .metadata(blob_metadata(metadata));
+                if let Some(tier) = access_tier {
+                    copy = copy.access_tier(blob_access_tier(tier));
+                }
+                copy.await
+            }
+        },
+    )
+    .await
+    .map_err(|e| storage_error("copy blob failed", e))?;
+    debug!(
+        "promote {} -> {} : {:?}",
+        staging_name, blob_name, copy_res.copy_status
+    );
+
+    let mut copy_status = copy_res.copy_status;
+    let mut attempts = 0;
+    while copy_status == CopyStatus::Pending {
+        if attempts >= COPY_POLL_ATTEMPTS {
+            error!("copy blob timed out: {}", blob_name);
+            return Err(ErrorResponse::new("copy blob timed out"));
+        }
+        attempts += 1;
+        actix_web::rt::time::sleep(COPY_POLL_INTERVAL).await;
+        copy_status = with_retry(
+            &config.retry_policy,
+            &config.circuit_breaker,
+            "get properties",
+            move |_| async move {
+                let props = final_client.get_properties().await?;
+                Ok(props
+                    .blob
+                    .properties
+                    .copy_status
+                    .unwrap_or(CopyStatus::Success))
+            },
+        )
+        .await
+        .map_err(|e| storage_error("get properties failed", e))?;
+    }
+    if copy_status != CopyStatus::Success {
+        error!("copy blob failed with status {:?}", copy_status);
+        return Err(ErrorResponse::new("copy blob failed"));
+    }
+    if !tags.is_empty() {
+        with_retry(
+            &config.retry_policy,
+            &config.circuit_breaker,
+            "set blob tags",
+            move |_| async move {
+                let mut blob_tags = Tags::new();
+                for (key, value) in tags {
+                    blob_tags.insert(key.clone(), value.clone());
+                }
+                final_client.set_tags(blob_tags).await.map(|_| ())
+            },
+        )
+        .await
+        .map_err(|e| storage_error("set blob tags failed", e))?;
+    }
+
+    let delete_res = with_retry(
+        &config.retry_policy,
+        &config.circuit_breaker,
+        "delete blob",
+        move |_| async move { staging_client.delete().await.map(|_| ()) },
+    )
+    .await;
+    if let Err(e) = delete_res {
+        // the published blob is complete, a leftover staging blob only costs storage
+        error!("delete staging blob failed: {:?}", e);
+    }
+    Ok(())
+}
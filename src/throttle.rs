@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::StatusCode;
+use actix_web::web::Data;
+use actix_web::HttpRequest;
+use futures::future::LocalBoxFuture;
+use tracing::error;
+
+use crate::auth::{Caller, ANONYMOUS};
+use crate::models::{ErrorResponse, WebAPIResult};
+
+/// Keys kept before buckets that refilled completely are dropped again.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Token bucket refilled at `rate` per second up to `burst`. A take may overdraw the
+/// bucket, so a chunk larger than `burst` still passes once the bucket is not in debt,
+/// and the debt holds back what comes after it.
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token buckets by key, e.g. one per client or per user. All state is local to the
+/// process, every replica enforces the limits on its own.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(rate: u64, burst: u64) -> RateLimiter {
+        RateLimiter {
+            rate: rate.max(1) as f64,
+            burst: burst.max(1) as f64,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes `amount` tokens from the bucket of `key`, or tells how long to wait until
+    /// the bucket is out of debt.
+    pub fn take(&self, key: &str, amount: u64) -> Result<(), Duration> {
+        self.take_at(key, amount, Instant::now())
+    }
+
+    fn take_at(&self, key: &str, amount: u64, now: Instant) -> Result<(), Duration> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens < 0.0 {
+            return Err(Duration::from_secs_f64(-bucket.tokens / self.rate));
+        }
+        bucket.tokens -= amount as f64;
+        Ok(())
+    }
+
+    /// Whether `key` is in debt right now, without taking anything.
+    fn wait_time(&self, key: &str) -> Option<Duration> {
+        let buckets = self.buckets.lock().unwrap();
+        let bucket = buckets.get(key)?;
+        let elapsed = Instant::now().duration_since(bucket.updated).as_secs_f64();
+        let tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        (tokens < 0.0).then(|| Duration::from_secs_f64(-tokens / self.rate))
+    }
+}
+
+/// Limits on `start_upload` requests per client and on chunk bytes per user and overall.
+/// Unset limits let everything through.
+#[derive(Default)]
+pub struct Throttle {
+    pub start_upload: Option<RateLimiter>,
+    pub user_bandwidth: Option<RateLimiter>,
+    pub global_bandwidth: Option<RateLimiter>,
+    /// take the client address from `Forwarded` / `X-Forwarded-For`, only behind a proxy
+    /// that sets them
+    pub trust_proxy: bool,
+}
+
+fn throttled(error: &str, retry_after: Duration) -> ErrorResponse {
+    ErrorResponse::too_many_requests(error, retry_after)
+}
+
+impl Throttle {
+    /// Authenticated callers are limited by their id, anonymous ones by their address.
+    fn client_key(&self, req: &HttpRequest, caller: &Caller) -> String {
+        if caller.id != ANONYMOUS {
+            return format!("caller:{}", caller.id);
+        }
+        let info = req.connection_info();
+        let addr = if self.trust_proxy {
+            info.realip_remote_addr()
+        } else {
+            info.peer_addr()
+        };
+        format!("addr:{}", addr.unwrap_or("unknown"))
+    }
+
+    pub fn check_start_upload(&self, req: &HttpRequest, caller: &Caller) -> WebAPIResult<()> {
+        let limiter = match &self.start_upload {
+            Some(limiter) => limiter,
+            None => return Ok(()),
+        };
+        let key = self.client_key(req, caller);
+        limiter.take(&key, 1).map_err(|retry_after| {
+            error!("start_upload rate limit hit by {}", key);
+            throttled("too many requests", retry_after)
+        })
+    }
+
+    fn limits_bandwidth(&self) -> bool {
+        self.user_bandwidth.is_some() || self.global_bandwidth.is_some()
+    }
+
+    /// Charges a chunk of `size` bytes to the bandwidth of `caller_id` and of the server.
+    /// Nothing is charged when either is still in debt from earlier chunks.
+    pub fn check_bandwidth(&self, caller_id: &str, size: u64) -> WebAPIResult<()> {
+        let waits = [
+            self.user_bandwidth
+                .as_ref()
+                .and_then(|l| l.wait_time(caller_id)),
+            self.global_bandwidth.as_ref().and_then(|l| l.wait_time("")),
+        ];
+        if let Some(retry_after) = waits.iter().flatten().max() {
+            error!("bandwidth limit hit by {}", caller_id);
+            return Err(throttled("upload bandwidth exceeded", *retry_after));
+        }
+        let charges = [
+            self.user_bandwidth
+                .as_ref()
+                .map(|l| l.take(caller_id, size)),
+            self.global_bandwidth.as_ref().map(|l| l.take("", size)),
+        ];
+        // another request got into debt in between, its wait applies to this one too
+        if let Some(Err(retry_after)) = charges.into_iter().flatten().find(Result::is_err) {
+            error!("bandwidth limit hit by {}", caller_id);
+            return Err(throttled("upload bandwidth exceeded", retry_after));
+        }
+        Ok(())
+    }
+}
+
+/// Middleware charging the `Content-Length` of a request to the bandwidth of its caller
+/// before the body is read, so a throttled chunk is never received. Requests without
+/// credentials pass through and are rejected by the handler.
+pub struct BandwidthLimit(pub Data<Throttle>);
+
+impl<S, B> Transform<S, ServiceRequest> for BandwidthLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Transform = BandwidthLimitService<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(BandwidthLimitService {
+            service: Rc::new(service),
+            throttle: self.0.clone(),
+        }))
+    }
+}
+
+pub struct BandwidthLimitService<S> {
+    service: Rc<S>,
+    throttle: Data<Throttle>,
+}
+
+impl<S, B> Service<ServiceRequest> for BandwidthLimitService<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = actix_web::Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = actix_web::Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    actix_web::dev::forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let throttle = self.throttle.clone();
+        Box::pin(async move {
+            if !throttle.limits_bandwidth() {
+                return service.call(req).await;
+            }
+            let caller = match req.extract::<Caller>().await {
+                Ok(caller) => caller,
+                Err(_) => return service.call(req).await,
+            };
+            let size = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.parse::<u64>().ok());
+            let size = match size {
+                Some(size) => size,
+                None => {
+                    error!("chunk of {} without Content-Length", caller.id);
+                    return Err(ErrorResponse::with_status(
+                        StatusCode::LENGTH_REQUIRED,
+                        "Content-Length required",
+                    )
+                    .into());
+                }
+            };
+            throttle.check_bandwidth(&caller.id, size)?;
+            service.call(req).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use actix_web::test::{call_service, init_service, try_call_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    use super::*;
+    use crate::auth::Authenticator;
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = RateLimiter::new(2, 3);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert!(limiter.take_at("a", 1, start).is_ok());
+        }
+        // empty but not in debt, the next take overdraws
+        assert!(limiter.take_at("a", 1, start).is_ok());
+        assert_eq!(
+            limiter.take_at("a", 1, start),
+            Err(Duration::from_millis(500))
+        );
+        // two tokens a second pay the debt back and leave one
+        let later = start + Duration::from_secs(1);
+        assert!(limiter.take_at("a", 1, later).is_ok());
+        assert!(limiter.take_at("a", 1, later).is_ok());
+        assert!(limiter.take_at("a", 1, later).is_err());
+    }
+
+    #[test]
+    fn refill_capped_at_burst() {
+        let limiter = RateLimiter::new(10, 2);
+        let start = Instant::now();
+        assert!(limiter.take_at("a", 2, start).is_ok());
+        let later = start + Duration::from_secs(60);
+        assert!(limiter.take_at("a", 2, later).is_ok());
+        assert!(limiter.take_at("a", 1, later).is_ok());
+        assert!(limiter.take_at("a", 1, later).is_err());
+    }
+
+    #[test]
+    fn large_take_goes_into_debt() {
+        let limiter = RateLimiter::new(100, 100);
+        let start = Instant::now();
+        assert!(limiter.take_at("a", 300, start).is_ok());
+        assert_eq!(limiter.take_at("a", 1, start), Err(Duration::from_secs(2)));
+        assert!(limiter
+            .take_at("a", 1, start + Duration::from_secs(2))
+            .is_ok());
+    }
+
+    #[test]
+    fn buckets_are_per_key() {
+        let limiter = RateLimiter::new(1, 1);
+        let start = Instant::now();
+        assert!(limiter.take_at("a", 2, start).is_ok());
+        assert!(limiter.take_at("a", 1, start).is_err());
+        assert!(limiter.take_at("b", 1, start).is_ok());
+    }
+
+    #[test]
+    fn bandwidth_charged_to_user_and_server() {
+        let throttle = Throttle {
+            user_bandwidth: Some(RateLimiter::new(1, 10)),
+            global_bandwidth: Some(RateLimiter::new(1, 15)),
+            ..Throttle::default()
+        };
+        assert!(throttle.check_bandwidth("alice", 11).is_ok());
+        let e = throttle.check_bandwidth("alice", 1).unwrap_err();
+        assert_eq!(e.status, actix_web::http::StatusCode::TOO_MANY_REQUESTS);
+        assert!(throttle.check_bandwidth("bob", 5).is_ok());
+        // the server is in debt now, bob waits too
+        assert!(throttle.check_bandwidth("bob", 1).is_err());
+    }
+
+    #[actix_web::test]
+    async fn throttled_chunk_rejected_before_its_body_is_read() {
+        let throttle = Data::new(Throttle {
+            user_bandwidth: Some(RateLimiter::new(1, 10)),
+            ..Throttle::default()
+        });
+        let bodies = Arc::new(AtomicUsize::new(0));
+        let read = bodies.clone();
+        let app = init_service(
+            App::new()
+                .app_data(Data::new(Authenticator::default()))
+                .service(
+                    web::resource("/chunk")
+                        .wrap(BandwidthLimit(throttle))
+                        .route(web::post().to(move |body: web::Bytes| {
+                            read.fetch_add(1, Ordering::SeqCst);
+                            async move { HttpResponse::Ok().body(body) }
+                        })),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/chunk")
+            .set_payload(vec![0u8; 11])
+            .to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+        let req = TestRequest::post()
+            .uri("/chunk")
+            .set_payload(vec![0u8; 11])
+            .to_request();
+        let res = try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(
+            res.as_response_error().status_code(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(bodies.load(Ordering::SeqCst), 1);
+
+        let req = TestRequest::post()
+            .uri("/chunk")
+            .insert_header((CONTENT_LENGTH, "x"))
+            .to_request();
+        let res = try_call_service(&app, req).await.unwrap_err();
+        assert_eq!(
+            res.as_response_error().status_code(),
+            StatusCode::LENGTH_REQUIRED
+        );
+        assert_eq!(bodies.load(Ordering::SeqCst), 1);
+    }
+}
@@ -0,0 +1,321 @@
+use std::fmt;
+use std::time::Duration;
+
+use actix_web::http::StatusCode;
+use actix_web::HttpRequest;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use hmac::{Hmac, Mac};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::error;
+
+use crate::db::unix_now;
+use crate::models::{ErrorResponse, UploadInfo, WebAPIResult};
+
+const UPLOAD_TOKEN_HEADER: &str = "X-Upload-Token";
+/// purpose upload tokens are signed for, see `TokenKey::sign`
+const UPLOAD_PURPOSE: &str = "upload";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Signs small JSON payloads as `{payload}.{signature}`, both base64url encoded, so the
+/// server can trust what it handed out earlier without storing it.
+#[derive(Clone)]
+pub struct TokenKey {
+    secret: Vec<u8>,
+}
+
+impl TokenKey {
+    pub fn new(secret: &[u8]) -> TokenKey {
+        TokenKey {
+            secret: secret.to_vec(),
+        }
+    }
+
+    /// A key that only lives as long as the process, tokens die with a restart.
+    pub fn random() -> TokenKey {
+        TokenKey {
+            secret: rand::random::<[u8; 32]>().to_vec(),
+        }
+    }
+
+    fn mac(&self) -> HmacSha256 {
+        HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any size")
+    }
+
+    /// The MAC of a token payload, prefixed with what the token is for. Tokens of
+    /// different kinds share the key, the prefix keeps one from passing as another.
+    fn token_mac(&self, purpose: &str, payload: &str) -> HmacSha256 {
+        let mut mac = self.mac();
+        mac.update(purpose.as_bytes());
+        mac.update(b"\0");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    pub fn sign<T: Serialize>(&self, purpose: &str, claims: &T) -> String {
+        let payload = URL_SAFE_NO_PAD.encode(serde_json::to_vec(claims).unwrap_or_default());
+        let mac = self.token_mac(purpose, &payload);
+        let signature = URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes());
+        format!("{}.{}", payload, signature)
+    }
+
+    /// Keyed digest of `data`, base64url encoded. Unlike a plain hash it cannot be brute
+    /// forced offline by whoever sees it.
+    pub fn digest(&self, data: &[u8]) -> String {
+        let mut mac = self.mac();
+        mac.update(data);
+        URL_SAFE_NO_PAD.encode(mac.finalize().into_bytes())
+    }
+
+    /// Whether `digest` is the digest of `data`, compared in constant time.
+    pub fn verify_digest(&self, data: &[u8], digest: &str) -> bool {
+        let digest = match URL_SAFE_NO_PAD.decode(digest) {
+            Ok(digest) => digest,
+            Err(_) => return false,
+        };
+        let mut mac = self.mac();
+        mac.update(data);
+        mac.verify_slice(&digest).is_ok()
+    }
+
+    /// The claims of a token signed with this key for `purpose`, `None` when it was
+    /// tampered with or signed for something else.
+    pub fn open<T: DeserializeOwned>(&self, purpose: &str, token: &str) -> Option<T> {
+        let (payload, signature) = token.split_once('.')?;
+        let signature = URL_SAFE_NO_PAD.decode(signature).ok()?;
+        let mac = self.token_mac(purpose, payload);
+        // constant time comparison
+        mac.verify_slice(&signature).ok()?;
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(payload).ok()?).ok()
+    }
+}
+
+/// Token proving the right to write to one upload. Its `Debug` output hides the value so
+/// it never shows up in logged responses.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct UploadToken(pub String);
+
+impl fmt::Debug for UploadToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("UploadToken(***)")
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct UploadClaims {
+    upload_id: String,
+    file_size: u64,
+    owner: String,
+    /// unix seconds
+    exp: i64,
+}
+
+/// Hands out upload tokens in `start_upload` and `continue_upload` and checks them on
+/// every chunk and on `finish_upload`, so knowing an upload id is not enough to write to it.
+pub struct UploadTokens {
+    key: TokenKey,
+    ttl: Duration,
+}
+
+fn invalid_token(error: &str) -> ErrorResponse {
+    ErrorResponse::with_status(StatusCode::FORBIDDEN, error)
+}
+
+impl UploadTokens {
+    pub fn new(key: TokenKey, ttl: Duration) -> UploadTokens {
+        UploadTokens { key, ttl }
+    }
+
+    /// A token for `upload_info` valid for the configured ttl. Every `continue_upload`
+    /// answers with a fresh one, so only idle uploads run out of time.
+    pub fn issue(&self, upload_info: &UploadInfo) -> UploadToken {
+        self.issue_until(upload_info, unix_now() + self.ttl.as_secs() as i64)
+    }
+
+    /// A token for `upload_info` valid until `exp` in unix seconds. Direct uploads get
+    /// one lasting as long as their chunk URLs, nothing renews it while the chunks go
+    /// straight to storage.
+    pub fn issue_until(&self, upload_info: &UploadInfo, exp: i64) -> UploadToken {
+        UploadToken(self.key.sign(
+            UPLOAD_PURPOSE,
+            &UploadClaims {
+                upload_id: upload_info.upload_id.clone(),
+                file_size: upload_info.file_size,
+                owner: upload_info.owner.clone(),
+                exp,
+            },
+        ))
+    }
+
+    /// Checks the `X-Upload-Token` header against the upload the request is about.
+    pub fn verify(&self, req: &HttpRequest, upload_info: &UploadInfo) -> WebAPIResult<()> {
+        let token = match req.headers().get(UPLOAD_TOKEN_HEADER) {
+            Some(token) => token.to_str().unwrap_or_default(),
+            None => {
+                error!("upload token missing for {}", upload_info.upload_id);
+                return Err(invalid_token("missing upload token"));
+            }
+        };
+        let claims = match self.key.open::<UploadClaims>(UPLOAD_PURPOSE, token) {
+            Some(claims) => claims,
+            None => {
+                error!("upload token of {} not signed by us", upload_info.upload_id);
+                return Err(invalid_token("invalid upload token"));
+            }
+        };
+        if claims.upload_id != upload_info.upload_id
+            || claims.file_size != upload_info.file_size
+            || claims.owner != upload_info.owner
+        {
+            error!(
+                "upload token for {} used on {}",
+                claims.upload_id, upload_info.upload_id
+            );
+            return Err(invalid_token("invalid upload token"));
+        }
+        if claims.exp < unix_now() {
+            error!("upload token of {} expired", upload_info.upload_id);
+            return Err(invalid_token("upload token expired"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    fn upload_info(upload_id: &str) -> UploadInfo {
+        serde_json::from_value(serde_json::json!({
+            "upload_id": upload_id,
+            "file_name": "a.bin",
+            "file_size": 10,
+            "file_hash": "",
+            "content_type": "application/octet-stream",
+            "blob_access_token": "",
+            "blob_file_hash": "",
+            "blob_name": "a.bin",
+            "conflict_mode": "fail",
+            "block_size": 4,
+            "chunk_size": 4,
+            "staged_size": 0,
+            "state": "created",
+            "owner": "alice",
+        }))
+        .unwrap()
+    }
+
+    fn tokens() -> UploadTokens {
+        UploadTokens::new(TokenKey::new(b"secret"), Duration::from_secs(60))
+    }
+
+    fn request(token: &str) -> HttpRequest {
+        TestRequest::default()
+            .insert_header((UPLOAD_TOKEN_HEADER, token))
+            .to_http_request()
+    }
+
+    fn error_of(res: WebAPIResult<()>) -> String {
+        res.unwrap_err().error
+    }
+
+    #[test]
+    fn issued_token_verifies() {
+        let tokens = tokens();
+        let upload_info = upload_info("u1");
+        let token = tokens.issue(&upload_info);
+        assert!(tokens.verify(&request(&token.0), &upload_info).is_ok());
+    }
+
+    #[test]
+    fn expired_token_rejected() {
+        let tokens = tokens();
+        let upload_info = upload_info("u1");
+        let token = tokens.issue_until(&upload_info, unix_now() - 1);
+        assert_eq!(
+            error_of(tokens.verify(&request(&token.0), &upload_info)),
+            "upload token expired"
+        );
+    }
+
+    #[test]
+    fn tampered_token_rejected() {
+        let tokens = tokens();
+        let upload_info = upload_info("u1");
+        let token = tokens.issue(&upload_info).0;
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged = URL_SAFE_NO_PAD.encode(
+            serde_json::to_vec(&UploadClaims {
+                upload_id: "u1".to_string(),
+                file_size: 10,
+                owner: "alice".to_string(),
+                exp: i64::MAX,
+            })
+            .unwrap(),
+        );
+        assert_eq!(
+            error_of(tokens.verify(&request(&format!("{}.{}", forged, signature)), &upload_info)),
+            "invalid upload token"
+        );
+        let other_key = UploadTokens::new(TokenKey::new(b"other"), Duration::from_secs(60));
+        let token = other_key.issue(&upload_info);
+        assert_eq!(
+            error_of(tokens.verify(&request(&token.0), &upload_info)),
+            "invalid upload token"
+        );
+    }
+
+    #[test]
+    fn token_of_other_purpose_rejected() {
+        let tokens = tokens();
+        let upload_info = upload_info("u1");
+        let token = tokens.key.sign(
+            "share",
+            &UploadClaims {
+                upload_id: "u1".to_string(),
+                file_size: 10,
+                owner: "alice".to_string(),
+                exp: i64::MAX,
+            },
+        );
+        assert_eq!(
+            error_of(tokens.verify(&request(&token), &upload_info)),
+            "invalid upload token"
+        );
+    }
+
+    #[test]
+    fn token_bound_to_its_upload() {
+        let tokens = tokens();
+        let token = tokens.issue(&upload_info("u1"));
+        assert_eq!(
+            error_of(tokens.verify(&request(&token.0), &upload_info("u2"))),
+            "invalid upload token"
+        );
+    }
+
+    #[test]
+    fn missing_token_rejected() {
+        let tokens = tokens();
+        let req = TestRequest::default().to_http_request();
+        assert_eq!(
+            error_of(tokens.verify(&req, &upload_info("u1"))),
+            "missing upload token"
+        );
+    }
+
+    #[test]
+    fn digest_verified() {
+        let key = TokenKey::new(b"secret");
+        let digest = key.digest(b"data");
+        assert!(key.verify_digest(b"data", &digest));
+        assert!(!key.verify_digest(b"other", &digest));
+        assert!(!key.verify_digest(b"data", "not base64!"));
+    }
+}